@@ -197,11 +197,14 @@ fn main() -> Result<(), amethyst_error::Error> {
             )
             .with_plugin(RenderDebugLines::default())
             .with_plugin(RenderShaded3D::default())
-            .with_plugin(RenderVoxels::<
-                ExampleVoxel,
-                VecStorage<ExampleVoxel, MortonEncoder>,
-                DrawVoxelsBoundsDefault,
-            >::default()),
+            .with_plugin(
+                RenderVoxels::<
+                    ExampleVoxel,
+                    VecStorage<ExampleVoxel, MortonEncoder>,
+                    DrawVoxelsBoundsDefault,
+                >::default()
+                .with_greedy_meshing(true),
+            ),
     );
 
     let game = Application::build(assets_directory, ExampleState)?.build(dispatcher)?;
@@ -3,13 +3,24 @@
 layout(set = 1, binding = 0) uniform sampler2D albedo;
 
 layout(location = 0) in VertexData {
-    vec2 tex_uv;
+    vec2 tile_uv;
+    flat vec2 tex_top_left;
+    flat vec2 tex_bottom_right;
     vec4 color;
 } vertex;
 layout(location = 0) out vec4 out_color;
 
 void main() {
-    vec4 color = texture(albedo, vertex.tex_uv) * vertex.color;
+    // Repeat the sprite across the face. The gradients are taken from the unwrapped coordinates,
+    // such that the mip level doesn't jump at the seams between tiles.
+    vec2 sprite_size = vertex.tex_bottom_right - vertex.tex_top_left;
+    vec2 tex_uv = vertex.tex_top_left + fract(vertex.tile_uv) * sprite_size;
+    vec4 color = textureGrad(
+      albedo,
+      tex_uv,
+      dFdx(vertex.tile_uv) * sprite_size,
+      dFdy(vertex.tile_uv) * sprite_size
+    ) * vertex.color;
     if (color.a == 0.0) {
         discard;
     }
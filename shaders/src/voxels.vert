@@ -14,9 +14,12 @@ layout(location = 1) in vec2 tex_bottom_right;
 layout(location = 2) in vec4 color;
 layout(location = 3) in ivec3 voxel_coordinate;
 layout(location = 4) in uint face;
+layout(location = 5) in uvec2 face_size;
 
 layout(location = 0) out VertexData {
-    vec2 tex_uv;
+    vec2 tile_uv;
+    flat vec2 tex_top_left;
+    flat vec2 tex_bottom_right;
     vec4 color;
 } vertex_data;
 
@@ -66,14 +69,15 @@ const vec3 face_vertices[6][4] = vec3[][](
   )
 );
 
-
-// Maps the coordinates on the face `uv` to the coordinates in the actual texture.
-vec2 texture_coords(vec2 uv, vec2 top_left, vec2 bottom_right) {
-    return vec2(
-      mix(top_left.x, bottom_right.x, uv.x + 0.5),
-      mix(top_left.y, bottom_right.y, uv.y + 0.5)
-    );
-}
+// Directions in which the u and v coordinates of the texture increase on each face.
+const vec3 face_axes[6][2] = vec3[][](
+  vec3[](vec3(1.0, 0.0, 0.0), vec3(0.0, 1.0, 0.0)),
+  vec3[](vec3(1.0, 0.0, 0.0), vec3(0.0, -1.0, 0.0)),
+  vec3[](vec3(1.0, 0.0, 0.0), vec3(0.0, 0.0, -1.0)),
+  vec3[](vec3(1.0, 0.0, 0.0), vec3(0.0, 0.0, 1.0)),
+  vec3[](vec3(0.0, 0.0, 1.0), vec3(0.0, 1.0, 0.0)),
+  vec3[](vec3(0.0, 0.0, -1.0), vec3(0.0, 1.0, 0.0))
+);
 
 void main() {
     // Voxel coordinate
@@ -89,8 +93,13 @@ void main() {
     // `map_transform` is the entities' transform and maps the coordinates to world space.
     vec4 world_coordinate = map_coordinate_transform * coord * transpose(map_transform);
 
-    // Determines the texture coordinates for this vertice in the target texture.
-    vertex_data.tex_uv = texture_coords(texture_vertices[gl_VertexIndex], tex_top_left, tex_bottom_right);
+    // Coordinates of this vertice on the face, counted in tiles of the texture. The fragment shader
+    // repeats the sprite once per voxel.
+    vec2 uv = texture_vertices[gl_VertexIndex] + 0.5;
+    vec2 size = vec2(face_size);
+    vertex_data.tile_uv = uv * size;
+    vertex_data.tex_top_left = tex_top_left;
+    vertex_data.tex_bottom_right = tex_bottom_right;
 
     // Set tint
     vertex_data.color = color;
@@ -100,8 +109,10 @@ void main() {
     vec3 dir_y = (map_transform[1] * voxel_dimensions.y).xyz;
     vec3 dir_z = (map_transform[2] * voxel_dimensions.z).xyz;
 
-    // Face vertex
-    vec3 face_vertex = face_vertices[face][gl_VertexIndex];
+    // Face vertex. Merged faces start at `voxel_coordinate` and are stretched over `face_size` voxels.
+    vec3 face_vertex = face_vertices[face][gl_VertexIndex]
+      + uv.x * (size.x - 1.0) * face_axes[face][0]
+      + uv.y * (size.y - 1.0) * face_axes[face][1];
 
     // Offset vertex to world coordinates and orientation/scaling of map transform.
     vec4 vertex = vec4(
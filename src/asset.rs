@@ -0,0 +1,343 @@
+//! Loading voxel maps with `amethyst_assets`.
+//!
+//! MagicaVoxel files (`.vox`) and files in the [native
+//! format](crate::formats::native) (`.avxm`) can be loaded with the asset
+//! loader, which returns a `Handle<VoxelMapAsset>`. Attach it together with the
+//! sprite sheet as [`VoxelMapHandle`] to an entity, and [`VoxelMapAssetSystem`]
+//! adds the [`VoxelMap`] component once the asset has been loaded. When the
+//! asset is reloaded, the voxel data of the map is replaced in place.
+//!
+//! ```ignore
+//! let handle = loader.load("level.vox");
+//! world.push((Transform::default(), VoxelMapHandle::new(handle, sprite_sheet)));
+//! ```
+
+use std::{
+    convert::TryInto,
+    marker::PhantomData,
+};
+
+use amethyst_assets::{
+    register_asset_type,
+    register_format,
+    register_format_type,
+    register_importer,
+    Asset,
+    AssetProcessorSystem,
+    AssetStorage,
+    Format,
+    Handle,
+    ProcessableAsset,
+    ProcessingState,
+};
+use amethyst_core::ecs::{
+    CommandBuffer,
+    Entity,
+    IntoQuery,
+    ParallelRunnable,
+    System,
+    SystemBuilder,
+    TryWrite,
+};
+use amethyst_rendy::SpriteSheet;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use type_uuid::TypeUuid;
+
+use crate::{
+    formats::{
+        native::{
+            self,
+            BinaryVoxel,
+        },
+        vox::{
+            VoxFile,
+            VoxPalette,
+        },
+        Error,
+    },
+    storage::FromBounds,
+    Voxel,
+    VoxelMap,
+};
+
+/// Voxels that can be loaded from a [`VoxelMapAsset`].
+pub trait AssetVoxel: Voxel + PartialEq + BinaryVoxel {
+    /// Creates the voxel for a palette index of a MagicaVoxel file. `palette`
+    /// contains the colors stored in the file.
+    fn from_vox(index: u8, palette: &VoxPalette) -> Self;
+}
+
+/// Contents of a voxel map file. Since the type of the voxels isn't known to
+/// the asset loader, the file is only validated on import and decoded by
+/// [`VoxelMapAssetSystem`].
+#[derive(Clone, Debug, Serialize, Deserialize, TypeUuid)]
+#[uuid = "5efd085e-aee5-416b-982d-7cdc4a69b78d"]
+pub enum VoxelMapAsset {
+    Vox(Vec<u8>),
+    Native(Vec<u8>),
+}
+
+impl VoxelMapAsset {
+    /// Decodes the voxel data into a new storage.
+    pub fn to_storage<V, S>(&self) -> Result<S, Error>
+    where
+        V: AssetVoxel,
+        S: FromBounds<V>,
+    {
+        match self {
+            Self::Vox(bytes) => {
+                let file = VoxFile::read_from(&bytes[..])?;
+                file.to_storage(|index| V::from_vox(index, &file.palette))
+            }
+            Self::Native(bytes) => native::read_from(&bytes[..]),
+        }
+    }
+}
+
+impl Asset for VoxelMapAsset {
+    type Data = Self;
+
+    fn name() -> &'static str {
+        "VoxelMap"
+    }
+}
+
+impl ProcessableAsset for VoxelMapAsset {
+    fn process(data: Self) -> Result<ProcessingState<Self, Self>, amethyst_error::Error> {
+        Ok(ProcessingState::Loaded(data))
+    }
+}
+
+register_format_type!(VoxelMapAsset);
+register_asset_type!(VoxelMapAsset => VoxelMapAsset; AssetProcessorSystem<VoxelMapAsset>);
+
+/// Format for MagicaVoxel files (`.vox`).
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, TypeUuid)]
+#[uuid = "fb2c78c1-1f2f-4466-ad5d-988b6b013a27"]
+pub struct VoxFormat;
+
+register_format!("VOX", VoxFormat as VoxelMapAsset);
+register_importer!(".vox", VoxFormat);
+
+impl Format<VoxelMapAsset> for VoxFormat {
+    fn name(&self) -> &'static str {
+        "VOX"
+    }
+
+    fn import_simple(&self, bytes: Vec<u8>) -> Result<VoxelMapAsset, amethyst_error::Error> {
+        VoxFile::read_from(&bytes[..])?;
+        Ok(VoxelMapAsset::Vox(bytes))
+    }
+}
+
+/// Format for files in the native format (`.avxm`).
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, TypeUuid)]
+#[uuid = "7b4c2c88-950e-4e97-b56c-f0419c9193ea"]
+pub struct NativeFormat;
+
+register_format!("AVXM", NativeFormat as VoxelMapAsset);
+register_importer!(".avxm", NativeFormat);
+
+impl Format<VoxelMapAsset> for NativeFormat {
+    fn name(&self) -> &'static str {
+        "AVXM"
+    }
+
+    fn import_simple(&self, bytes: Vec<u8>) -> Result<VoxelMapAsset, amethyst_error::Error> {
+        // The voxels can only be decoded once their type is known, so only the
+        // header is checked here.
+        if !bytes.starts_with(&native::MAGIC) {
+            return Err(Error::InvalidMagic.into());
+        }
+        let version = bytes
+            .get(4..8)
+            .and_then(|version| version.try_into().ok())
+            .map(u32::from_le_bytes)
+            .ok_or(Error::Truncated)?;
+        if version != native::VERSION {
+            return Err(Error::UnsupportedVersion(version).into());
+        }
+
+        Ok(VoxelMapAsset::Native(bytes))
+    }
+}
+
+/// Component referencing a voxel map asset, which is turned into a
+/// [`VoxelMap`] by [`VoxelMapAssetSystem`].
+#[derive(Clone, Debug)]
+pub struct VoxelMapHandle {
+    pub handle: Handle<VoxelMapAsset>,
+    pub sprite_sheet: Handle<SpriteSheet>,
+
+    /// Version of the asset the map was created from.
+    version: Option<u32>,
+}
+
+impl VoxelMapHandle {
+    pub fn new(handle: Handle<VoxelMapAsset>, sprite_sheet: Handle<SpriteSheet>) -> Self {
+        Self {
+            handle,
+            sprite_sheet,
+            version: None,
+        }
+    }
+}
+
+/// Adds a `VoxelMap<V, S>` to every entity with a [`VoxelMapHandle`], once its
+/// asset has been loaded. If the asset is reloaded, the voxel data of the
+/// existing map is replaced. Assets that fail to decode are logged and
+/// skipped.
+#[derive(Debug)]
+pub struct VoxelMapAssetSystem<V, S> {
+    _marker: PhantomData<(V, S)>,
+}
+
+impl<V, S> Default for VoxelMapAssetSystem<V, S> {
+    fn default() -> Self {
+        Self {
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<V, S> System for VoxelMapAssetSystem<V, S>
+where
+    V: AssetVoxel,
+    S: FromBounds<V> + Send + Sync + 'static,
+{
+    fn build(self) -> Box<dyn ParallelRunnable> {
+        Box::new(
+            SystemBuilder::new("VoxelMapAssetSystem")
+                .read_resource::<AssetStorage<VoxelMapAsset>>()
+                .with_query(<(Entity, &mut VoxelMapHandle, TryWrite<VoxelMap<V, S>>)>::query())
+                .build(move |commands, world, assets, query| {
+                    for (entity, handle, map) in query.iter_mut(world) {
+                        load_map(commands, *entity, handle, map, assets);
+                    }
+                }),
+        )
+    }
+}
+
+fn load_map<V, S>(
+    commands: &mut CommandBuffer,
+    entity: Entity,
+    handle: &mut VoxelMapHandle,
+    map: Option<&mut VoxelMap<V, S>>,
+    assets: &AssetStorage<VoxelMapAsset>,
+) where
+    V: AssetVoxel,
+    S: FromBounds<V> + Send + Sync + 'static,
+{
+    let (asset, version) = match assets.get_asset_with_version(&handle.handle) {
+        Some(asset) => asset,
+        None => return,
+    };
+    if handle.version == Some(version) {
+        return;
+    }
+    handle.version = Some(version);
+
+    let data = match asset.to_storage::<V, S>() {
+        Ok(data) => data,
+        Err(e) => {
+            log::error!("Failed to load voxel map: {}", e);
+            return;
+        }
+    };
+
+    match map {
+        Some(map) => {
+            log::debug!("Reloading voxel map of {:?}", entity);
+            map.set_data(data);
+        }
+        None => {
+            commands.add_component(entity, VoxelMap::new(data, handle.sprite_sheet.clone()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use amethyst_core::math::Point3;
+
+    use super::*;
+    use crate::{
+        bounds::Bounds,
+        storage::{
+            VecStorage,
+            VoxelStorage,
+        },
+        testing::{
+            assert_same_voxels,
+            TestVoxel,
+        },
+        FlatEncoder,
+    };
+
+    impl AssetVoxel for TestVoxel {
+        fn from_vox(index: u8, _palette: &VoxPalette) -> Self {
+            Self(index.into())
+        }
+    }
+
+    fn sample() -> VecStorage<TestVoxel, FlatEncoder> {
+        let bounds = Bounds::new(Point3::new(0, 0, -2), Point3::new(3, 2, 0));
+        let mut storage = VecStorage::from_bounds(bounds.clone());
+        for (i, coord) in bounds.iter().enumerate() {
+            *storage.get_mut(&coord).unwrap() = TestVoxel(i as u16 + 1);
+        }
+        storage
+    }
+
+    #[test]
+    fn imports_native() {
+        let mut bytes = Vec::new();
+        native::write_to(&sample(), &mut bytes).unwrap();
+
+        let asset = NativeFormat.import_simple(bytes.clone()).unwrap();
+        assert!(matches!(asset, VoxelMapAsset::Native(_)));
+        let storage: VecStorage<TestVoxel, FlatEncoder> = asset.to_storage().unwrap();
+        assert_same_voxels(&storage, &sample());
+
+        let mut invalid = bytes.clone();
+        invalid[0] = b'X';
+        assert!(NativeFormat.import_simple(invalid).is_err());
+
+        let mut unsupported = bytes.clone();
+        unsupported[4..8].copy_from_slice(&(native::VERSION + 1).to_le_bytes());
+        assert!(NativeFormat.import_simple(unsupported).is_err());
+
+        assert!(NativeFormat.import_simple(bytes[..6].to_vec()).is_err());
+    }
+
+    #[test]
+    fn imports_vox() {
+        let file = VoxFile::from_storage(&sample(), |voxel| {
+            Some((voxel.0 as u8, VoxPalette::default().color(voxel.0 as u8)))
+        })
+        .unwrap();
+        let mut bytes = Vec::new();
+        file.write_to(&mut bytes).unwrap();
+
+        let asset = VoxFormat.import_simple(bytes.clone()).unwrap();
+        assert!(matches!(asset, VoxelMapAsset::Vox(_)));
+        let storage: VecStorage<TestVoxel, FlatEncoder> = asset.to_storage().unwrap();
+        assert_same_voxels(&storage, &sample());
+
+        let mut invalid = bytes.clone();
+        invalid[0] = b'X';
+        assert!(VoxFormat.import_simple(invalid).is_err());
+
+        let mut unsupported = bytes.clone();
+        unsupported[4..8].copy_from_slice(&1u32.to_le_bytes());
+        assert!(VoxFormat.import_simple(unsupported).is_err());
+
+        assert!(VoxFormat
+            .import_simple(bytes[..bytes.len() - 1].to_vec())
+            .is_err());
+    }
+}
@@ -17,6 +17,11 @@ use crate::{
 /// coordinates for iteration. The lower (min) coordinates are inclusive and the
 /// upper (max) coordinates are exclusive.
 #[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(try_from = "RawBounds")
+)]
 pub struct Bounds {
     min: Point3<i32>,
     max: Point3<i32>,
@@ -50,7 +55,36 @@ impl Bounds {
     }
 
     pub fn center(&self) -> Point3<i32> {
-        self.min + self.max.coords / 2
+        Point3::from((self.min.coords + self.max.coords) / 2)
+    }
+
+    /// Returns `true` if this `Region` doesn't contain any coordinates.
+    pub fn is_empty(&self) -> bool {
+        self.min.x == self.max.x || self.min.y == self.max.y || self.min.z == self.max.z
+    }
+
+    /// Returns the smallest `Region` containing both this and the provided
+    /// `Region`. Empty regions are ignored.
+    pub fn union(&self, other: &Self) -> Self {
+        if self.is_empty() {
+            return other.clone();
+        }
+        if other.is_empty() {
+            return self.clone();
+        }
+
+        Self {
+            min: Point3::new(
+                self.min.x.min(other.min.x),
+                self.min.y.min(other.min.y),
+                self.min.z.min(other.min.z),
+            ),
+            max: Point3::new(
+                self.max.x.max(other.max.x),
+                self.max.y.max(other.max.y),
+                self.max.z.max(other.max.z),
+            ),
+        }
     }
 
     /// Check if this cube contains the provided coordinate.
@@ -70,6 +104,27 @@ impl Bounds {
             && (self.min.z < other.max.z && self.max.z > other.min.z)
     }
 
+    /// Returns the `Region` that is contained in both this and the provided
+    /// `Region`, or an empty `Region` if they don't intersect.
+    pub fn intersection(&self, other: &Self) -> Self {
+        if !self.intersects(other) {
+            return Self::empty();
+        }
+
+        Self {
+            min: Point3::new(
+                self.min.x.max(other.min.x),
+                self.min.y.max(other.min.y),
+                self.min.z.max(other.min.z),
+            ),
+            max: Point3::new(
+                self.max.x.min(other.max.x),
+                self.max.y.min(other.max.y),
+                self.max.z.min(other.max.z),
+            ),
+        }
+    }
+
     /// Calculate the volume of this bounding box volume.
     pub fn volume(&self) -> u32 {
         let v = (self.max.x - self.min.x) * (self.max.y - self.min.y) * (self.max.z - self.min.z);
@@ -85,6 +140,31 @@ impl Bounds {
     }
 }
 
+/// Unchecked `Bounds`, used to validate deserialized bounds.
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct RawBounds {
+    min: Point3<i32>,
+    max: Point3<i32>,
+}
+
+#[cfg(feature = "serde")]
+impl std::convert::TryFrom<RawBounds> for Bounds {
+    type Error = &'static str;
+
+    fn try_from(raw: RawBounds) -> Result<Self, Self::Error> {
+        if raw.min.x <= raw.max.x && raw.min.y <= raw.max.y && raw.min.z <= raw.max.z {
+            Ok(Self {
+                min: raw.min,
+                max: raw.max,
+            })
+        }
+        else {
+            Err("Minimum of bounds is greater than maximum")
+        }
+    }
+}
+
 impl<'a> IntoIterator for &'a Bounds {
     type Item = Point3<i32>;
     type IntoIter = BoundsLinearIter;
@@ -114,7 +194,7 @@ impl Iterator for BoundsLinearIter {
     fn next(&mut self) -> Option<Self::Item> {
         let ret = self.track;
 
-        if self.track.z >= self.bounds.max.z {
+        if self.track.z >= self.bounds.max.z || self.bounds.is_empty() {
             return None;
         }
 
@@ -203,3 +283,17 @@ impl DrawVoxelsBounds for DrawVoxelsBoundsDefault {
         None
     }
 }
+
+#[cfg(all(test, feature = "serde"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serde_round_trip() {
+        let bounds = Bounds::new(Point3::new(-1, 0, 2), Point3::new(1, 2, 3));
+        assert_eq!(crate::testing::serde_round_trip(&bounds), bounds);
+
+        let json = r#"{"min":[0,3,0],"max":[1,2,1]}"#;
+        assert!(serde_json::from_str::<Bounds>(json).is_err());
+    }
+}
@@ -0,0 +1,342 @@
+use std::{
+    collections::{
+        HashMap,
+        HashSet,
+    },
+    hash::Hash,
+    ops::Range,
+};
+
+use amethyst_rendy::{
+    rendy::{
+        command::RenderPassEncoder,
+        hal,
+        memory::Dynamic,
+        resource::{
+            Buffer,
+            Escape,
+        },
+    },
+    util::ensure_buffer,
+    Backend,
+    Factory,
+};
+
+/// Minimum number of items allocated for a chunk.
+const MIN_CAPACITY: u64 = 64;
+
+/// Range of a chunk in the buffer.
+#[derive(Debug)]
+struct Slot {
+    offset: u64,
+    capacity: u64,
+    len: u64,
+
+    /// Version of the data of the chunk.
+    version: u64,
+
+    /// Changes whenever the data or location of the chunk change.
+    id: u64,
+}
+
+/// Assigns the ranges of a [`ChunkedVertexBuffer`] to chunks.
+#[derive(Debug)]
+struct Allocator<K> {
+    slots: HashMap<K, Slot>,
+
+    /// End of the last slot.
+    end: u64,
+
+    /// Sum of the capacities of all slots.
+    used: u64,
+
+    next_id: u64,
+}
+
+#[derive(Debug)]
+struct PerImage<B: Backend, K> {
+    buffer: Option<Escape<Buffer<B>>>,
+
+    /// Slot ID of every chunk in this buffer.
+    uploaded: HashMap<K, u64>,
+}
+
+/// Vertex buffer made up of chunks, which are only uploaded when they change.
+///
+/// Each chunk gets a range with some spare capacity, such that it can grow a
+/// bit without being moved. Chunks that outgrow their range are moved to the
+/// end of the buffer. If more than half of the buffer is unused, all chunks are
+/// packed again.
+///
+/// New chunks are placed in the order they're passed to [`Self::write`], as
+/// are all chunks when they're packed. The spare capacity of each range is
+/// filled with a padding item, such that chunks next to each other can be
+/// drawn together, including the padding between them.
+#[derive(Debug)]
+pub(crate) struct ChunkedVertexBuffer<B: Backend, K, T> {
+    allocator: Allocator<K>,
+
+    per_image: Vec<PerImage<B, K>>,
+
+    /// Item written into the spare capacity of every range.
+    padding: T,
+
+    /// Copies of `padding`, uploaded after the data of chunks.
+    padding_items: Vec<T>,
+}
+
+impl<B: Backend, K: Clone + Eq + Hash, T: 'static + Copy> ChunkedVertexBuffer<B, K, T> {
+    /// Creates an empty buffer. `padding` fills the spare capacity of the
+    /// ranges of chunks and must not draw anything.
+    pub fn new(padding: T) -> Self {
+        Self {
+            allocator: Allocator::new(),
+            per_image: Vec::new(),
+            padding,
+            padding_items: Vec::new(),
+        }
+    }
+
+    /// Writes the buffer for frame `index`. `chunks` contains the key, data
+    /// and version of every chunk. Chunks not contained in `chunks` are
+    /// removed. Only chunks whose version changed since the last write to this
+    /// frame's buffer are uploaded.
+    ///
+    /// Returns `true` if the buffer has been reallocated and needs to be bound
+    /// again.
+    pub fn write(&mut self, factory: &Factory<B>, index: usize, chunks: &[(K, &[T], u64)]) -> bool {
+        self.allocator.allocate(chunks);
+
+        if self.per_image.len() <= index {
+            self.per_image.resize_with(index + 1, || {
+                PerImage {
+                    buffer: None,
+                    uploaded: HashMap::new(),
+                }
+            });
+        }
+        let image = &mut self.per_image[index];
+
+        let item_size = std::mem::size_of::<T>() as u64;
+        let reallocated = ensure_buffer(
+            factory,
+            &mut image.buffer,
+            hal::buffer::Usage::VERTEX,
+            Dynamic,
+            self.allocator.end.max(1) * item_size,
+        )
+        .expect("Failed to allocate vertex buffer");
+        if reallocated {
+            image.uploaded.clear();
+        }
+
+        let slots = &self.allocator.slots;
+        image.uploaded.retain(|key, _| slots.contains_key(key));
+
+        let buffer = image.buffer.as_mut().unwrap();
+        for (key, data, _) in chunks {
+            let slot = &slots[key];
+            if image.uploaded.get(key) != Some(&slot.id) {
+                let spare = (slot.capacity - slot.len) as usize;
+                if self.padding_items.len() < spare {
+                    self.padding_items.resize(spare, self.padding);
+                }
+
+                unsafe {
+                    factory
+                        .upload_visible_buffer(buffer, slot.offset * item_size, data)
+                        .expect("Failed to upload chunk");
+                    if spare > 0 {
+                        factory
+                            .upload_visible_buffer(
+                                buffer,
+                                (slot.offset + slot.len) * item_size,
+                                &self.padding_items[..spare],
+                            )
+                            .expect("Failed to upload padding");
+                    }
+                }
+                image.uploaded.insert(key.clone(), slot.id);
+            }
+        }
+
+        reallocated
+    }
+
+    /// Returns the range of items of a chunk and the end of its slot. The
+    /// items between the two are padding, so a chunk whose range starts at the
+    /// end of the slot of another one can be drawn together with it.
+    pub fn range(&self, key: &K) -> Option<(Range<u32>, u32)> {
+        self.allocator.range(key)
+    }
+
+    pub fn bind(&self, index: usize, binding_id: u32, encoder: &mut RenderPassEncoder<'_, B>) {
+        if let Some(buffer) = self
+            .per_image
+            .get(index)
+            .and_then(|image| image.buffer.as_ref())
+        {
+            unsafe {
+                encoder.bind_vertex_buffers(binding_id, Some((buffer.raw(), 0)));
+            }
+        }
+    }
+}
+
+impl<K: Clone + Eq + Hash> Allocator<K> {
+    fn new() -> Self {
+        Self {
+            slots: HashMap::new(),
+            end: 0,
+            used: 0,
+            next_id: 0,
+        }
+    }
+
+    fn range(&self, key: &K) -> Option<(Range<u32>, u32)> {
+        self.slots.get(key).map(|slot| {
+            let offset = slot.offset as u32;
+            (
+                offset..offset + slot.len as u32,
+                offset + slot.capacity as u32,
+            )
+        })
+    }
+
+    /// Assigns a slot to every chunk.
+    fn allocate<T>(&mut self, chunks: &[(K, &[T], u64)]) {
+        let keys = chunks.iter().map(|(key, _, _)| key).collect::<HashSet<_>>();
+
+        let mut used = self.used;
+        self.slots.retain(|key, slot| {
+            let keep = keys.contains(key);
+            if !keep {
+                used -= slot.capacity;
+            }
+            keep
+        });
+        self.used = used;
+
+        for (key, data, version) in chunks {
+            let len = data.len() as u64;
+            match self.slots.get_mut(key) {
+                Some(slot) if slot.capacity >= len => {
+                    if slot.version != *version {
+                        slot.len = len;
+                        slot.version = *version;
+                        slot.id = self.next_id;
+                        self.next_id += 1;
+                    }
+                }
+                _ => {
+                    if let Some(slot) = self.slots.remove(key) {
+                        self.used -= slot.capacity;
+                    }
+
+                    let capacity = (len + len / 2).max(MIN_CAPACITY);
+                    self.slots.insert(
+                        key.clone(),
+                        Slot {
+                            offset: self.end,
+                            capacity,
+                            len,
+                            version: *version,
+                            id: self.next_id,
+                        },
+                    );
+                    self.next_id += 1;
+                    self.end += capacity;
+                    self.used += capacity;
+                }
+            }
+        }
+
+        if self.end > 2 * self.used {
+            self.end = 0;
+            for (key, _, _) in chunks {
+                let slot = self.slots.get_mut(key).unwrap();
+                slot.offset = self.end;
+                slot.id = self.next_id;
+                self.next_id += 1;
+                self.end += slot.capacity;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Allocates slots for chunks with the given keys, lengths and versions.
+    fn allocate(allocator: &mut Allocator<u32>, chunks: &[(u32, usize, u64)]) {
+        let data = vec![0u8; chunks.iter().map(|(_, len, _)| *len).max().unwrap_or(0)];
+        let chunks = chunks
+            .iter()
+            .map(|(key, len, version)| (*key, &data[..*len], *version))
+            .collect::<Vec<_>>();
+        allocator.allocate(&chunks);
+    }
+
+    fn id(allocator: &Allocator<u32>, key: u32) -> u64 {
+        allocator.slots[&key].id
+    }
+
+    #[test]
+    fn allocates_slots_in_order() {
+        let mut allocator = Allocator::new();
+        allocate(&mut allocator, &[(1, 10, 0), (2, 100, 0), (3, 0, 0)]);
+
+        // Slots have half of their length as spare capacity, but at least
+        // `MIN_CAPACITY` items.
+        assert_eq!(allocator.range(&1), Some((0..10, 64)));
+        assert_eq!(allocator.range(&2), Some((64..164, 214)));
+        assert_eq!(allocator.range(&3), Some((214..214, 278)));
+        assert_eq!(allocator.range(&4), None);
+        assert_eq!((allocator.end, allocator.used), (278, 278));
+    }
+
+    #[test]
+    fn grows_within_capacity() {
+        let mut allocator = Allocator::new();
+        allocate(&mut allocator, &[(1, 100, 0), (2, 10, 0)]);
+        let old_id = id(&allocator, 1);
+
+        // The same version keeps the slot, so the chunk isn't uploaded again.
+        allocate(&mut allocator, &[(1, 100, 0), (2, 10, 0)]);
+        assert_eq!(id(&allocator, 1), old_id);
+
+        allocate(&mut allocator, &[(1, 150, 1), (2, 10, 0)]);
+        assert_eq!(allocator.range(&1), Some((0..150, 150)));
+        assert_ne!(id(&allocator, 1), old_id);
+
+        // Chunks that outgrow their slot are moved to the end.
+        allocate(&mut allocator, &[(1, 151, 2), (2, 10, 0)]);
+        assert_eq!(allocator.range(&1), Some((214..365, 440)));
+        assert_eq!(allocator.range(&2), Some((150..160, 214)));
+        assert_eq!((allocator.end, allocator.used), (440, 290));
+    }
+
+    #[test]
+    fn packs_when_half_empty() {
+        let mut allocator = Allocator::new();
+        allocate(&mut allocator, &[(1, 64, 0), (2, 64, 0), (3, 64, 0)]);
+        assert_eq!(allocator.range(&3), Some((192..256, 288)));
+
+        // Exactly half of the buffer is still in use.
+        allocate(&mut allocator, &[(2, 64, 0), (3, 64, 0)]);
+        assert_eq!((allocator.end, allocator.used), (288, 192));
+        allocate(&mut allocator, &[(1, 64, 0), (3, 64, 0)]);
+        assert_eq!((allocator.end, allocator.used), (384, 192));
+        assert_eq!(allocator.range(&1), Some((288..352, 384)));
+
+        let old_id = id(&allocator, 3);
+        allocate(&mut allocator, &[(3, 64, 0), (4, 10, 0)]);
+        assert_eq!((allocator.end, allocator.used), (160, 160));
+        assert_eq!(allocator.range(&3), Some((0..64, 96)));
+        assert_eq!(allocator.range(&4), Some((96..106, 160)));
+
+        // Moved chunks have to be uploaded again.
+        assert_ne!(id(&allocator, 3), old_id);
+    }
+}
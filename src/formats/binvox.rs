@@ -0,0 +1,334 @@
+//! Binvox files (`.binvox`), which store a run-length encoded occupancy grid
+//! as emitted by voxelizers.
+//!
+//! The voxels of a binvox file are ordered with y varying fastest, then z,
+//! then x. The header maps the grid into model space: a voxel at `p` covers
+//! the region starting at `translate + p * scale / d`, where `d` is the
+//! largest dimension of the grid.
+
+use std::io::{
+    BufRead,
+    BufReader,
+    Read,
+    Write,
+};
+
+use amethyst_core::{
+    math::{
+        Point3,
+        Vector3,
+    },
+    Transform,
+};
+
+use crate::{
+    formats::{
+        Error,
+        MAX_VOLUME,
+    },
+    map::CoordinateEncoder,
+    storage::{
+        VecStorage,
+        VoxelStorage,
+    },
+    Voxel,
+};
+
+/// Version of the format, which is the only one that exists.
+pub const VERSION: u32 = 1;
+
+/// Maximum length of a header line. Longer lines are rejected as corrupt.
+const MAX_LINE_LENGTH: u64 = 256;
+
+/// Contents of a binvox file.
+#[derive(Clone, Debug)]
+pub struct Binvox<V, E> {
+    /// Occupancy grid with its origin at `(0, 0, 0)`.
+    pub storage: VecStorage<V, E>,
+    pub translate: Vector3<f32>,
+    pub scale: f32,
+}
+
+impl<V: Voxel, E: CoordinateEncoder> Binvox<V, E> {
+    /// Reads a binvox file. Occupied voxels are created with `V::from(true)`,
+    /// empty ones with `V::from(false)`.
+    pub fn read_from(reader: impl Read) -> Result<Self, Error>
+    where
+        V: From<bool>,
+    {
+        let mut reader = BufReader::new(reader);
+
+        let version = read_line(&mut reader)?;
+        let version = version
+            .strip_prefix("#binvox ")
+            .ok_or(Error::InvalidMagic)?
+            .trim()
+            .parse()
+            .map_err(|_| Error::Corrupt(format!("Invalid version {:?}", version)))?;
+        if version != VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+
+        let mut dimensions = None;
+        let mut translate = Vector3::zeros();
+        let mut scale = 1.0;
+
+        loop {
+            let line = read_line(&mut reader)?;
+            let mut words = line.split_whitespace();
+
+            match words.next() {
+                Some("dim") => {
+                    let dim = parse_words::<u32>(words, &line)?;
+                    if dim.iter().any(|&x| x > i32::MAX as u32) {
+                        return Err(Error::Corrupt(format!("Invalid line {:?}", line)));
+                    }
+                    // Grids are stored as depth (x), height (z) and width (y).
+                    dimensions = Some(Vector3::new(dim[0], dim[2], dim[1]));
+                }
+                Some("translate") => {
+                    let translation = parse_words::<f32>(words, &line)?;
+                    translate = Vector3::from_column_slice(&translation);
+                }
+                Some("scale") => {
+                    scale = words
+                        .next()
+                        .and_then(|word| word.parse().ok())
+                        .ok_or_else(|| Error::Corrupt(format!("Invalid line {:?}", line)))?;
+                }
+                Some("data") => break,
+                _ => return Err(Error::Corrupt(format!("Invalid line {:?}", line))),
+            }
+        }
+
+        let dimensions =
+            dimensions.ok_or_else(|| Error::Corrupt("Missing dimensions".to_owned()))?;
+        let volume = dimensions
+            .iter()
+            .try_fold(1u64, |volume, &x| volume.checked_mul(x as u64))
+            .filter(|&volume| volume <= MAX_VOLUME)
+            .ok_or_else(|| Error::Corrupt(format!("Grid {:?} too large", dimensions)))?;
+
+        // Decode the runs before allocating the storage, such that corrupt
+        // dimensions are detected before allocating a huge storage.
+        let mut runs = Vec::new();
+        let mut total = 0;
+        while total < volume {
+            let mut run = [0; 2];
+            reader.read_exact(&mut run)?;
+
+            let [value, count] = run;
+            if value > 1 || count == 0 || total + count as u64 > volume {
+                return Err(Error::Corrupt(format!("Invalid run {:?}", run)));
+            }
+
+            runs.push((value == 1, count));
+            total += count as u64;
+        }
+
+        let mut storage = VecStorage::from_dimensions(dimensions);
+        let mut coordinates = grid_coordinates(&dimensions);
+        for (occupied, count) in runs {
+            for coord in coordinates.by_ref().take(count as usize) {
+                *storage.get_mut(&coord).unwrap() = V::from(occupied);
+            }
+        }
+
+        Ok(Self {
+            storage,
+            translate,
+            scale,
+        })
+    }
+
+    /// Writes a binvox file. `occupied` decides which voxels are occupied.
+    pub fn write_to<F>(&self, mut writer: impl Write, mut occupied: F) -> Result<(), Error>
+    where
+        F: FnMut(&V) -> bool,
+    {
+        let dimensions = self.storage.dimensions();
+        let origin = self.storage.origin();
+
+        writeln!(writer, "#binvox {}", VERSION)?;
+        writeln!(
+            writer,
+            "dim {} {} {}",
+            dimensions.x, dimensions.z, dimensions.y
+        )?;
+        writeln!(
+            writer,
+            "translate {} {} {}",
+            self.translate.x, self.translate.y, self.translate.z
+        )?;
+        writeln!(writer, "scale {}", self.scale)?;
+        writeln!(writer, "data")?;
+
+        let mut run: Option<(bool, u8)> = None;
+        for coord in grid_coordinates(&dimensions) {
+            let voxel = self.storage.get(&(origin + coord.coords)).unwrap();
+            let value = occupied(voxel);
+
+            run = match run {
+                Some((previous, count)) if previous == value && count < u8::MAX => {
+                    Some((value, count + 1))
+                }
+                Some((previous, count)) => {
+                    writer.write_all(&[previous as u8, count])?;
+                    Some((value, 1))
+                }
+                None => Some((value, 1)),
+            };
+        }
+        if let Some((value, count)) = run {
+            writer.write_all(&[value as u8, count])?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the transform that maps the grid into model space as described
+    /// by the header, compensating for the centering of
+    /// [`VoxelMap`](crate::VoxelMap).
+    pub fn transform(&self) -> Transform {
+        let dimensions = self.storage.dimensions();
+        let size = dimensions.x.max(dimensions.y).max(dimensions.z).max(1);
+        let factor = self.scale / size as f32;
+        let center = self
+            .storage
+            .bounds()
+            .unwrap()
+            .center()
+            .coords
+            .map(|x| x as f32);
+
+        let mut transform = Transform::default();
+        transform.set_translation(self.translate + center * factor);
+        transform.set_scale(Vector3::repeat(factor));
+        transform
+    }
+}
+
+/// Iterates over the coordinates of a grid in the order they're stored.
+fn grid_coordinates(dimensions: &Vector3<u32>) -> impl Iterator<Item = Point3<i32>> {
+    let dimensions = dimensions.map(|x| x as i32);
+
+    (0..dimensions.x).flat_map(move |x| {
+        (0..dimensions.z).flat_map(move |z| (0..dimensions.y).map(move |y| Point3::new(x, y, z)))
+    })
+}
+
+fn read_line(reader: &mut impl BufRead) -> Result<String, Error> {
+    let mut line = String::new();
+    reader.take(MAX_LINE_LENGTH).read_line(&mut line)?;
+
+    if !line.ends_with('\n') {
+        return Err(if line.len() as u64 == MAX_LINE_LENGTH {
+            Error::Corrupt("Header line too long".to_owned())
+        }
+        else {
+            Error::Truncated
+        });
+    }
+
+    Ok(line.trim_end().to_owned())
+}
+
+fn parse_words<'a, T: std::str::FromStr>(
+    words: impl Iterator<Item = &'a str>,
+    line: &str,
+) -> Result<Vec<T>, Error> {
+    words
+        .map(str::parse)
+        .collect::<Result<Vec<T>, _>>()
+        .ok()
+        .filter(|values| values.len() == 3)
+        .ok_or_else(|| Error::Corrupt(format!("Invalid line {:?}", line)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::TestVoxel,
+        FlatEncoder,
+    };
+
+    fn read(bytes: &[u8]) -> Result<Binvox<TestVoxel, FlatEncoder>, Error> {
+        Binvox::read_from(bytes)
+    }
+
+    fn header(dim: &str) -> Vec<u8> {
+        format!("#binvox 1\ndim {}\ntranslate 0 0 0\nscale 1\ndata\n", dim).into_bytes()
+    }
+
+    #[test]
+    fn round_trip() {
+        // Long enough for runs to be split at 255 voxels.
+        let dimensions = Vector3::new(7, 40, 3);
+        let mut storage = VecStorage::<TestVoxel, FlatEncoder>::from_dimensions(dimensions);
+        for coord in grid_coordinates(&dimensions) {
+            let occupied = coord.y < 30 || (coord.x + coord.z) % 2 == 0;
+            *storage.get_mut(&coord).unwrap() = TestVoxel::from(occupied);
+        }
+        let binvox = Binvox {
+            storage,
+            translate: Vector3::new(1.5, -2.0, 0.25),
+            scale: 3.0,
+        };
+
+        let mut bytes = Vec::new();
+        binvox.write_to(&mut bytes, |voxel| voxel.0 != 0).unwrap();
+        let read = read(&bytes).unwrap();
+
+        assert_eq!(read.translate, binvox.translate);
+        assert_eq!(read.scale, binvox.scale);
+        assert_eq!(read.storage.dimensions(), dimensions);
+        for (coord, voxel) in binvox.storage.iter() {
+            assert_eq!(read.storage.get(&coord), Some(voxel));
+        }
+    }
+
+    #[test]
+    fn bad_header() {
+        assert!(matches!(read(b"#voxels 1\n"), Err(Error::InvalidMagic)));
+        assert!(matches!(
+            read(b"#binvox 2\n"),
+            Err(Error::UnsupportedVersion(2))
+        ));
+        assert!(matches!(read(b"#binvox 1\ndim 1 1"), Err(Error::Truncated)));
+
+        let corrupt = vec![
+            b"#binvox x\n".to_vec(),
+            b"#binvox 1\ndim 1 1\ndata\n".to_vec(),
+            b"#binvox 1\nvoxels 1 1 1\ndata\n".to_vec(),
+            b"#binvox 1\ndata\n".to_vec(),
+            header("4294967295 1 1"),
+            header("65536 65536 1"),
+            [&b"#binvox 1\n"[..], &[b'#'; 300]].concat(),
+        ];
+        for bytes in corrupt {
+            assert!(matches!(read(&bytes), Err(Error::Corrupt(_))));
+        }
+    }
+
+    #[test]
+    fn runs() {
+        let mut bytes = header("2 2 2");
+        bytes.extend_from_slice(&[1, 5]);
+        assert!(matches!(read(&bytes), Err(Error::Truncated)));
+
+        // Runs past the end of the grid.
+        for run in &[[1, 5, 0, 4], [1, 9, 0, 0]] {
+            let mut bytes = header("2 2 2");
+            bytes.extend_from_slice(run);
+            assert!(matches!(read(&bytes), Err(Error::Corrupt(_))));
+        }
+
+        // Invalid values and empty runs.
+        for run in &[[2, 8], [1, 0]] {
+            let mut bytes = header("2 2 2");
+            bytes.extend_from_slice(run);
+            assert!(matches!(read(&bytes), Err(Error::Corrupt(_))));
+        }
+    }
+}
@@ -0,0 +1,267 @@
+//! Terrain generated from heightmap images.
+//!
+//! Each pixel of the heightmap becomes a column of voxels standing on `y = 0`,
+//! whose height is the brightness of the pixel times the height scale. The
+//! pixel at `(x, y)` of the image is the column at `(x, z)` of the map, so the
+//! top of the image faces towards negative z.
+//!
+//! An optional material map of the same size can be used to paint the
+//! terrain, e.g. to place sand along rivers.
+
+use std::io::Read;
+
+use amethyst_core::math::{
+    Point3,
+    Vector3,
+};
+use image::{
+    codecs::png::PngDecoder,
+    DynamicImage,
+    ImageBuffer,
+    Luma,
+    RgbaImage,
+};
+
+use crate::{
+    bounds::Bounds,
+    formats::{
+        storage_from_bounds,
+        Error,
+    },
+    storage::FromBounds,
+    Voxel,
+};
+
+/// Position of a voxel in its column.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Depth {
+    /// The topmost voxel of a column.
+    Top,
+    /// One of the voxels directly below the top voxel.
+    Subsurface,
+    /// Any voxel below the subsurface voxels.
+    Deep,
+}
+
+/// Heightmap with an optional material map.
+#[derive(Clone, Debug)]
+pub struct Heightmap {
+    heights: ImageBuffer<Luma<u16>, Vec<u16>>,
+    materials: Option<RgbaImage>,
+    height_scale: f32,
+    subsurface_depth: u32,
+}
+
+impl Heightmap {
+    /// Creates a heightmap from a grayscale image. Colored images are
+    /// converted to grayscale. A white pixel results in a column that is
+    /// `height_scale` voxels high.
+    pub fn new(heights: &DynamicImage, height_scale: f32) -> Self {
+        Self {
+            heights: heights.to_luma16(),
+            materials: None,
+            height_scale,
+            subsurface_depth: 3,
+        }
+    }
+
+    /// Reads the heightmap from a PNG image.
+    pub fn read_from(reader: impl Read, height_scale: f32) -> Result<Self, Error> {
+        Ok(Self::new(&read_png(reader)?, height_scale))
+    }
+
+    /// Sets the material map, which must have the same size as the heightmap.
+    pub fn with_materials(mut self, materials: &DynamicImage) -> Result<Self, Error> {
+        let materials = materials.to_rgba8();
+        if materials.dimensions() != self.heights.dimensions() {
+            return Err(Error::Unsupported(format!(
+                "Material map is {:?} pixels, but heightmap is {:?} pixels",
+                materials.dimensions(),
+                self.heights.dimensions()
+            )));
+        }
+
+        self.materials = Some(materials);
+        Ok(self)
+    }
+
+    /// Reads the material map from a PNG image.
+    pub fn read_materials_from(self, reader: impl Read) -> Result<Self, Error> {
+        let materials = read_png(reader)?;
+        self.with_materials(&materials)
+    }
+
+    /// Sets the number of [`Depth::Subsurface`] voxels below the top voxel of
+    /// each column. Defaults to 3.
+    pub fn with_subsurface_depth(mut self, subsurface_depth: u32) -> Self {
+        self.subsurface_depth = subsurface_depth;
+        self
+    }
+
+    /// Returns the height of the column at `(x, z)` in voxels.
+    pub fn height(&self, x: u32, z: u32) -> u32 {
+        let brightness = self.heights.get_pixel(x, z)[0] as f32 / u16::MAX as f32;
+
+        // The cast saturates and maps NaN to 0. Clamping in the float domain
+        // instead would round `i32::MAX` up to 2^31, which overflows the
+        // bounds.
+        ((brightness * self.height_scale).round() as u32).min(i32::MAX as u32 - 1)
+    }
+
+    /// Returns the bounds of the terrain, which starts at the origin.
+    pub fn bounds(&self) -> Bounds {
+        let (width, length) = self.heights.dimensions();
+        let height = (0..length)
+            .flat_map(|z| (0..width).map(move |x| (x, z)))
+            .map(|(x, z)| self.height(x, z))
+            .max()
+            .unwrap_or_default();
+
+        Bounds::new(
+            Point3::origin(),
+            Point3::from(Vector3::new(width, height, length).map(|x| x as i32)),
+        )
+    }
+
+    /// Creates a storage containing the terrain. `voxel` is called for every
+    /// voxel of each column with its depth and the RGBA color of the material
+    /// map at that column, if there is one. Voxels above the terrain are left
+    /// at `V::default()`.
+    ///
+    /// Returns [`Error::Unsupported`] if `S` can't cover the bounds of the
+    /// terrain, see [`FromBounds::supports_bounds`].
+    pub fn to_storage<V, S, F>(&self, mut voxel: F) -> Result<S, Error>
+    where
+        V: Voxel,
+        S: FromBounds<V>,
+        F: FnMut(Depth, Option<[u8; 4]>) -> V,
+    {
+        let mut storage = storage_from_bounds::<V, S>(self.bounds())?;
+        let (width, length) = self.heights.dimensions();
+
+        for z in 0..length {
+            for x in 0..width {
+                let height = self.height(x, z) as i32;
+                let material = self
+                    .materials
+                    .as_ref()
+                    .map(|materials| materials.get_pixel(x, z).0);
+
+                for y in 0..height {
+                    let below_top = (height - 1 - y) as u32;
+                    let depth = if below_top == 0 {
+                        Depth::Top
+                    }
+                    else if below_top <= self.subsurface_depth {
+                        Depth::Subsurface
+                    }
+                    else {
+                        Depth::Deep
+                    };
+
+                    if let Some(v) = storage.get_mut(&Point3::new(x as i32, y, z as i32)) {
+                        *v = voxel(depth, material);
+                    }
+                }
+            }
+        }
+
+        Ok(storage)
+    }
+}
+
+fn read_png(reader: impl Read) -> Result<DynamicImage, Error> {
+    Ok(DynamicImage::from_decoder(PngDecoder::new(reader)?)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        storage::{
+            OctreeStorage,
+            VecStorage,
+            VoxelStorage,
+        },
+        testing::TestVoxel,
+        FlatEncoder,
+    };
+
+    fn image(width: u32, height: u32, pixels: Vec<u8>) -> DynamicImage {
+        DynamicImage::ImageLuma8(ImageBuffer::from_raw(width, height, pixels).unwrap())
+    }
+
+    fn depth_voxel(depth: Depth, material: Option<[u8; 4]>) -> TestVoxel {
+        let material = material.map_or(0, |[r, ..]| r as u16);
+        TestVoxel(
+            material
+                + match depth {
+                    Depth::Top => 1,
+                    Depth::Subsurface => 2,
+                    Depth::Deep => 3,
+                },
+        )
+    }
+
+    #[test]
+    fn builds_columns() {
+        let heightmap = Heightmap::new(&image(2, 2, vec![0, 255, 128, 51]), 10.0);
+        assert_eq!(heightmap.height(0, 0), 0);
+        assert_eq!(heightmap.height(1, 0), 10);
+        assert_eq!(heightmap.height(0, 1), 5);
+        assert_eq!(heightmap.height(1, 1), 2);
+        assert_eq!(
+            heightmap.bounds(),
+            Bounds::new(Point3::origin(), Point3::new(2, 10, 2))
+        );
+
+        let storage: VecStorage<TestVoxel, FlatEncoder> =
+            heightmap.to_storage(depth_voxel).unwrap();
+        let column = |x: i32, z: i32| {
+            (0..10)
+                .map(|y| storage.get(&Point3::new(x, y, z)).unwrap().0)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(column(0, 0), vec![0; 10]);
+        assert_eq!(column(1, 0), vec![3, 3, 3, 3, 3, 3, 2, 2, 2, 1]);
+        assert_eq!(column(0, 1), vec![3, 2, 2, 2, 1, 0, 0, 0, 0, 0]);
+        assert_eq!(column(1, 1), vec![2, 1, 0, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn paints_materials() {
+        let materials = DynamicImage::ImageRgba8(
+            ImageBuffer::from_raw(2, 1, vec![10, 0, 0, 255, 20, 0, 0, 255]).unwrap(),
+        );
+        let heightmap = Heightmap::new(&image(2, 1, vec![255, 255]), 2.0)
+            .with_subsurface_depth(0)
+            .with_materials(&materials)
+            .unwrap();
+
+        let storage: VecStorage<TestVoxel, FlatEncoder> =
+            heightmap.to_storage(depth_voxel).unwrap();
+        assert_eq!(storage.get(&Point3::new(0, 0, 0)), Some(&TestVoxel(13)));
+        assert_eq!(storage.get(&Point3::new(0, 1, 0)), Some(&TestVoxel(11)));
+        assert_eq!(storage.get(&Point3::new(1, 1, 0)), Some(&TestVoxel(21)));
+
+        let materials = image(1, 1, vec![0]);
+        assert!(matches!(
+            heightmap.with_materials(&materials),
+            Err(Error::Unsupported(_))
+        ));
+    }
+
+    #[test]
+    fn clamps_heights() {
+        let white = image(1, 1, vec![255]);
+        assert_eq!(Heightmap::new(&white, -5.0).height(0, 0), 0);
+        assert_eq!(Heightmap::new(&white, f32::NAN).height(0, 0), 0);
+
+        let heightmap = Heightmap::new(&white, f32::MAX);
+        assert_eq!(heightmap.height(0, 0), i32::MAX as u32 - 1);
+        assert_eq!(heightmap.bounds().max().y, i32::MAX - 1);
+
+        let storage = heightmap.to_storage::<TestVoxel, OctreeStorage<TestVoxel>, _>(depth_voxel);
+        assert!(matches!(storage, Err(Error::Unsupported(_))));
+    }
+}
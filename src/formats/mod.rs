@@ -0,0 +1,100 @@
+//! Reading and writing voxel maps from and to files.
+
+pub mod binvox;
+pub mod heightmap;
+pub mod native;
+pub mod nbt;
+pub mod qb;
+pub mod schem;
+pub mod slices;
+pub mod vox;
+
+use std::{
+    fmt,
+    io,
+};
+
+use crate::{
+    bounds::Bounds,
+    storage::FromBounds,
+};
+
+/// Error returned when reading or writing a voxel file fails.
+#[derive(Debug)]
+pub enum Error {
+    /// The underlying reader or writer failed.
+    Io(io::Error),
+    /// The input ended before the file was complete.
+    Truncated,
+    /// The input doesn't start with the magic number of the format.
+    InvalidMagic,
+    /// The file was written with a version of the format that isn't
+    /// supported.
+    UnsupportedVersion(u32),
+    /// The file is malformed.
+    Corrupt(String),
+    /// The voxel data can't be represented in the format, e.g. because the
+    /// storage is unbounded.
+    Unsupported(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "IO error: {}", e),
+            Self::Truncated => write!(f, "Unexpected end of file"),
+            Self::InvalidMagic => write!(f, "Invalid magic number"),
+            Self::UnsupportedVersion(version) => write!(f, "Unsupported version: {}", version),
+            Self::Corrupt(reason) => write!(f, "Corrupt file: {}", reason),
+            Self::Unsupported(reason) => write!(f, "Unsupported voxel data: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            Self::Truncated
+        }
+        else {
+            Self::Io(e)
+        }
+    }
+}
+
+impl From<image::ImageError> for Error {
+    fn from(e: image::ImageError) -> Self {
+        match e {
+            image::ImageError::IoError(e) => e.into(),
+            image::ImageError::Unsupported(e) => Self::Unsupported(e.to_string()),
+            e => Self::Corrupt(e.to_string()),
+        }
+    }
+}
+
+/// Upper bound of the number of voxels in a file. Used to reject corrupt
+/// headers before allocating a storage for them.
+pub(crate) const MAX_VOLUME: u64 = 1 << 30;
+
+/// Creates a storage covering bounds read from a file. Returns an error
+/// instead of panicking if the storage can't cover them.
+pub(crate) fn storage_from_bounds<V, S: FromBounds<V>>(bounds: Bounds) -> Result<S, Error> {
+    if S::supports_bounds(&bounds) {
+        Ok(S::from_bounds(bounds))
+    }
+    else {
+        Err(Error::Unsupported(format!(
+            "Storage can't cover the bounds {:?}",
+            bounds
+        )))
+    }
+}
@@ -0,0 +1,402 @@
+//! Native binary format for voxel maps. Files in this format use the `.avxm`
+//! extension.
+//!
+//! All integers are stored in little-endian byte order. A file consists of:
+//!
+//! | Field      | Type                | Description                          |
+//! |------------|---------------------|--------------------------------------|
+//! | magic      | `[u8; 4]`           | [`MAGIC`]                            |
+//! | version    | `u32`               | [`VERSION`]                          |
+//! | min        | `[i32; 3]`          | Minimum of the bounds (inclusive)    |
+//! | max        | `[i32; 3]`          | Maximum of the bounds (exclusive)    |
+//! | palette    | `u32`               | Number of palette entries            |
+//! | entries    | `(u32, [u8])` each  | Length and bytes of each voxel       |
+//! | data       | `u64`               | Length of the compressed voxel data  |
+//! | voxels     | zlib stream         | Palette index of every voxel         |
+//!
+//! The palette indices are ordered with x varying fastest, then y, then z. Each
+//! index takes 1, 2 or 4 bytes, depending on whether the palette has up to
+//! 2^8, 2^16 or more entries.
+
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    io::{
+        self,
+        Read,
+        Write,
+    },
+};
+
+use amethyst_core::math::Point3;
+use flate2::{
+    read::ZlibDecoder,
+    write::ZlibEncoder,
+    Compression,
+};
+
+use crate::{
+    bounds::Bounds,
+    formats::{
+        storage_from_bounds,
+        Error,
+        MAX_VOLUME,
+    },
+    storage::{
+        FromBounds,
+        VoxelStorage,
+    },
+    Voxel,
+};
+
+/// Magic number at the start of every file.
+pub const MAGIC: [u8; 4] = *b"AVXM";
+
+/// Version of the format written by [`write_to`].
+pub const VERSION: u32 = 1;
+
+/// Upper bound of the compression ratio of deflate. Used to reject files that
+/// claim to contain far more voxels than their data could hold, before
+/// allocating a storage for them.
+const MAX_COMPRESSION_RATIO: u64 = 1032;
+
+/// Voxels that can be stored in the native format. Every distinct voxel is
+/// written once into the palette of the file.
+pub trait BinaryVoxel: Sized {
+    fn write_voxel(&self, writer: &mut dyn Write) -> io::Result<()>;
+
+    /// Reads a voxel written by [`BinaryVoxel::write_voxel`]. The reader only
+    /// contains the bytes of this voxel.
+    fn read_voxel(reader: &mut dyn Read) -> io::Result<Self>;
+}
+
+/// Writes all voxels of a bounded storage.
+pub fn write_to<V, S>(storage: &S, mut writer: impl Write) -> Result<(), Error>
+where
+    V: Voxel + Eq + Hash + BinaryVoxel,
+    S: VoxelStorage<V>,
+{
+    let bounds = storage
+        .bounds()
+        .ok_or_else(|| Error::Unsupported("Storage is unbounded".to_owned()))?;
+    let default = V::default();
+    let voxel = |coord: &Point3<i32>| storage.get(coord).unwrap_or(&default);
+
+    let mut lookup = HashMap::new();
+    let mut palette = Vec::new();
+    for coord in coordinates(&bounds) {
+        let voxel = voxel(&coord);
+        if !lookup.contains_key(voxel) {
+            lookup.insert(voxel, palette.len() as u32);
+            palette.push(voxel);
+        }
+    }
+
+    let width = index_width(palette.len());
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    for coord in coordinates(&bounds) {
+        let index = lookup[voxel(&coord)];
+        encoder.write_all(&index.to_le_bytes()[..width])?;
+    }
+    let data = encoder.finish()?;
+
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&VERSION.to_le_bytes())?;
+    write_point(&mut writer, &bounds.min())?;
+    write_point(&mut writer, &bounds.max())?;
+
+    writer.write_all(&(palette.len() as u32).to_le_bytes())?;
+    let mut buf = Vec::new();
+    for voxel in palette {
+        buf.clear();
+        voxel.write_voxel(&mut buf)?;
+        writer.write_all(&(buf.len() as u32).to_le_bytes())?;
+        writer.write_all(&buf)?;
+    }
+
+    writer.write_all(&(data.len() as u64).to_le_bytes())?;
+    writer.write_all(&data)?;
+
+    Ok(())
+}
+
+/// Reads voxels written by [`write_to`] into a new storage covering the bounds
+/// stored in the file. Voxels equal to `V::default()` aren't written to the
+/// storage, which keeps sparse storages sparse.
+pub fn read_from<V, S>(mut reader: impl Read) -> Result<S, Error>
+where
+    V: Voxel + PartialEq + BinaryVoxel,
+    S: FromBounds<V>,
+{
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(Error::InvalidMagic);
+    }
+
+    let version = read_u32(&mut reader)?;
+    if version != VERSION {
+        return Err(Error::UnsupportedVersion(version));
+    }
+
+    let min = read_point(&mut reader)?;
+    let max = read_point(&mut reader)?;
+    if (0..3).any(|axis| min[axis] > max[axis]) {
+        return Err(Error::Corrupt(
+            "Minimum of bounds is greater than maximum".to_owned(),
+        ));
+    }
+    let bounds = Bounds::new(min, max);
+    let volume = (0..3)
+        .try_fold(1u64, |volume, axis| {
+            volume.checked_mul((max[axis] as i64 - min[axis] as i64) as u64)
+        })
+        .filter(|&volume| volume <= MAX_VOLUME)
+        .ok_or_else(|| Error::Corrupt("Bounds too large".to_owned()))?;
+
+    let palette_len = read_u32(&mut reader)?;
+    if palette_len as u64 > volume || (palette_len == 0 && volume > 0) {
+        return Err(Error::Corrupt(format!(
+            "Invalid palette size {} for {} voxels",
+            palette_len, volume
+        )));
+    }
+
+    let mut palette = Vec::with_capacity(palette_len as usize);
+    let mut entry = Vec::new();
+    for _ in 0..palette_len {
+        let len = read_u32(&mut reader)?;
+        entry.clear();
+        (&mut reader).take(len as u64).read_to_end(&mut entry)?;
+        if entry.len() != len as usize {
+            return Err(Error::Truncated);
+        }
+
+        let mut bytes = &entry[..];
+        let voxel = V::read_voxel(&mut bytes)
+            .map_err(|e| Error::Corrupt(format!("Invalid palette entry: {}", e)))?;
+        if !bytes.is_empty() {
+            return Err(Error::Corrupt(
+                "Palette entry longer than its voxel".to_owned(),
+            ));
+        }
+
+        palette.push(voxel);
+    }
+
+    let data_len = read_u64(&mut reader)?;
+    let width = index_width(palette.len());
+    if volume.saturating_mul(width as u64) > data_len.saturating_mul(MAX_COMPRESSION_RATIO) {
+        return Err(Error::Corrupt(format!(
+            "{} bytes of voxel data can't hold {} voxels",
+            data_len, volume
+        )));
+    }
+
+    let default = V::default();
+    let mut storage = storage_from_bounds::<V, S>(bounds.clone())?;
+    let mut decoder = ZlibDecoder::new((&mut reader).take(data_len));
+    let mut buf = [0; 4];
+
+    for coord in coordinates(&bounds) {
+        decoder
+            .read_exact(&mut buf[..width])
+            .map_err(decode_error)?;
+        let index = u32::from_le_bytes(buf) as usize;
+
+        let voxel = palette
+            .get(index)
+            .ok_or_else(|| Error::Corrupt(format!("Palette index {} out of range", index)))?;
+        if *voxel != default {
+            *storage.get_mut(&coord).ok_or_else(|| {
+                Error::Unsupported(format!("Storage doesn't contain {:?}", coord))
+            })? = voxel.clone();
+        }
+    }
+
+    if decoder.read(&mut [0]).map_err(decode_error)? != 0 {
+        return Err(Error::Corrupt("Trailing voxel data".to_owned()));
+    }
+
+    Ok(storage)
+}
+
+/// Number of bytes per palette index.
+fn index_width(palette_len: usize) -> usize {
+    match palette_len {
+        0..=0x100 => 1,
+        0x101..=0x10000 => 2,
+        _ => 4,
+    }
+}
+
+/// Iterates over the coordinates in `bounds` in the order they're stored.
+fn coordinates(bounds: &Bounds) -> impl Iterator<Item = Point3<i32>> {
+    let empty = bounds.is_empty();
+    bounds.iter().take_while(move |_| !empty)
+}
+
+/// Invalid compressed data is reported as IO error by the decoder.
+fn decode_error(e: io::Error) -> Error {
+    match e.kind() {
+        io::ErrorKind::InvalidInput | io::ErrorKind::InvalidData => {
+            Error::Corrupt(format!("Invalid compressed voxel data: {}", e))
+        }
+        _ => e.into(),
+    }
+}
+
+fn write_point(writer: &mut impl Write, point: &Point3<i32>) -> io::Result<()> {
+    for x in point.iter() {
+        writer.write_all(&x.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_point(reader: &mut impl Read) -> io::Result<Point3<i32>> {
+    let mut point = Point3::origin();
+    for x in point.iter_mut() {
+        *x = read_u32(reader)? as i32;
+    }
+    Ok(point)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        storage::{
+            OctreeStorage,
+            SparseStorage,
+            VecStorage,
+        },
+        testing::TestVoxel,
+        MortonEncoder,
+    };
+
+    fn sample() -> VecStorage<TestVoxel, MortonEncoder> {
+        let bounds = Bounds::new(Point3::new(-3, -1, 2), Point3::new(9, 7, 20));
+        let mut storage = VecStorage::from_bounds(bounds.clone());
+        for (i, coord) in bounds.iter().enumerate() {
+            *storage.get_mut(&coord).unwrap() = TestVoxel((i % 300) as u16);
+        }
+        storage
+    }
+
+    fn write(storage: &impl VoxelStorage<TestVoxel>) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_to(storage, &mut bytes).unwrap();
+        bytes
+    }
+
+    /// Writes a file with the given bounds, a palette of `TestVoxel(0)` and
+    /// `data` as its palette indices.
+    fn write_raw(min: [i32; 3], max: [i32; 3], data_len: Option<u64>, data: &[u8]) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        let data = encoder.finish().unwrap();
+
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&MAGIC);
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        write_point(&mut bytes, &Point3::from(min)).unwrap();
+        write_point(&mut bytes, &Point3::from(max)).unwrap();
+        bytes.extend_from_slice(&1u32.to_le_bytes());
+        bytes.extend_from_slice(&2u32.to_le_bytes());
+        bytes.extend_from_slice(&[0, 0]);
+        bytes.extend_from_slice(&data_len.unwrap_or(data.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(&data);
+        bytes
+    }
+
+    #[test]
+    fn round_trip() {
+        let storage = sample();
+        let bytes = write(&storage);
+
+        let vec: VecStorage<TestVoxel, MortonEncoder> = read_from(&bytes[..]).unwrap();
+        let sparse: SparseStorage<TestVoxel> = read_from(&bytes[..]).unwrap();
+        assert_eq!(vec.bounds(), storage.bounds());
+        for (coord, voxel) in storage.iter() {
+            assert_eq!(vec.get(&coord), Some(voxel));
+            assert_eq!(sparse.get(&coord), Some(voxel));
+        }
+    }
+
+    #[test]
+    fn round_trip_empty() {
+        let bytes = write(&SparseStorage::<TestVoxel>::new());
+        let storage: VecStorage<TestVoxel, MortonEncoder> = read_from(&bytes[..]).unwrap();
+        assert!(storage.bounds().unwrap().is_empty());
+    }
+
+    #[test]
+    fn truncated() {
+        let bytes = write(&sample());
+
+        for len in 0..bytes.len() {
+            let result =
+                read_from::<TestVoxel, VecStorage<TestVoxel, MortonEncoder>>(&bytes[..len]);
+            assert!(
+                matches!(result, Err(Error::Truncated)),
+                "{} bytes: {:?}",
+                len,
+                result.err()
+            );
+        }
+    }
+
+    #[test]
+    fn corrupt() {
+        let read =
+            |bytes: &[u8]| read_from::<TestVoxel, VecStorage<TestVoxel, MortonEncoder>>(bytes);
+        let bytes = write(&sample());
+
+        let mut invalid = bytes.clone();
+        invalid[0] = b'X';
+        assert!(matches!(read(&invalid), Err(Error::InvalidMagic)));
+
+        let mut invalid = bytes.clone();
+        invalid[4] = 2;
+        assert!(matches!(read(&invalid), Err(Error::UnsupportedVersion(2))));
+
+        let mut invalid = bytes.clone();
+        let len = invalid.len();
+        invalid[len - 8] ^= 0xff;
+        assert!(matches!(read(&invalid), Err(Error::Corrupt(_))));
+
+        let invalid = write_raw([0, 0, 1], [1, 1, 0], None, &[]);
+        assert!(matches!(read(&invalid), Err(Error::Corrupt(_))));
+
+        let invalid = write_raw([0, 0, 0], [1, 1, 1], None, &[1]);
+        assert!(matches!(read(&invalid), Err(Error::Corrupt(_))));
+
+        let invalid = write_raw([0, 0, 0], [1, 1, 1], None, &[0, 0]);
+        assert!(matches!(read(&invalid), Err(Error::Corrupt(_))));
+
+        let invalid = write_raw([0, 0, 0], [1 << 20, 1 << 20, 1], Some(1), &[0]);
+        assert!(matches!(read(&invalid), Err(Error::Corrupt(_))));
+
+        let invalid = write_raw([0, 0, 0], [1 << 16, 1 << 16, 1], Some(u64::MAX), &[0]);
+        assert!(matches!(read(&invalid), Err(Error::Corrupt(_))));
+    }
+
+    #[test]
+    fn unsupported_bounds() {
+        let bytes = write_raw([i32::MAX - 5, 0, 0], [i32::MAX, 1, 1], Some(u64::MAX), &[]);
+        let result = read_from::<TestVoxel, OctreeStorage<TestVoxel>>(&bytes[..]);
+        assert!(matches!(result, Err(Error::Unsupported(_))));
+    }
+}
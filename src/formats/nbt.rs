@@ -0,0 +1,383 @@
+//! Minecraft's Named Binary Tag format, which is used by schematics.
+//!
+//! Only uncompressed NBT is handled here. Strings are expected to be valid
+//! UTF-8, which is the case for everything but unusual characters in
+//! Java's modified UTF-8.
+
+use std::{
+    collections::BTreeMap,
+    convert::TryInto,
+    io::{
+        Read,
+        Write,
+    },
+};
+
+use crate::formats::Error;
+
+/// Maximum nesting of lists and compounds. Deeper nesting is rejected instead
+/// of overflowing the stack.
+const MAX_DEPTH: usize = 512;
+
+/// Upper bound for preallocating arrays, such that a corrupt length doesn't
+/// allocate huge amounts of memory before the input runs out.
+const MAX_PREALLOCATION: usize = 0x10000;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Tag {
+    Byte(i8),
+    Short(i16),
+    Int(i32),
+    Long(i64),
+    Float(f32),
+    Double(f64),
+    ByteArray(Vec<i8>),
+    String(String),
+    /// All elements of a list must have the same type.
+    List(Vec<Tag>),
+    Compound(BTreeMap<String, Tag>),
+    IntArray(Vec<i32>),
+    LongArray(Vec<i64>),
+}
+
+impl Tag {
+    /// Returns the entry of a compound.
+    pub fn get(&self, name: &str) -> Option<&Tag> {
+        match self {
+            Self::Compound(entries) => entries.get(name),
+            _ => None,
+        }
+    }
+
+    pub fn as_int(&self) -> Option<i32> {
+        match self {
+            Self::Byte(x) => Some(*x as i32),
+            Self::Short(x) => Some(*x as i32),
+            Self::Int(x) => Some(*x),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_compound(&self) -> Option<&BTreeMap<String, Tag>> {
+        match self {
+            Self::Compound(entries) => Some(entries),
+            _ => None,
+        }
+    }
+
+    fn id(&self) -> u8 {
+        match self {
+            Self::Byte(_) => 1,
+            Self::Short(_) => 2,
+            Self::Int(_) => 3,
+            Self::Long(_) => 4,
+            Self::Float(_) => 5,
+            Self::Double(_) => 6,
+            Self::ByteArray(_) => 7,
+            Self::String(_) => 8,
+            Self::List(_) => 9,
+            Self::Compound(_) => 10,
+            Self::IntArray(_) => 11,
+            Self::LongArray(_) => 12,
+        }
+    }
+}
+
+/// Reads a named root tag, which must be a compound.
+pub fn read_from(mut reader: impl Read) -> Result<(String, Tag), Error> {
+    let mut reader = TagReader(&mut reader);
+
+    if reader.u8()? != 10 {
+        return Err(Error::Corrupt("Root tag isn't a compound".to_owned()));
+    }
+    let name = reader.string()?;
+    let tag = reader.payload(10, 0)?;
+
+    Ok((name, tag))
+}
+
+/// Writes a named root tag, which must be a compound.
+pub fn write_to(mut writer: impl Write, name: &str, tag: &Tag) -> Result<(), Error> {
+    if tag.id() != 10 {
+        return Err(Error::Unsupported("Root tag isn't a compound".to_owned()));
+    }
+
+    let mut writer = TagWriter(&mut writer);
+    writer.0.write_all(&[10])?;
+    writer.string(name)?;
+    writer.payload(tag)
+}
+
+struct TagReader<R>(R);
+
+impl<R: Read> TagReader<R> {
+    fn bytes<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        let mut buf = [0; N];
+        self.0.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.bytes::<1>()?[0])
+    }
+
+    fn i32(&mut self) -> Result<i32, Error> {
+        Ok(i32::from_be_bytes(self.bytes()?))
+    }
+
+    fn len(&mut self) -> Result<usize, Error> {
+        self.i32()?
+            .try_into()
+            .map_err(|_| Error::Corrupt("Negative length".to_owned()))
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        let len = u16::from_be_bytes(self.bytes()?) as usize;
+        let mut buf = vec![0; len];
+        self.0.read_exact(&mut buf)?;
+        String::from_utf8(buf).map_err(|_| Error::Corrupt("Invalid string".to_owned()))
+    }
+
+    fn array<T>(
+        &mut self,
+        mut element: impl FnMut(&mut Self) -> Result<T, Error>,
+    ) -> Result<Vec<T>, Error> {
+        let len = self.len()?;
+        let mut elements = Vec::with_capacity(len.min(MAX_PREALLOCATION));
+        for _ in 0..len {
+            elements.push(element(self)?);
+        }
+        Ok(elements)
+    }
+
+    fn payload(&mut self, id: u8, depth: usize) -> Result<Tag, Error> {
+        if depth > MAX_DEPTH {
+            return Err(Error::Corrupt("Tags nested too deeply".to_owned()));
+        }
+
+        Ok(match id {
+            1 => Tag::Byte(i8::from_be_bytes(self.bytes()?)),
+            2 => Tag::Short(i16::from_be_bytes(self.bytes()?)),
+            3 => Tag::Int(self.i32()?),
+            4 => Tag::Long(i64::from_be_bytes(self.bytes()?)),
+            5 => Tag::Float(f32::from_be_bytes(self.bytes()?)),
+            6 => Tag::Double(f64::from_be_bytes(self.bytes()?)),
+            7 => Tag::ByteArray(self.array(|r| Ok(i8::from_be_bytes(r.bytes()?)))?),
+            8 => Tag::String(self.string()?),
+            9 => {
+                let id = self.u8()?;
+                let elements = self.array(|r| r.payload(id, depth + 1))?;
+                if id == 0 && !elements.is_empty() {
+                    return Err(Error::Corrupt("List of end tags".to_owned()));
+                }
+                Tag::List(elements)
+            }
+            10 => {
+                let mut entries = BTreeMap::new();
+                loop {
+                    let id = self.u8()?;
+                    if id == 0 {
+                        break;
+                    }
+                    let name = self.string()?;
+                    entries.insert(name, self.payload(id, depth + 1)?);
+                }
+                Tag::Compound(entries)
+            }
+            11 => Tag::IntArray(self.array(|r| r.i32())?),
+            12 => Tag::LongArray(self.array(|r| Ok(i64::from_be_bytes(r.bytes()?)))?),
+            _ => return Err(Error::Corrupt(format!("Invalid tag type {}", id))),
+        })
+    }
+}
+
+struct TagWriter<W>(W);
+
+impl<W: Write> TagWriter<W> {
+    fn len(&mut self, len: usize) -> Result<(), Error> {
+        let len: i32 = len
+            .try_into()
+            .map_err(|_| Error::Unsupported("Array too long".to_owned()))?;
+        self.0.write_all(&len.to_be_bytes())?;
+        Ok(())
+    }
+
+    fn string(&mut self, s: &str) -> Result<(), Error> {
+        let len: u16 = s
+            .len()
+            .try_into()
+            .map_err(|_| Error::Unsupported("String too long".to_owned()))?;
+        self.0.write_all(&len.to_be_bytes())?;
+        self.0.write_all(s.as_bytes())?;
+        Ok(())
+    }
+
+    fn payload(&mut self, tag: &Tag) -> Result<(), Error> {
+        match tag {
+            Tag::Byte(x) => self.0.write_all(&x.to_be_bytes())?,
+            Tag::Short(x) => self.0.write_all(&x.to_be_bytes())?,
+            Tag::Int(x) => self.0.write_all(&x.to_be_bytes())?,
+            Tag::Long(x) => self.0.write_all(&x.to_be_bytes())?,
+            Tag::Float(x) => self.0.write_all(&x.to_be_bytes())?,
+            Tag::Double(x) => self.0.write_all(&x.to_be_bytes())?,
+            Tag::ByteArray(xs) => {
+                self.len(xs.len())?;
+                let bytes = xs.iter().map(|x| *x as u8).collect::<Vec<_>>();
+                self.0.write_all(&bytes)?;
+            }
+            Tag::String(s) => self.string(s)?,
+            Tag::List(elements) => {
+                let id = elements.first().map_or(0, Tag::id);
+                if elements.iter().any(|element| element.id() != id) {
+                    return Err(Error::Unsupported(
+                        "List with elements of different types".to_owned(),
+                    ));
+                }
+
+                self.0.write_all(&[id])?;
+                self.len(elements.len())?;
+                for element in elements {
+                    self.payload(element)?;
+                }
+            }
+            Tag::Compound(entries) => {
+                for (name, tag) in entries {
+                    self.0.write_all(&[tag.id()])?;
+                    self.string(name)?;
+                    self.payload(tag)?;
+                }
+                self.0.write_all(&[0])?;
+            }
+            Tag::IntArray(xs) => {
+                self.len(xs.len())?;
+                for x in xs {
+                    self.0.write_all(&x.to_be_bytes())?;
+                }
+            }
+            Tag::LongArray(xs) => {
+                self.len(xs.len())?;
+                for x in xs {
+                    self.0.write_all(&x.to_be_bytes())?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Tag {
+        let mut nested = BTreeMap::new();
+        nested.insert("name".to_owned(), Tag::String("stone".to_owned()));
+        nested.insert("empty".to_owned(), Tag::List(vec![]));
+
+        let mut entries = BTreeMap::new();
+        entries.insert("byte".to_owned(), Tag::Byte(-3));
+        entries.insert("short".to_owned(), Tag::Short(-300));
+        entries.insert("int".to_owned(), Tag::Int(1 << 20));
+        entries.insert("long".to_owned(), Tag::Long(-(1 << 40)));
+        entries.insert("float".to_owned(), Tag::Float(1.5));
+        entries.insert("double".to_owned(), Tag::Double(-0.25));
+        entries.insert("bytes".to_owned(), Tag::ByteArray(vec![-1, 0, 1]));
+        entries.insert("string".to_owned(), Tag::String("äöü".to_owned()));
+        entries.insert(
+            "list".to_owned(),
+            Tag::List(vec![Tag::Short(1), Tag::Short(2)]),
+        );
+        entries.insert("compound".to_owned(), Tag::Compound(nested));
+        entries.insert("ints".to_owned(), Tag::IntArray(vec![i32::MIN, 7]));
+        entries.insert("longs".to_owned(), Tag::LongArray(vec![i64::MAX]));
+        Tag::Compound(entries)
+    }
+
+    fn write(tag: &Tag) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_to(&mut bytes, "root", tag).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn round_trip() {
+        let tag = sample();
+        let bytes = write(&tag);
+        assert_eq!(read_from(&bytes[..]).unwrap(), ("root".to_owned(), tag));
+    }
+
+    #[test]
+    fn truncated() {
+        let bytes = write(&sample());
+        for len in 0..bytes.len() {
+            assert!(matches!(read_from(&bytes[..len]), Err(Error::Truncated)));
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_tags() {
+        // Root tag isn't a compound.
+        assert!(matches!(
+            read_from(&[1, 0, 0, 5][..]),
+            Err(Error::Corrupt(_))
+        ));
+        // Unknown tag type.
+        assert!(matches!(
+            read_from(&[10, 0, 0, 13, 0, 1, b'x', 0][..]),
+            Err(Error::Corrupt(_))
+        ));
+        // Unknown element type of a list.
+        assert!(matches!(
+            read_from(&[10, 0, 0, 9, 0, 1, b'x', 13, 0, 0, 0, 1, 0][..]),
+            Err(Error::Corrupt(_))
+        ));
+        // List of end tags.
+        assert!(matches!(
+            read_from(&[10, 0, 0, 9, 0, 1, b'x', 0, 0, 0, 0, 1, 0][..]),
+            Err(Error::Corrupt(_))
+        ));
+        // Negative length.
+        assert!(matches!(
+            read_from(&[10, 0, 0, 7, 0, 1, b'x', 0xff, 0xff, 0xff, 0xff, 0][..]),
+            Err(Error::Corrupt(_))
+        ));
+        // Invalid UTF-8.
+        assert!(matches!(
+            read_from(&[10, 0, 1, 0xff, 0][..]),
+            Err(Error::Corrupt(_))
+        ));
+
+        let mut deep = vec![10, 0, 0];
+        for _ in 0..=MAX_DEPTH {
+            deep.extend_from_slice(&[10, 0, 0]);
+        }
+        assert!(matches!(read_from(&deep[..]), Err(Error::Corrupt(_))));
+    }
+
+    #[test]
+    fn rejects_unsupported_tags() {
+        let mut bytes = Vec::new();
+        assert!(matches!(
+            write_to(&mut bytes, "root", &Tag::Int(0)),
+            Err(Error::Unsupported(_))
+        ));
+
+        let mut entries = BTreeMap::new();
+        entries.insert(
+            "list".to_owned(),
+            Tag::List(vec![Tag::Int(0), Tag::Short(0)]),
+        );
+        assert!(matches!(
+            write_to(&mut bytes, "root", &Tag::Compound(entries)),
+            Err(Error::Unsupported(_))
+        ));
+    }
+}
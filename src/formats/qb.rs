@@ -0,0 +1,429 @@
+//! Qubicle binary files (`.qb`).
+//!
+//! A Qubicle model consists of named matrices, which are loaded into separate
+//! storages. Each storage covers the region of its matrix in model
+//! coordinates, such that [`QbMatrix::transform`] places the matrices
+//! correctly relative to each other when they're rendered as separate
+//! [`VoxelMap`](crate::VoxelMap)s.
+//!
+//! Colors are passed to and from the mapping callbacks as RGBA. A color with an
+//! alpha of 0 is an empty voxel.
+
+use std::{
+    convert::TryInto,
+    io::{
+        Read,
+        Write,
+    },
+};
+
+use amethyst_core::{
+    math::{
+        Point3,
+        Vector3,
+    },
+    Transform,
+};
+
+use crate::{
+    bounds::Bounds,
+    formats::{
+        storage_from_bounds,
+        Error,
+        MAX_VOLUME,
+    },
+    storage::{
+        FromBounds,
+        VoxelStorage,
+    },
+    Voxel,
+};
+
+/// Version of the format, which is the only one that exists.
+pub const VERSION: [u8; 4] = [1, 1, 0, 0];
+
+/// Marks a run of identical voxels in compressed matrices.
+const CODE_FLAG: u32 = 2;
+
+/// Marks the end of a z-slice in compressed matrices.
+const NEXT_SLICE_FLAG: u32 = 6;
+
+/// Index of the first voxel in the matrix, number of voxels and color of a run
+/// of voxels with the same color. Voxels are indexed with x varying fastest,
+/// then y, then z.
+type ColorRun = (usize, usize, [u8; 4]);
+
+/// A named part of a Qubicle model.
+#[derive(Clone, Debug)]
+pub struct QbMatrix<S> {
+    pub name: String,
+    pub storage: S,
+}
+
+impl<S> QbMatrix<S> {
+    /// Returns the transform that places this matrix at its position in the
+    /// model, compensating for the centering of [`VoxelMap`](crate::VoxelMap).
+    pub fn transform<V: Voxel>(&self) -> Transform
+    where
+        S: VoxelStorage<V>,
+    {
+        let mut transform = Transform::default();
+        if let Some(bounds) = self.storage.bounds() {
+            transform.set_translation(bounds.center().coords.map(|x| x as f32));
+        }
+        transform
+    }
+}
+
+/// Reads all matrices of a model. `voxel` maps the RGBA colors of non-empty
+/// voxels to voxels.
+///
+/// Models with a left-handed coordinate system are mirrored along the z-axis,
+/// i.e. a voxel at `(x, y, z)` ends up at `(x, y, -z - 1)`.
+pub fn read_from<V, S, F>(mut reader: impl Read, mut voxel: F) -> Result<Vec<QbMatrix<S>>, Error>
+where
+    V: Voxel,
+    S: FromBounds<V>,
+    F: FnMut([u8; 4]) -> V,
+{
+    let version = read_bytes(&mut reader)?;
+    if version != VERSION {
+        return Err(Error::UnsupportedVersion(u32::from_le_bytes(version)));
+    }
+
+    let bgra = match read_u32(&mut reader)? {
+        0 => false,
+        1 => true,
+        format => return Err(Error::Corrupt(format!("Invalid color format {}", format))),
+    };
+    let left_handed = read_u32(&mut reader)? == 0;
+    let compressed = read_u32(&mut reader)? != 0;
+    // With the visibility mask encoded, the alpha channel contains the visible
+    // faces, but it's still 0 for empty voxels.
+    let _visibility_mask = read_u32(&mut reader)?;
+    let num_matrices = read_u32(&mut reader)?;
+
+    let mut matrices = Vec::new();
+    for _ in 0..num_matrices {
+        let name_len = read_bytes::<1>(&mut reader)?[0] as usize;
+        let mut name = vec![0; name_len];
+        reader.read_exact(&mut name)?;
+        let name = String::from_utf8_lossy(&name).into_owned();
+
+        let mut size = Vector3::zeros();
+        for x in size.iter_mut() {
+            *x = read_u32(&mut reader)? as usize;
+        }
+        let mut position = Point3::origin();
+        for x in position.iter_mut() {
+            *x = read_u32(&mut reader)? as i32;
+        }
+
+        let mut max = position;
+        for axis in 0..3 {
+            max[axis] = size[axis]
+                .try_into()
+                .ok()
+                .and_then(|size| position[axis].checked_add(size))
+                .ok_or_else(|| Error::Corrupt(format!("Invalid size of matrix {:?}", name)))?;
+        }
+        // A compressed matrix can describe a whole z-slice with a single run,
+        // so its size can't be checked against the length of its data.
+        let volume = size
+            .iter()
+            .try_fold(1u64, |volume, &x| volume.checked_mul(x as u64))
+            .filter(|&volume| volume <= MAX_VOLUME)
+            .ok_or_else(|| Error::Corrupt(format!("Matrix {:?} too large", name)))?;
+        let bounds = if left_handed {
+            let mirror = |z: i32| {
+                z.checked_neg().ok_or_else(|| {
+                    Error::Corrupt(format!("Position of matrix {:?} can't be mirrored", name))
+                })
+            };
+            Bounds::new(
+                Point3::new(position.x, position.y, mirror(max.z)?),
+                Point3::new(max.x, max.y, mirror(position.z)?),
+            )
+        }
+        else {
+            Bounds::new(position, max)
+        };
+
+        let runs = if compressed {
+            read_compressed(&mut reader, &size)?
+        }
+        else {
+            read_uncompressed(&mut reader, volume as usize)?
+        };
+
+        let mut storage = storage_from_bounds::<V, S>(bounds)?;
+        let slice_len = size.x * size.y;
+        for (start, count, color) in runs {
+            let rgba = if bgra {
+                [color[2], color[1], color[0], color[3]]
+            }
+            else {
+                color
+            };
+            let voxel = voxel(rgba);
+
+            for index in start..start + count {
+                let local = Point3::new(
+                    index % size.x,
+                    index % slice_len / size.x,
+                    index / slice_len,
+                );
+                let mut coord = position + local.coords.map(|x| x as i32);
+                if left_handed {
+                    coord.z = -coord.z - 1;
+                }
+
+                if let Some(v) = storage.get_mut(&coord) {
+                    *v = voxel.clone();
+                }
+            }
+        }
+
+        matrices.push(QbMatrix { name, storage });
+    }
+
+    Ok(matrices)
+}
+
+/// Writes matrices as RLE-compressed model with a right-handed coordinate
+/// system. `color` maps voxels to RGBA colors, or returns `None` for empty
+/// voxels. Each matrix covers the bounds of its storage.
+pub fn write_to<V, S, F>(
+    matrices: &[QbMatrix<S>],
+    mut writer: impl Write,
+    mut color: F,
+) -> Result<(), Error>
+where
+    V: Voxel,
+    S: VoxelStorage<V>,
+    F: FnMut(&V) -> Option<[u8; 4]>,
+{
+    writer.write_all(&VERSION)?;
+    for &x in &[0, 1, 1, 0] {
+        writer.write_all(&u32::to_le_bytes(x))?;
+    }
+    writer.write_all(&(matrices.len() as u32).to_le_bytes())?;
+
+    let default = V::default();
+    for matrix in matrices {
+        let name_len: u8 = matrix
+            .name
+            .len()
+            .try_into()
+            .map_err(|_| Error::Unsupported(format!("Name too long: {:?}", matrix.name)))?;
+        writer.write_all(&[name_len])?;
+        writer.write_all(matrix.name.as_bytes())?;
+
+        let bounds = matrix
+            .storage
+            .bounds()
+            .ok_or_else(|| Error::Unsupported("Storage is unbounded".to_owned()))?;
+        for x in (bounds.max() - bounds.min()).iter() {
+            writer.write_all(&(*x as u32).to_le_bytes())?;
+        }
+        for x in bounds.min().iter() {
+            writer.write_all(&x.to_le_bytes())?;
+        }
+
+        for z in bounds.min().z..bounds.max().z {
+            let mut slice = Vec::new();
+            for y in bounds.min().y..bounds.max().y {
+                for x in bounds.min().x..bounds.max().x {
+                    let voxel = matrix
+                        .storage
+                        .get(&Point3::new(x, y, z))
+                        .unwrap_or(&default);
+                    slice.push(
+                        color(voxel)
+                            .filter(|color| color[3] != 0)
+                            .map_or(0, u32::from_le_bytes),
+                    );
+                }
+            }
+
+            let mut start = 0;
+            while start < slice.len() {
+                let data = slice[start];
+                let count = slice[start..].iter().take_while(|&&x| x == data).count();
+                start += count;
+
+                if count > 2 {
+                    for x in &[CODE_FLAG, count as u32, data] {
+                        writer.write_all(&x.to_le_bytes())?;
+                    }
+                }
+                else {
+                    for _ in 0..count {
+                        writer.write_all(&data.to_le_bytes())?;
+                    }
+                }
+            }
+            writer.write_all(&NEXT_SLICE_FLAG.to_le_bytes())?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads the runs of non-empty voxels of an uncompressed matrix with `volume`
+/// voxels. Neighbouring voxels with the same color are merged into one run.
+fn read_uncompressed(reader: &mut impl Read, volume: usize) -> Result<Vec<ColorRun>, Error> {
+    let mut runs: Vec<ColorRun> = Vec::new();
+
+    for index in 0..volume {
+        let color = read_bytes(reader)?;
+        if color[3] == 0 {
+            continue;
+        }
+
+        match runs.last_mut() {
+            Some((start, count, run_color)) if *start + *count == index && *run_color == color => {
+                *count += 1;
+            }
+            _ => runs.push((index, 1, color)),
+        }
+    }
+
+    Ok(runs)
+}
+
+/// Reads the runs of non-empty voxels of a compressed matrix. Each z-slice is
+/// run-length encoded and terminated by [`NEXT_SLICE_FLAG`].
+fn read_compressed(reader: &mut impl Read, size: &Vector3<usize>) -> Result<Vec<ColorRun>, Error> {
+    let slice_len = size.x * size.y;
+    let mut runs = Vec::new();
+
+    for z in 0..size.z {
+        let mut index = 0;
+
+        loop {
+            let (color, count) = match read_u32(reader)? {
+                NEXT_SLICE_FLAG => break,
+                CODE_FLAG => {
+                    let count = read_u32(reader)? as usize;
+                    (read_bytes(reader)?, count)
+                }
+                data => (data.to_le_bytes(), 1),
+            };
+
+            if count > slice_len - index {
+                return Err(Error::Corrupt("Run exceeds z-slice".to_owned()));
+            }
+
+            if color[3] != 0 && count > 0 {
+                runs.push((z * slice_len + index, count, color));
+            }
+            index += count;
+        }
+    }
+
+    Ok(runs)
+}
+
+fn read_bytes<const N: usize>(reader: &mut impl Read) -> Result<[u8; N], Error> {
+    let mut buf = [0; N];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn read_u32(reader: &mut impl Read) -> Result<u32, Error> {
+    Ok(u32::from_le_bytes(read_bytes(reader)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        storage::{
+            SparseStorage,
+            VecStorage,
+        },
+        testing::TestVoxel,
+        MortonEncoder,
+    };
+
+    /// Writes the header of a model with a single matrix.
+    fn header(left_handed: bool, compressed: bool, size: [u32; 3], position: [i32; 3]) -> Vec<u8> {
+        let mut bytes = VERSION.to_vec();
+        for &x in &[0, !left_handed as u32, compressed as u32, 0, 1] {
+            bytes.extend_from_slice(&x.to_le_bytes());
+        }
+        bytes.extend_from_slice(&[1, b'm']);
+        for x in &size {
+            bytes.extend_from_slice(&x.to_le_bytes());
+        }
+        for x in &position {
+            bytes.extend_from_slice(&x.to_le_bytes());
+        }
+        bytes
+    }
+
+    fn read<S: FromBounds<TestVoxel>>(bytes: &[u8]) -> Result<Vec<QbMatrix<S>>, Error> {
+        read_from(bytes, |color| TestVoxel(color[0].into()))
+    }
+
+    #[test]
+    fn read_left_handed() {
+        let mut bytes = header(true, false, [2, 1, 2], [1, 0, 3]);
+        for &color in &[[1, 0, 0, 255], [0; 4], [1, 0, 0, 255], [2, 0, 0, 255]] {
+            bytes.extend_from_slice(&color);
+        }
+
+        let matrices: Vec<QbMatrix<VecStorage<TestVoxel, MortonEncoder>>> = read(&bytes).unwrap();
+        let storage = &matrices[0].storage;
+        assert_eq!(
+            storage.bounds(),
+            Some(Bounds::new(Point3::new(1, 0, -5), Point3::new(3, 1, -3)))
+        );
+        assert_eq!(storage.get(&Point3::new(1, 0, -4)), Some(&TestVoxel(1)));
+        assert_eq!(storage.get(&Point3::new(2, 0, -4)), Some(&TestVoxel(0)));
+        assert_eq!(storage.get(&Point3::new(1, 0, -5)), Some(&TestVoxel(1)));
+        assert_eq!(storage.get(&Point3::new(2, 0, -5)), Some(&TestVoxel(2)));
+    }
+
+    #[test]
+    fn read_large_run() {
+        let mut bytes = header(false, true, [1 << 8, 1 << 8, 1], [0, 0, 0]);
+        for &x in &[
+            CODE_FLAG,
+            1 << 16,
+            u32::from_le_bytes([3, 0, 0, 255]),
+            NEXT_SLICE_FLAG,
+        ] {
+            bytes.extend_from_slice(&x.to_le_bytes());
+        }
+
+        let matrices: Vec<QbMatrix<SparseStorage<TestVoxel>>> = read(&bytes).unwrap();
+        let storage = &matrices[0].storage;
+        assert_eq!(storage.get(&Point3::new(0, 0, 0)), Some(&TestVoxel(3)));
+        assert_eq!(storage.get(&Point3::new(255, 255, 0)), Some(&TestVoxel(3)));
+    }
+
+    #[test]
+    fn corrupt() {
+        let read = |bytes: &[u8]| read::<SparseStorage<TestVoxel>>(bytes).map(|_| ());
+
+        let bytes = header(false, true, [1 << 16, 1 << 16, 1], [0, 0, 0]);
+        assert!(matches!(read(&bytes), Err(Error::Corrupt(_))));
+
+        let bytes = header(false, true, [1, 1, 1], [i32::MAX, 0, 0]);
+        assert!(matches!(read(&bytes), Err(Error::Corrupt(_))));
+
+        let bytes = header(true, true, [0, 0, 0], [0, 0, i32::MIN]);
+        assert!(matches!(read(&bytes), Err(Error::Corrupt(_))));
+
+        let mut bytes = header(false, true, [2, 2, 1], [0, 0, 0]);
+        for &x in &[CODE_FLAG, 5, 0, NEXT_SLICE_FLAG] {
+            bytes.extend_from_slice(&x.to_le_bytes());
+        }
+        assert!(matches!(read(&bytes), Err(Error::Corrupt(_))));
+
+        let bytes = header(false, false, [2, 2, 1], [0, 0, 0]);
+        assert!(matches!(read(&bytes), Err(Error::Truncated)));
+    }
+}
@@ -0,0 +1,358 @@
+//! Sponge schematics (`.schem`), as used by WorldEdit and other Minecraft
+//! tools.
+//!
+//! Versions 1 to 3 of the format can be read, version 2 is written. Blocks are
+//! identified by their block state, e.g. `minecraft:oak_stairs[facing=north]`,
+//! which is mapped to voxels by a callback. Block entities, entities and
+//! biomes are ignored.
+
+use std::{
+    collections::{
+        BTreeMap,
+        HashMap,
+    },
+    convert::TryInto,
+    io::{
+        Read,
+        Write,
+    },
+};
+
+use amethyst_core::math::{
+    Point3,
+    Vector3,
+};
+use flate2::{
+    read::GzDecoder,
+    write::GzEncoder,
+    Compression,
+};
+
+use crate::{
+    bounds::Bounds,
+    formats::{
+        nbt::{
+            self,
+            Tag,
+        },
+        storage_from_bounds,
+        Error,
+    },
+    storage::{
+        FromBounds,
+        VoxelStorage,
+    },
+    Voxel,
+};
+
+/// Version of the format written by [`write_to`].
+pub const VERSION: i32 = 2;
+
+/// Minecraft data version written by [`write_to`], which is the one of
+/// Minecraft 1.16.5.
+pub const DATA_VERSION: i32 = 2586;
+
+/// Reads a gzip-compressed schematic into a new storage. `block` maps block
+/// states to voxels and is called once per entry of the palette. Voxels
+/// equal to `V::default()` aren't written to the storage.
+///
+/// The offset stored in the schematic is used as the minimum corner of the
+/// bounds of the storage.
+pub fn read_from<V, S, F>(reader: impl Read, mut block: F) -> Result<S, Error>
+where
+    V: Voxel + PartialEq,
+    S: FromBounds<V>,
+    F: FnMut(&str) -> V,
+{
+    let (_, root) = nbt::read_from(GzDecoder::new(reader))?;
+
+    // Version 3 wraps everything in a `Schematic` compound and moves the
+    // blocks into their own compound.
+    let schematic = root.get("Schematic").unwrap_or(&root);
+    let version = field(schematic, "Version")?
+        .as_int()
+        .ok_or_else(|| invalid("Version"))?;
+    let (palette, data) = match version {
+        1 | 2 => (field(schematic, "Palette")?, field(schematic, "BlockData")?),
+        3 => {
+            let blocks = field(schematic, "Blocks")?;
+            (field(blocks, "Palette")?, field(blocks, "Data")?)
+        }
+        _ => return Err(Error::UnsupportedVersion(version as u32)),
+    };
+
+    let mut dimensions = Vector3::zeros();
+    for (axis, name) in ["Width", "Height", "Length"].iter().enumerate() {
+        dimensions[axis] = field(schematic, name)?
+            .as_int()
+            .ok_or_else(|| invalid(name))? as u16 as i32;
+    }
+
+    let origin = match schematic.get("Offset") {
+        Some(Tag::IntArray(offset)) if offset.len() == 3 => {
+            Point3::new(offset[0], offset[1], offset[2])
+        }
+        Some(_) => return Err(invalid("Offset")),
+        None => Point3::origin(),
+    };
+
+    let palette = palette.as_compound().ok_or_else(|| invalid("Palette"))?;
+    let mut voxels: Vec<Option<V>> = vec![None; palette.len()];
+    for (state, index) in palette {
+        let voxel = index
+            .as_int()
+            .and_then(|index| voxels.get_mut(index as usize))
+            .ok_or_else(|| invalid("Palette"))?;
+        *voxel = Some(block(state));
+    }
+
+    let data = match data {
+        Tag::ByteArray(data) => data,
+        _ => return Err(invalid("BlockData")),
+    };
+
+    let mut max = origin;
+    for axis in 0..3 {
+        max[axis] = origin[axis]
+            .checked_add(dimensions[axis])
+            .ok_or_else(|| invalid("Offset"))?;
+    }
+    let bounds = Bounds::new(origin, max);
+
+    // Every block takes at least one byte.
+    let volume = dimensions.iter().map(|&x| x as u64).product::<u64>();
+    if volume > data.len() as u64 {
+        return Err(Error::Corrupt("Block data too short".to_owned()));
+    }
+
+    let default = V::default();
+    let mut storage = storage_from_bounds::<V, S>(bounds)?;
+    let mut bytes = data.iter().map(|&x| x as u8);
+
+    // Blocks are ordered with x varying fastest, then z, then y.
+    for y in 0..dimensions.y {
+        for z in 0..dimensions.z {
+            for x in 0..dimensions.x {
+                let index = read_varint(&mut bytes)?;
+                let voxel = voxels.get(index).and_then(Option::as_ref).ok_or_else(|| {
+                    Error::Corrupt(format!("Palette index {} out of range", index))
+                })?;
+
+                if *voxel != default {
+                    let coord = origin + Vector3::new(x, y, z);
+                    *storage.get_mut(&coord).ok_or_else(|| {
+                        Error::Unsupported(format!("Storage doesn't contain {:?}", coord))
+                    })? = voxel.clone();
+                }
+            }
+        }
+    }
+
+    if bytes.next().is_some() {
+        return Err(Error::Corrupt("Trailing block data".to_owned()));
+    }
+
+    Ok(storage)
+}
+
+/// Writes a bounded storage as gzip-compressed schematic. `block` maps voxels
+/// to block states, e.g. `minecraft:air` for empty voxels.
+pub fn write_to<V, S, F, B>(storage: &S, writer: impl Write, mut block: F) -> Result<(), Error>
+where
+    V: Voxel,
+    S: VoxelStorage<V>,
+    F: FnMut(&V) -> B,
+    B: AsRef<str>,
+{
+    let bounds = storage
+        .bounds()
+        .ok_or_else(|| Error::Unsupported("Storage is unbounded".to_owned()))?;
+    let dimensions = (bounds.max() - bounds.min())
+        .iter()
+        .map(|&x| x.try_into())
+        .collect::<Result<Vec<u16>, _>>()
+        .map_err(|_| {
+            Error::Unsupported("Schematics can't be larger than 65535 blocks".to_owned())
+        })?;
+
+    let default = V::default();
+    let mut palette = HashMap::new();
+    let mut data = Vec::new();
+
+    for y in bounds.min().y..bounds.max().y {
+        for z in bounds.min().z..bounds.max().z {
+            for x in bounds.min().x..bounds.max().x {
+                let voxel = storage.get(&Point3::new(x, y, z)).unwrap_or(&default);
+                let state = block(voxel);
+                let len = palette.len();
+                let index = match palette.get(state.as_ref()) {
+                    Some(&index) => index,
+                    None => *palette.entry(state.as_ref().to_owned()).or_insert(len),
+                };
+                write_varint(&mut data, index);
+            }
+        }
+    }
+
+    let palette_max = palette.len() as i32;
+    let palette = palette
+        .into_iter()
+        .map(|(state, index)| (state, Tag::Int(index as i32)))
+        .collect();
+    let offset = bounds.min();
+
+    let mut schematic = BTreeMap::new();
+    schematic.insert("Version".to_owned(), Tag::Int(VERSION));
+    schematic.insert("DataVersion".to_owned(), Tag::Int(DATA_VERSION));
+    schematic.insert("Width".to_owned(), Tag::Short(dimensions[0] as i16));
+    schematic.insert("Height".to_owned(), Tag::Short(dimensions[1] as i16));
+    schematic.insert("Length".to_owned(), Tag::Short(dimensions[2] as i16));
+    schematic.insert(
+        "Offset".to_owned(),
+        Tag::IntArray(vec![offset.x, offset.y, offset.z]),
+    );
+    schematic.insert("PaletteMax".to_owned(), Tag::Int(palette_max));
+    schematic.insert("Palette".to_owned(), Tag::Compound(palette));
+    schematic.insert(
+        "BlockData".to_owned(),
+        Tag::ByteArray(data.into_iter().map(|x| x as i8).collect()),
+    );
+
+    let mut encoder = GzEncoder::new(writer, Compression::default());
+    nbt::write_to(&mut encoder, "Schematic", &Tag::Compound(schematic))?;
+    encoder.finish()?;
+
+    Ok(())
+}
+
+fn field<'a>(tag: &'a Tag, name: &str) -> Result<&'a Tag, Error> {
+    tag.get(name)
+        .ok_or_else(|| Error::Corrupt(format!("Missing {}", name)))
+}
+
+fn invalid(name: &str) -> Error {
+    Error::Corrupt(format!("Invalid {}", name))
+}
+
+/// Reads an unsigned LEB128 varint, as used for the block data.
+fn read_varint(bytes: &mut impl Iterator<Item = u8>) -> Result<usize, Error> {
+    let mut value = 0;
+
+    for shift in (0..32).step_by(7) {
+        let byte = bytes
+            .next()
+            .ok_or_else(|| Error::Corrupt("Block data too short".to_owned()))?;
+        value |= ((byte & 0x7f) as usize) << shift;
+
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+
+    Err(Error::Corrupt("Varint too long".to_owned()))
+}
+
+fn write_varint(data: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        data.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    data.push(value as u8);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        storage::VecStorage,
+        testing::TestVoxel,
+        FlatEncoder,
+    };
+
+    fn block(voxel: &TestVoxel) -> String {
+        format!("test:block_{}", voxel.0)
+    }
+
+    fn voxel(state: &str) -> TestVoxel {
+        TestVoxel(state.trim_start_matches("test:block_").parse().unwrap())
+    }
+
+    /// Writes a schematic with the given palette and block data.
+    fn write_raw(dimensions: [i16; 3], palette: &[&str], data: Vec<i8>) -> Vec<u8> {
+        let mut schematic = BTreeMap::new();
+        schematic.insert("Version".to_owned(), Tag::Int(2));
+        for (name, &x) in ["Width", "Height", "Length"].iter().zip(&dimensions) {
+            schematic.insert((*name).to_owned(), Tag::Short(x));
+        }
+        schematic.insert(
+            "Palette".to_owned(),
+            Tag::Compound(
+                palette
+                    .iter()
+                    .enumerate()
+                    .map(|(i, state)| ((*state).to_owned(), Tag::Int(i as i32)))
+                    .collect(),
+            ),
+        );
+        schematic.insert("BlockData".to_owned(), Tag::ByteArray(data));
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        nbt::write_to(&mut encoder, "Schematic", &Tag::Compound(schematic)).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn read(bytes: &[u8]) -> Result<VecStorage<TestVoxel, FlatEncoder>, Error> {
+        read_from(bytes, voxel)
+    }
+
+    #[test]
+    fn round_trip() {
+        // More than 128 block states, so palette indices take two bytes.
+        let bounds = Bounds::new(Point3::new(-2, -1, 3), Point3::new(10, 6, 8));
+        let mut storage = VecStorage::<TestVoxel, FlatEncoder>::from_bounds(bounds.clone());
+        for (i, coord) in bounds.iter().enumerate() {
+            *storage.get_mut(&coord).unwrap() = TestVoxel((i % 300) as u16);
+        }
+
+        let mut bytes = Vec::new();
+        write_to(&storage, &mut bytes, block).unwrap();
+        let read = read(&bytes).unwrap();
+
+        assert_eq!(read.bounds(), Some(bounds));
+        for (coord, voxel) in storage.iter() {
+            assert_eq!(read.get(&coord), Some(voxel));
+        }
+    }
+
+    #[test]
+    fn varints() {
+        for &value in &[0, 1, 0x7f, 0x80, 0x3fff, 0x4000, 1 << 28] {
+            let mut data = Vec::new();
+            write_varint(&mut data, value);
+            let mut bytes = data.into_iter();
+            assert_eq!(read_varint(&mut bytes).unwrap(), value);
+            assert_eq!(bytes.next(), None);
+        }
+
+        let mut bytes = vec![0x80u8; 5].into_iter();
+        assert!(matches!(read_varint(&mut bytes), Err(Error::Corrupt(_))));
+    }
+
+    #[test]
+    fn corrupt() {
+        let palette = ["test:block_0", "test:block_1"];
+
+        // Block data too short.
+        let bytes = write_raw([2, 1, 1], &palette, vec![1]);
+        assert!(matches!(read(&bytes), Err(Error::Corrupt(_))));
+        // Unterminated varint.
+        let bytes = write_raw([2, 1, 1], &palette, vec![1, -1]);
+        assert!(matches!(read(&bytes), Err(Error::Corrupt(_))));
+        // Trailing data.
+        let bytes = write_raw([1, 1, 1], &palette, vec![1, 0]);
+        assert!(matches!(read(&bytes), Err(Error::Corrupt(_))));
+        // Palette index out of range.
+        let bytes = write_raw([1, 1, 1], &palette, vec![2]);
+        assert!(matches!(read(&bytes), Err(Error::Corrupt(_))));
+    }
+}
@@ -0,0 +1,318 @@
+//! Stacks of PNG images, one per z-slice of a map.
+//!
+//! This makes it possible to edit the layers of a map in any paint program.
+//! The pixel at `(x, y)` of the `i`-th image is the voxel at
+//! `(x, height - y - 1, i)`, such that the images are upright when y points
+//! up. Colors are passed to and from the mapping callbacks as RGBA. A color
+//! with an alpha of 0 is an empty voxel.
+
+use std::{
+    fs::{
+        self,
+        File,
+    },
+    io::{
+        BufReader,
+        BufWriter,
+        Read,
+        Write,
+    },
+    path::Path,
+};
+
+use amethyst_core::math::Point3;
+use image::{
+    codecs::png::{
+        PngDecoder,
+        PngEncoder,
+    },
+    ColorType,
+    DynamicImage,
+    RgbaImage,
+};
+
+use crate::{
+    bounds::Bounds,
+    formats::{
+        storage_from_bounds,
+        Error,
+    },
+    storage::{
+        FromBounds,
+        VoxelStorage,
+    },
+    Voxel,
+};
+
+/// Reads a stack of PNG images into a new storage, starting with the slice at
+/// `z = 0`. All images must have the same size. `voxel` maps the RGBA colors
+/// of non-empty voxels to voxels.
+pub fn read_from<V, S, F, R>(slices: impl IntoIterator<Item = R>, voxel: F) -> Result<S, Error>
+where
+    V: Voxel,
+    S: FromBounds<V>,
+    F: FnMut([u8; 4]) -> V,
+    R: Read,
+{
+    let slices = slices
+        .into_iter()
+        .map(read_png)
+        .collect::<Result<Vec<_>, Error>>()?;
+    to_storage(&slices, voxel)
+}
+
+/// Reads all PNG images in a directory in the order of their file names.
+pub fn read_dir<V, S, F>(path: impl AsRef<Path>, voxel: F) -> Result<S, Error>
+where
+    V: Voxel,
+    S: FromBounds<V>,
+    F: FnMut([u8; 4]) -> V,
+{
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let path = entry?.path();
+        let is_png = path
+            .extension()
+            .map_or(false, |extension| extension.eq_ignore_ascii_case("png"));
+        if is_png && path.is_file() {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    let slices = paths
+        .iter()
+        .map(|path| read_png(BufReader::new(File::open(path)?)))
+        .collect::<Result<Vec<_>, Error>>()?;
+    to_storage(&slices, voxel)
+}
+
+/// Writes a bounded storage as stack of PNG images. `slice` is called with the
+/// index of each slice, starting at the minimum z-coordinate of the storage,
+/// and returns the writer for its image. `color` maps voxels to RGBA colors,
+/// or returns `None` for empty voxels.
+pub fn write_to<V, S, W, G, F>(storage: &S, mut slice: G, mut color: F) -> Result<(), Error>
+where
+    V: Voxel,
+    S: VoxelStorage<V>,
+    W: Write,
+    G: FnMut(usize) -> Result<W, Error>,
+    F: FnMut(&V) -> Option<[u8; 4]>,
+{
+    let bounds = storage
+        .bounds()
+        .ok_or_else(|| Error::Unsupported("Storage is unbounded".to_owned()))?;
+    let (min, max) = (bounds.min(), bounds.max());
+    let width = (max.x - min.x) as u32;
+    let height = (max.y - min.y) as u32;
+    if (width == 0 || height == 0) && min.z < max.z {
+        return Err(Error::Unsupported(
+            "Slices without pixels can't be written as PNG images".to_owned(),
+        ));
+    }
+
+    let default = V::default();
+    for (index, z) in (min.z..max.z).enumerate() {
+        let mut image = RgbaImage::new(width, height);
+        for (x, y, pixel) in image.enumerate_pixels_mut() {
+            let coord = Point3::new(min.x + x as i32, max.y - y as i32 - 1, z);
+            let voxel = storage.get(&coord).unwrap_or(&default);
+            pixel.0 = color(voxel)
+                .filter(|color| color[3] != 0)
+                .unwrap_or_default();
+        }
+
+        let mut writer = slice(index)?;
+        PngEncoder::new(&mut writer).encode(&image, width, height, ColorType::Rgba8)?;
+        writer.flush()?;
+    }
+
+    Ok(())
+}
+
+/// Writes a bounded storage as stack of PNG images into a directory, which is
+/// created if necessary. The images are named by the zero-padded index of
+/// their slice, e.g. `0000.png`, such that [`read_dir`] reads them in the
+/// right order.
+pub fn write_dir<V, S, F>(storage: &S, path: impl AsRef<Path>, color: F) -> Result<(), Error>
+where
+    V: Voxel,
+    S: VoxelStorage<V>,
+    F: FnMut(&V) -> Option<[u8; 4]>,
+{
+    let path = path.as_ref();
+    fs::create_dir_all(path)?;
+
+    let num_slices = storage
+        .bounds()
+        .map_or(0, |bounds| bounds.max().z - bounds.min().z);
+    let digits = num_slices.to_string().len().max(4);
+
+    write_to(
+        storage,
+        |index| {
+            let file = File::create(path.join(format!("{:01$}.png", index, digits)))?;
+            Ok(BufWriter::new(file))
+        },
+        color,
+    )
+}
+
+fn read_png(reader: impl Read) -> Result<RgbaImage, Error> {
+    Ok(DynamicImage::from_decoder(PngDecoder::new(reader)?)?.to_rgba8())
+}
+
+fn to_storage<V, S, F>(slices: &[RgbaImage], mut voxel: F) -> Result<S, Error>
+where
+    V: Voxel,
+    S: FromBounds<V>,
+    F: FnMut([u8; 4]) -> V,
+{
+    let (width, height) = slices.first().map_or((0, 0), RgbaImage::dimensions);
+    for (z, slice) in slices.iter().enumerate() {
+        if slice.dimensions() != (width, height) {
+            return Err(Error::Corrupt(format!(
+                "Slice {} is {:?} pixels, but slice 0 is {:?} pixels",
+                z,
+                slice.dimensions(),
+                (width, height)
+            )));
+        }
+    }
+
+    let max = Point3::new(width, height, slices.len() as u32).map(|x| x as i32);
+    let mut storage = storage_from_bounds::<V, S>(Bounds::new(Point3::origin(), max))?;
+
+    for (z, slice) in slices.iter().enumerate() {
+        for (x, y, color) in slice.enumerate_pixels() {
+            if color[3] != 0 {
+                let coord = Point3::new(x, height - y - 1, z as u32).map(|x| x as i32);
+                if let Some(v) = storage.get_mut(&coord) {
+                    *v = voxel(color.0);
+                }
+            }
+        }
+    }
+
+    Ok(storage)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::RefCell,
+        io,
+    };
+
+    use super::*;
+    use crate::{
+        storage::VecStorage,
+        testing::{
+            assert_same_voxels,
+            TestVoxel,
+        },
+        FlatEncoder,
+    };
+
+    /// Appends everything written to the last of the encoded slices.
+    struct SliceWriter<'a>(&'a RefCell<Vec<Vec<u8>>>);
+
+    impl Write for SliceWriter<'_> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0
+                .borrow_mut()
+                .last_mut()
+                .unwrap()
+                .extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn color(voxel: &TestVoxel) -> Option<[u8; 4]> {
+        (voxel.0 != 0).then(|| [voxel.0 as u8, 0, 0, 255])
+    }
+
+    fn voxel(color: [u8; 4]) -> TestVoxel {
+        TestVoxel(color[0].into())
+    }
+
+    fn write(storage: &VecStorage<TestVoxel, FlatEncoder>) -> Result<Vec<Vec<u8>>, Error> {
+        let slices = RefCell::new(Vec::new());
+        write_to(
+            storage,
+            |_| {
+                slices.borrow_mut().push(Vec::new());
+                Ok(SliceWriter(&slices))
+            },
+            color,
+        )?;
+        Ok(slices.into_inner())
+    }
+
+    fn read(slices: &[Vec<u8>]) -> Result<VecStorage<TestVoxel, FlatEncoder>, Error> {
+        read_from(slices.iter().map(|slice| &slice[..]), voxel)
+    }
+
+    fn sample() -> VecStorage<TestVoxel, FlatEncoder> {
+        let bounds = Bounds::new(Point3::origin(), Point3::new(5, 4, 3));
+        let mut storage = VecStorage::from_bounds(bounds.clone());
+        for (i, coord) in bounds.iter().enumerate() {
+            *storage.get_mut(&coord).unwrap() = TestVoxel((i % 7) as u16);
+        }
+        storage
+    }
+
+    #[test]
+    fn round_trip() {
+        let storage = sample();
+        let slices = write(&storage).unwrap();
+        assert_eq!(slices.len(), 3);
+
+        // The top left pixel of the first slice is the voxel at the top of
+        // the map.
+        let image = read_png(&slices[0][..]).unwrap();
+        let top = storage.get(&Point3::new(0, 3, 0)).unwrap();
+        assert_eq!(Some(image.get_pixel(0, 0).0), color(top).or(Some([0; 4])));
+
+        let read = read(&slices).unwrap();
+        assert_same_voxels(&read, &storage);
+    }
+
+    #[test]
+    fn round_trip_dir() {
+        let path =
+            std::env::temp_dir().join(format!("amethyst_voxelmap_slices_{}", std::process::id()));
+        let storage = sample();
+
+        write_dir(&storage, &path, color).unwrap();
+        let read: Result<VecStorage<TestVoxel, FlatEncoder>, _> = read_dir(&path, voxel);
+        fs::remove_dir_all(&path).unwrap();
+
+        assert_same_voxels(&read.unwrap(), &storage);
+    }
+
+    #[test]
+    fn rejects_mismatched_slices() {
+        let mut slices = write(&sample()).unwrap();
+        let bounds = Bounds::new(Point3::origin(), Point3::new(5, 5, 1));
+        slices.extend(write(&VecStorage::from_bounds(bounds)).unwrap());
+
+        assert!(matches!(read(&slices), Err(Error::Corrupt(_))));
+    }
+
+    #[test]
+    fn rejects_empty_slices() {
+        let bounds = Bounds::new(Point3::origin(), Point3::new(0, 4, 3));
+        assert!(matches!(
+            write(&VecStorage::from_bounds(bounds)),
+            Err(Error::Unsupported(_))
+        ));
+
+        let bounds = Bounds::new(Point3::origin(), Point3::new(5, 4, 0));
+        assert_eq!(write(&VecStorage::from_bounds(bounds)).unwrap().len(), 0);
+    }
+}
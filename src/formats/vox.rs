@@ -0,0 +1,1192 @@
+//! MagicaVoxel `.vox` files.
+//!
+//! MagicaVoxel uses a z-up coordinate system, while voxel maps are y-up. A
+//! voxel at `(x, y, z)` in MagicaVoxel ends up at `(x, z, -y - 1)` in the map.
+
+use std::{
+    collections::{
+        HashMap,
+        HashSet,
+    },
+    convert::TryInto,
+    io::{
+        Read,
+        Write,
+    },
+};
+
+use amethyst_core::math::{
+    Matrix3,
+    Point3,
+    Vector3,
+};
+use amethyst_rendy::palette::Srgba;
+
+use crate::{
+    bounds::Bounds,
+    formats::{
+        storage_from_bounds,
+        Error,
+    },
+    storage::{
+        FromBounds,
+        VoxelStorage,
+    },
+    Voxel,
+};
+
+/// Magic number at the start of every `.vox` file.
+pub const MAGIC: [u8; 4] = *b"VOX ";
+
+/// Versions of the format that can be read.
+pub const SUPPORTED_VERSIONS: [u32; 2] = [150, 200];
+
+/// Version of the format written by [`VoxFile::write_to`].
+pub const VERSION: u32 = 150;
+
+/// Maximum edge length of a model.
+pub const MAX_MODEL_SIZE: u32 = 256;
+
+/// Maximum nesting of scene graph nodes. Deeper graphs are rejected to bound
+/// the recursion when flattening the graph.
+const MAX_NODE_DEPTH: usize = 64;
+
+/// Contents of a `.vox` file, with the scene graph flattened into a list of
+/// model instances.
+#[derive(Clone, Debug)]
+pub struct VoxFile {
+    pub models: Vec<VoxModel>,
+    /// Visible instances of the models. Hidden nodes and layers are skipped.
+    pub instances: Vec<VoxInstance>,
+    pub palette: VoxPalette,
+}
+
+/// Model of a `.vox` file.
+#[derive(Clone, Debug)]
+pub struct VoxModel {
+    /// Dimensions of the model in MagicaVoxel coordinates.
+    pub size: Vector3<u32>,
+    /// Position within the model and palette index of every voxel. Palette
+    /// index 0 is empty and never used.
+    pub voxels: Vec<(Point3<u8>, u8)>,
+}
+
+/// Placement of a model in the scene.
+#[derive(Clone, Debug)]
+pub struct VoxInstance {
+    /// Index into [`VoxFile::models`].
+    pub model: usize,
+    pub transform: VoxTransform,
+}
+
+/// Rotation and translation in MagicaVoxel coordinates. A model is centered
+/// before it's transformed, i.e. a voxel at `p` in a model of size `s` is
+/// placed at `rotation * (p - s / 2) + translation`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VoxTransform {
+    /// Rotation with exactly one entry of 1 or -1 in each row and column.
+    pub rotation: Matrix3<i32>,
+    pub translation: Vector3<i32>,
+}
+
+impl VoxTransform {
+    pub fn identity() -> Self {
+        Self {
+            rotation: Matrix3::identity(),
+            translation: Vector3::zeros(),
+        }
+    }
+
+    /// Returns the transform that applies `other` first and then this one.
+    pub fn then(&self, other: &Self) -> Self {
+        Self {
+            rotation: self.rotation * other.rotation,
+            translation: self.rotation * other.translation + self.translation,
+        }
+    }
+
+    pub fn transform_point(&self, point: &Point3<i32>) -> Point3<i32> {
+        Point3::from(self.rotation * point.coords + self.translation)
+    }
+
+    /// Decodes a rotation from the `_r` attribute of a transform node. Bits
+    /// 0-1 and 2-3 are the column of the non-zero entry in the first and
+    /// second row, bits 4-6 are set if the entry in the respective row is
+    /// negative.
+    fn decode_rotation(byte: u8) -> Option<Matrix3<i32>> {
+        let first = (byte & 3) as usize;
+        let second = ((byte >> 2) & 3) as usize;
+
+        if first > 2 || second > 2 || first == second {
+            return None;
+        }
+
+        let sign = |bit: u8| if byte & (1 << bit) != 0 { -1 } else { 1 };
+        let mut rotation = Matrix3::zeros();
+        rotation[(0, first)] = sign(4);
+        rotation[(1, second)] = sign(5);
+        rotation[(2, 3 - first - second)] = sign(6);
+
+        Some(rotation)
+    }
+
+    /// Inverse of [`VoxTransform::decode_rotation`].
+    fn encode_rotation(rotation: &Matrix3<i32>) -> Option<u8> {
+        let mut byte = 0;
+        let mut columns = [false; 3];
+
+        for row in 0..3 {
+            let column = (0..3).find(|&column| rotation[(row, column)] != 0)?;
+            let sign = rotation[(row, column)];
+            let others = (0..3).filter(|&c| c != column && rotation[(row, c)] != 0);
+
+            if columns[column] || sign.abs() != 1 || others.count() > 0 {
+                return None;
+            }
+            columns[column] = true;
+
+            if row < 2 {
+                byte |= (column as u8) << (2 * row);
+            }
+            if sign < 0 {
+                byte |= 1 << (4 + row);
+            }
+        }
+
+        Some(byte)
+    }
+}
+
+/// Colors of the 256 palette entries of a `.vox` file. The default palette
+/// is the one MagicaVoxel uses for files without an `RGBA` chunk.
+///
+/// Voxels that store their palette index can use [`VoxPalette::tint`] to
+/// implement [`Voxel::tint`], e.g. with the palette stored as a resource.
+#[derive(Clone, Debug)]
+pub struct VoxPalette {
+    colors: [Srgba; 256],
+}
+
+impl VoxPalette {
+    pub fn color(&self, index: u8) -> Srgba {
+        self.colors[index as usize]
+    }
+
+    pub fn set_color(&mut self, index: u8, color: Srgba) {
+        self.colors[index as usize] = color;
+    }
+
+    /// Returns the color of a palette entry for all faces of a voxel, or
+    /// `None` for the empty entry 0.
+    pub fn tint(&self, index: u8) -> Option<[Srgba; 6]> {
+        if index == 0 {
+            None
+        }
+        else {
+            Some([self.color(index); 6])
+        }
+    }
+}
+
+impl Default for VoxPalette {
+    fn default() -> Self {
+        let mut colors = [rgba(0, 0, 0, 0); 256];
+        let mut index = 1;
+
+        // 6x6x6 color cube without black, followed by ramps of blue, green,
+        // red and gray.
+        let steps = [0xff, 0xcc, 0x99, 0x66, 0x33, 0x00];
+        for &r in &steps {
+            for &g in &steps {
+                for &b in &steps {
+                    if index < 216 {
+                        colors[index] = rgba(r, g, b, 0xff);
+                        index += 1;
+                    }
+                }
+            }
+        }
+
+        let ramp = [0xee, 0xdd, 0xbb, 0xaa, 0x88, 0x77, 0x55, 0x44, 0x22, 0x11];
+        for (i, &x) in ramp.iter().enumerate() {
+            colors[216 + i] = rgba(0, 0, x, 0xff);
+            colors[226 + i] = rgba(0, x, 0, 0xff);
+            colors[236 + i] = rgba(x, 0, 0, 0xff);
+            colors[246 + i] = rgba(x, x, x, 0xff);
+        }
+
+        Self { colors }
+    }
+}
+
+impl VoxFile {
+    pub fn read_from(mut reader: impl Read) -> Result<Self, Error> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(Error::InvalidMagic);
+        }
+
+        let mut version = [0; 4];
+        reader.read_exact(&mut version)?;
+        let version = u32::from_le_bytes(version);
+        if !SUPPORTED_VERSIONS.contains(&version) {
+            return Err(Error::UnsupportedVersion(version));
+        }
+
+        let mut header = [0; 12];
+        reader.read_exact(&mut header)?;
+        let mut header = ChunkReader(&header);
+        let id = header.id()?;
+        let content_len = header.len()?;
+        let children_len = header.len()?;
+        if &id != b"MAIN" {
+            return Err(Error::Corrupt("Expected MAIN chunk".to_owned()));
+        }
+
+        let mut data = Vec::new();
+        reader
+            .take(content_len as u64 + children_len as u64)
+            .read_to_end(&mut data)?;
+        if data.len() != content_len + children_len {
+            return Err(Error::Truncated);
+        }
+
+        let mut parser = Parser::default();
+        let mut children = ChunkReader(&data[content_len..]);
+        while !children.0.is_empty() {
+            let id = children.id()?;
+            let content_len = children.len()?;
+            let children_len = children.len()?;
+            let content = children.bytes(content_len)?;
+            children.bytes(children_len)?;
+
+            parser.parse_chunk(&id, ChunkReader(content))?;
+        }
+
+        parser.finish()
+    }
+
+    /// Iterates over all non-empty voxels of all instances with their map
+    /// coordinates and palette indices.
+    pub fn voxels(&self) -> impl Iterator<Item = (Point3<i32>, u8)> + '_ {
+        self.instances.iter().flat_map(move |instance| {
+            let model = &self.models[instance.model];
+            let half_size = model.size.map(|x| (x / 2) as i32);
+
+            model.voxels.iter().map(move |(position, index)| {
+                let local = Point3::from(position.coords.map(i32::from) - half_size);
+                let p = instance.transform.transform_point(&local);
+                (Point3::new(p.x, p.z, -p.y - 1), *index)
+            })
+        })
+    }
+
+    /// Returns the bounds of all voxels in map coordinates.
+    pub fn bounds(&self) -> Bounds {
+        self.voxels().fold(Bounds::empty(), |bounds, (coord, _)| {
+            bounds.union(&Bounds::new(coord, coord + Vector3::repeat(1)))
+        })
+    }
+
+    /// Creates a storage containing all voxels of the scene. `voxel` maps
+    /// palette indices to voxels. Voxels not covered by any model are left
+    /// at `V::default()`.
+    ///
+    /// Returns [`Error::Unsupported`] if `S` can't cover the bounds of the
+    /// scene, see [`FromBounds::supports_bounds`].
+    pub fn to_storage<V, S, F>(&self, mut voxel: F) -> Result<S, Error>
+    where
+        V: Voxel,
+        S: FromBounds<V>,
+        F: FnMut(u8) -> V,
+    {
+        let mut storage = storage_from_bounds::<V, S>(self.bounds())?;
+
+        for (coord, index) in self.voxels() {
+            if let Some(v) = storage.get_mut(&coord) {
+                *v = voxel(index);
+            }
+        }
+
+        Ok(storage)
+    }
+
+    /// Converts a storage into a scene. `voxel` maps voxels to their palette
+    /// index and color, or returns `None` for empty voxels. Palette index 0
+    /// is reserved for empty voxels and can't be used. If different colors
+    /// are returned for the same index, the last one is used.
+    ///
+    /// Since models are limited to [`MAX_MODEL_SIZE`] voxels along each axis,
+    /// larger maps are split into multiple models.
+    pub fn from_storage<V, S, F>(storage: &S, mut voxel: F) -> Result<Self, Error>
+    where
+        V: Voxel,
+        S: VoxelStorage<V>,
+        F: FnMut(&V) -> Option<(u8, Srgba)>,
+    {
+        let bounds = storage
+            .bounds()
+            .ok_or_else(|| Error::Unsupported("Storage is unbounded".to_owned()))?;
+
+        // Minimum corner of the map in MagicaVoxel coordinates.
+        let min = Point3::new(bounds.min().x, -bounds.max().z, bounds.min().y);
+        let max = Point3::new(bounds.max().x, -bounds.min().z, bounds.max().y);
+        let model_size = MAX_MODEL_SIZE as i32;
+
+        let mut palette = VoxPalette::default();
+        let mut tiles: HashMap<Point3<i32>, Vec<(Point3<u8>, u8)>> = HashMap::new();
+
+        for (coord, v) in storage.iter() {
+            if let Some((index, color)) = voxel(v) {
+                if index == 0 {
+                    return Err(Error::Unsupported(
+                        "Palette index 0 is reserved for empty voxels".to_owned(),
+                    ));
+                }
+                palette.set_color(index, color);
+
+                let offset = Point3::new(coord.x, -coord.z - 1, coord.y) - min;
+                let tile = offset.map(|x| x.div_euclid(model_size));
+                let position = offset.map(|x| x.rem_euclid(model_size) as u8);
+                tiles
+                    .entry(Point3::from(tile))
+                    .or_default()
+                    .push((Point3::from(position), index));
+            }
+        }
+
+        let mut tiles = tiles.into_iter().collect::<Vec<_>>();
+        tiles.sort_by_key(|(tile, _)| (tile.z, tile.y, tile.x));
+
+        let mut models = Vec::with_capacity(tiles.len());
+        let mut instances = Vec::with_capacity(tiles.len());
+
+        for (tile, voxels) in tiles {
+            let tile_min = min + tile.coords * model_size;
+            let size = (max - tile_min).map(|x| x.min(model_size) as u32);
+
+            instances.push(VoxInstance {
+                model: models.len(),
+                transform: VoxTransform {
+                    rotation: Matrix3::identity(),
+                    translation: tile_min.coords + size.map(|x| (x / 2) as i32),
+                },
+            });
+            models.push(VoxModel { size, voxels });
+        }
+
+        Ok(Self {
+            models,
+            instances,
+            palette,
+        })
+    }
+
+    /// Writes the scene as `.vox` file. The instances are written as
+    /// transform nodes below a single group node.
+    pub fn write_to(&self, mut writer: impl Write) -> Result<(), Error> {
+        let mut children = ChunkWriter::default();
+
+        // MagicaVoxel can't open files without models.
+        let empty = VoxModel {
+            size: Vector3::repeat(1),
+            voxels: vec![],
+        };
+        let models = if self.models.is_empty() {
+            std::slice::from_ref(&empty)
+        }
+        else {
+            &self.models
+        };
+
+        for model in models {
+            if model.size.iter().any(|&x| x == 0 || x > MAX_MODEL_SIZE) {
+                return Err(Error::Unsupported(format!(
+                    "Invalid model size {:?}",
+                    model.size
+                )));
+            }
+
+            let mut content = ChunkWriter::default();
+            for &x in model.size.iter() {
+                content.i32(x as i32);
+            }
+            children.chunk(b"SIZE", &content);
+
+            let mut content = ChunkWriter::default();
+            content.len(model.voxels.len())?;
+            for (position, index) in &model.voxels {
+                if (0..3).any(|axis| position[axis] as u32 >= model.size[axis]) {
+                    return Err(Error::Unsupported(format!(
+                        "Voxel at {:?} outside of model",
+                        position
+                    )));
+                }
+                content
+                    .0
+                    .extend_from_slice(&[position.x, position.y, position.z, *index]);
+            }
+            children.chunk(b"XYZI", &content);
+        }
+
+        let mut root = ChunkWriter::default();
+        root.i32(0);
+        root.dict(&[])?;
+        root.i32(1);
+        root.i32(-1);
+        root.i32(-1);
+        root.i32(1);
+        root.dict(&[])?;
+        children.chunk(b"nTRN", &root);
+
+        let mut group = ChunkWriter::default();
+        group.i32(1);
+        group.dict(&[])?;
+        group.len(self.instances.len())?;
+        for k in 0..self.instances.len() {
+            group.len(2 + 2 * k)?;
+        }
+        children.chunk(b"nGRP", &group);
+
+        for (k, instance) in self.instances.iter().enumerate() {
+            if instance.model >= self.models.len() {
+                return Err(Error::Unsupported(format!(
+                    "Missing model {}",
+                    instance.model
+                )));
+            }
+
+            let transform = &instance.transform;
+            let translation = format!(
+                "{} {} {}",
+                transform.translation.x, transform.translation.y, transform.translation.z
+            );
+            let rotation = VoxTransform::encode_rotation(&transform.rotation)
+                .ok_or_else(|| Error::Unsupported("Invalid rotation".to_owned()))?
+                .to_string();
+
+            let mut node = ChunkWriter::default();
+            node.len(2 + 2 * k)?;
+            node.dict(&[])?;
+            node.len(3 + 2 * k)?;
+            node.i32(-1);
+            node.i32(0);
+            node.i32(1);
+            node.dict(&[("_r", &rotation), ("_t", &translation)])?;
+            children.chunk(b"nTRN", &node);
+
+            let mut shape = ChunkWriter::default();
+            shape.len(3 + 2 * k)?;
+            shape.dict(&[])?;
+            shape.i32(1);
+            shape.len(instance.model)?;
+            shape.dict(&[])?;
+            children.chunk(b"nSHP", &shape);
+        }
+
+        // Entry i of the RGBA chunk is the color of palette index i + 1.
+        let mut content = ChunkWriter::default();
+        for index in 1..=256 {
+            let color = self.palette.colors[index % 256];
+            let channels = [color.red, color.green, color.blue, color.alpha];
+            let bytes = if index < 256 {
+                channels.map(|x| (x * 255.0).round().clamp(0.0, 255.0) as u8)
+            }
+            else {
+                [0; 4]
+            };
+            content.0.extend_from_slice(&bytes);
+        }
+        children.chunk(b"RGBA", &content);
+
+        let mut main = ChunkWriter::default();
+        main.chunk_with_children(b"MAIN", &ChunkWriter::default(), &children)?;
+
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        writer.write_all(&main.0)?;
+
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Parser {
+    models: Vec<VoxModel>,
+    size: Option<Vector3<u32>>,
+    palette: Option<VoxPalette>,
+    nodes: HashMap<i32, Node>,
+    hidden_layers: HashSet<i32>,
+}
+
+enum Node {
+    Transform {
+        hidden: bool,
+        layer: i32,
+        child: i32,
+        transform: VoxTransform,
+    },
+    Group {
+        hidden: bool,
+        children: Vec<i32>,
+    },
+    Shape {
+        hidden: bool,
+        model: Option<i32>,
+    },
+}
+
+impl Parser {
+    fn parse_chunk(&mut self, id: &[u8; 4], mut content: ChunkReader<'_>) -> Result<(), Error> {
+        match id {
+            b"SIZE" => {
+                let size = Vector3::new(content.u32()?, content.u32()?, content.u32()?);
+                if size.iter().any(|&x| x == 0 || x > MAX_MODEL_SIZE) {
+                    return Err(Error::Corrupt(format!("Invalid model size {:?}", size)));
+                }
+                self.size = Some(size);
+            }
+            b"XYZI" => {
+                let size = self.size.take().ok_or_else(|| {
+                    Error::Corrupt("XYZI chunk without preceding SIZE chunk".to_owned())
+                })?;
+
+                let num_voxels = content.len()?;
+                let data = content.bytes(num_voxels.saturating_mul(4))?;
+                let mut voxels = Vec::with_capacity(num_voxels);
+
+                for voxel in data.chunks_exact(4) {
+                    let position = Point3::new(voxel[0], voxel[1], voxel[2]);
+                    if (0..3).any(|axis| position[axis] as u32 >= size[axis]) {
+                        return Err(Error::Corrupt(format!(
+                            "Voxel at {:?} outside of model",
+                            position
+                        )));
+                    }
+                    if voxel[3] != 0 {
+                        voxels.push((position, voxel[3]));
+                    }
+                }
+
+                self.models.push(VoxModel { size, voxels });
+            }
+            b"RGBA" => {
+                let mut palette = VoxPalette::default();
+                for index in 1..=255 {
+                    let color = content.bytes(4)?;
+                    palette.set_color(index, rgba(color[0], color[1], color[2], color[3]));
+                }
+                self.palette = Some(palette);
+            }
+            b"nTRN" => {
+                let id = content.i32()?;
+                let hidden = is_hidden(&content.dict()?);
+                let child = content.i32()?;
+                let _reserved = content.i32()?;
+                let layer = content.i32()?;
+                let num_frames = content.len()?;
+
+                // Only the first frame of animations is used.
+                let transform = if num_frames > 0 {
+                    parse_transform(&content.dict()?)?
+                }
+                else {
+                    VoxTransform::identity()
+                };
+
+                self.insert_node(
+                    id,
+                    Node::Transform {
+                        hidden,
+                        layer,
+                        child,
+                        transform,
+                    },
+                )?;
+            }
+            b"nGRP" => {
+                let id = content.i32()?;
+                let hidden = is_hidden(&content.dict()?);
+                let num_children = content.len()?;
+                let children = (0..num_children)
+                    .map(|_| content.i32())
+                    .collect::<Result<_, _>>()?;
+
+                self.insert_node(id, Node::Group { hidden, children })?;
+            }
+            b"nSHP" => {
+                let id = content.i32()?;
+                let hidden = is_hidden(&content.dict()?);
+                let num_models = content.len()?;
+
+                // Only the first frame of animations is used.
+                let model = if num_models > 0 {
+                    Some(content.i32()?)
+                }
+                else {
+                    None
+                };
+
+                self.insert_node(id, Node::Shape { hidden, model })?;
+            }
+            b"LAYR" => {
+                let id = content.i32()?;
+                if is_hidden(&content.dict()?) {
+                    self.hidden_layers.insert(id);
+                }
+            }
+            // PACK only contains the number of models, which are counted
+            // anyway. Materials, cameras etc. aren't relevant for voxel maps.
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn insert_node(&mut self, id: i32, node: Node) -> Result<(), Error> {
+        if self.nodes.insert(id, node).is_some() {
+            return Err(Error::Corrupt(format!("Duplicate node {}", id)));
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<VoxFile, Error> {
+        let mut instances = Vec::new();
+
+        if self.nodes.is_empty() {
+            // Files without scene graph place all models at the origin.
+            for (model, VoxModel { size, .. }) in self.models.iter().enumerate() {
+                instances.push(VoxInstance {
+                    model,
+                    transform: VoxTransform {
+                        rotation: Matrix3::identity(),
+                        translation: size.map(|x| (x / 2) as i32),
+                    },
+                });
+            }
+        }
+        else {
+            let mut visited = HashSet::new();
+            self.walk(
+                0,
+                &VoxTransform::identity(),
+                0,
+                &mut visited,
+                &mut instances,
+            )?;
+        }
+
+        Ok(VoxFile {
+            models: self.models,
+            instances,
+            palette: self.palette.unwrap_or_default(),
+        })
+    }
+
+    fn walk(
+        &self,
+        id: i32,
+        transform: &VoxTransform,
+        depth: usize,
+        visited: &mut HashSet<i32>,
+        instances: &mut Vec<VoxInstance>,
+    ) -> Result<(), Error> {
+        if depth > MAX_NODE_DEPTH {
+            return Err(Error::Corrupt("Scene graph is too deep".to_owned()));
+        }
+
+        // Every node has a single parent. Rejecting shared nodes keeps a small
+        // file from expanding into exponentially many instances and also
+        // catches cycles.
+        if !visited.insert(id) {
+            return Err(Error::Corrupt(format!(
+                "Node {} is referenced more than once",
+                id
+            )));
+        }
+
+        let node = self
+            .nodes
+            .get(&id)
+            .ok_or_else(|| Error::Corrupt(format!("Missing node {}", id)))?;
+
+        match node {
+            Node::Transform {
+                hidden,
+                layer,
+                child,
+                transform: local,
+            } => {
+                if !hidden && !self.hidden_layers.contains(layer) {
+                    self.walk(
+                        *child,
+                        &transform.then(local),
+                        depth + 1,
+                        visited,
+                        instances,
+                    )?;
+                }
+            }
+            Node::Group { hidden, children } => {
+                if !hidden {
+                    for child in children {
+                        self.walk(*child, transform, depth + 1, visited, instances)?;
+                    }
+                }
+            }
+            Node::Shape { hidden, model } => {
+                if let (false, Some(model)) = (hidden, model) {
+                    let model = (*model)
+                        .try_into()
+                        .ok()
+                        .filter(|&model: &usize| model < self.models.len())
+                        .ok_or_else(|| Error::Corrupt(format!("Missing model {}", model)))?;
+
+                    instances.push(VoxInstance {
+                        model,
+                        transform: transform.clone(),
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads the content of a chunk. Since the chunk was read completely, running
+/// out of data means the chunk is malformed.
+struct ChunkReader<'a>(&'a [u8]);
+
+impl<'a> ChunkReader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if len > self.0.len() {
+            return Err(Error::Corrupt("Chunk too short".to_owned()));
+        }
+
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn id(&mut self) -> Result<[u8; 4], Error> {
+        Ok(self.bytes(4)?.try_into().unwrap())
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn i32(&mut self) -> Result<i32, Error> {
+        Ok(self.u32()? as i32)
+    }
+
+    /// Reads a length or count, which must not be negative.
+    fn len(&mut self) -> Result<usize, Error> {
+        self.i32()?
+            .try_into()
+            .map_err(|_| Error::Corrupt("Negative length".to_owned()))
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        let len = self.len()?;
+        String::from_utf8(self.bytes(len)?.to_vec())
+            .map_err(|_| Error::Corrupt("Invalid string".to_owned()))
+    }
+
+    fn dict(&mut self) -> Result<HashMap<String, String>, Error> {
+        let len = self.len()?;
+        (0..len)
+            .map(|_| Ok((self.string()?, self.string()?)))
+            .collect()
+    }
+}
+
+/// Builds the content of chunks.
+#[derive(Default)]
+struct ChunkWriter(Vec<u8>);
+
+impl ChunkWriter {
+    fn i32(&mut self, x: i32) {
+        self.0.extend_from_slice(&x.to_le_bytes());
+    }
+
+    fn len(&mut self, len: usize) -> Result<(), Error> {
+        let len = len
+            .try_into()
+            .map_err(|_| Error::Unsupported("Too many voxels".to_owned()))?;
+        self.i32(len);
+        Ok(())
+    }
+
+    fn string(&mut self, s: &str) -> Result<(), Error> {
+        self.len(s.len())?;
+        self.0.extend_from_slice(s.as_bytes());
+        Ok(())
+    }
+
+    fn dict(&mut self, entries: &[(&str, &str)]) -> Result<(), Error> {
+        self.len(entries.len())?;
+        for (key, value) in entries {
+            self.string(key)?;
+            self.string(value)?;
+        }
+        Ok(())
+    }
+
+    fn chunk(&mut self, id: &[u8; 4], content: &Self) {
+        self.0.extend_from_slice(id);
+        self.i32(content.0.len() as i32);
+        self.i32(0);
+        self.0.extend_from_slice(&content.0);
+    }
+
+    fn chunk_with_children(
+        &mut self,
+        id: &[u8; 4],
+        content: &Self,
+        children: &Self,
+    ) -> Result<(), Error> {
+        self.0.extend_from_slice(id);
+        self.len(content.0.len())?;
+        self.len(children.0.len())?;
+        self.0.extend_from_slice(&content.0);
+        self.0.extend_from_slice(&children.0);
+        Ok(())
+    }
+}
+
+fn is_hidden(attributes: &HashMap<String, String>) -> bool {
+    attributes
+        .get("_hidden")
+        .map_or(false, |hidden| hidden == "1")
+}
+
+/// Parses the `_r` and `_t` attributes of a frame of a transform node.
+fn parse_transform(frame: &HashMap<String, String>) -> Result<VoxTransform, Error> {
+    let mut transform = VoxTransform::identity();
+
+    if let Some(rotation) = frame.get("_r") {
+        transform.rotation = rotation
+            .parse()
+            .ok()
+            .and_then(VoxTransform::decode_rotation)
+            .ok_or_else(|| Error::Corrupt(format!("Invalid rotation {:?}", rotation)))?;
+    }
+
+    if let Some(translation) = frame.get("_t") {
+        let components = translation
+            .split_whitespace()
+            .map(str::parse)
+            .collect::<Result<Vec<i32>, _>>()
+            .ok()
+            .filter(|components| components.len() == 3)
+            .ok_or_else(|| Error::Corrupt(format!("Invalid translation {:?}", translation)))?;
+        transform.translation = Vector3::from_column_slice(&components);
+    }
+
+    Ok(transform)
+}
+
+fn rgba(r: u8, g: u8, b: u8, a: u8) -> Srgba {
+    Srgba::new(
+        r as f32 / 255.0,
+        g as f32 / 255.0,
+        b as f32 / 255.0,
+        a as f32 / 255.0,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        storage::VecStorage,
+        testing::TestVoxel,
+        FlatEncoder,
+        MortonEncoder,
+    };
+
+    fn ints(values: &[i32]) -> Vec<u8> {
+        values
+            .iter()
+            .flat_map(|x| x.to_le_bytes().to_vec())
+            .collect()
+    }
+
+    fn dict(entries: &[(&str, &str)]) -> Vec<u8> {
+        let mut bytes = ints(&[entries.len() as i32]);
+        for (key, value) in entries {
+            for s in &[key, value] {
+                bytes.extend(ints(&[s.len() as i32]));
+                bytes.extend_from_slice(s.as_bytes());
+            }
+        }
+        bytes
+    }
+
+    fn chunk(id: &[u8; 4], content: &[u8]) -> Vec<u8> {
+        let mut bytes = id.to_vec();
+        bytes.extend(ints(&[content.len() as i32, 0]));
+        bytes.extend_from_slice(content);
+        bytes
+    }
+
+    fn file(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let children = chunks.concat();
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&150u32.to_le_bytes());
+        bytes.extend_from_slice(b"MAIN");
+        bytes.extend(ints(&[0, children.len() as i32]));
+        bytes.extend(children);
+        bytes
+    }
+
+    fn model(size: [i32; 3], voxels: &[[u8; 4]]) -> Vec<Vec<u8>> {
+        let mut xyzi = ints(&[voxels.len() as i32]);
+        xyzi.extend(voxels.concat());
+        vec![chunk(b"SIZE", &ints(&size)), chunk(b"XYZI", &xyzi)]
+    }
+
+    fn transform(
+        id: i32,
+        attributes: &[(&str, &str)],
+        child: i32,
+        layer: i32,
+        frame: &[(&str, &str)],
+    ) -> Vec<u8> {
+        let mut content = ints(&[id]);
+        content.extend(dict(attributes));
+        content.extend(ints(&[child, -1, layer, 1]));
+        content.extend(dict(frame));
+        chunk(b"nTRN", &content)
+    }
+
+    fn group(id: i32, children: &[i32]) -> Vec<u8> {
+        let mut content = ints(&[id]);
+        content.extend(dict(&[]));
+        content.extend(ints(&[children.len() as i32]));
+        content.extend(ints(children));
+        chunk(b"nGRP", &content)
+    }
+
+    fn shape(id: i32, model: i32) -> Vec<u8> {
+        let mut content = ints(&[id]);
+        content.extend(dict(&[]));
+        content.extend(ints(&[1, model]));
+        content.extend(dict(&[]));
+        chunk(b"nSHP", &content)
+    }
+
+    fn read(chunks: &[Vec<u8>]) -> Result<VoxFile, Error> {
+        VoxFile::read_from(&file(chunks)[..])
+    }
+
+    #[test]
+    fn reads_models_without_scene_graph() {
+        let file = read(&model([2, 2, 2], &[[0, 0, 0, 1], [1, 1, 1, 216]])).unwrap();
+        assert_eq!(file.instances.len(), 1);
+
+        let mut voxels = file.voxels().collect::<Vec<_>>();
+        voxels.sort_by_key(|(coord, _)| (coord.z, coord.y, coord.x));
+        assert_eq!(
+            voxels,
+            vec![(Point3::new(1, 1, -2), 216), (Point3::new(0, 0, -1), 1)]
+        );
+
+        let storage: VecStorage<TestVoxel, MortonEncoder> =
+            file.to_storage(|index| TestVoxel(index.into())).unwrap();
+        assert_eq!(
+            storage.bounds(),
+            Some(Bounds::new(Point3::new(0, 0, -2), Point3::new(2, 2, 0)))
+        );
+        assert_eq!(storage.get(&Point3::new(0, 0, -1)), Some(&TestVoxel(1)));
+        assert_eq!(storage.get(&Point3::new(1, 1, -2)), Some(&TestVoxel(216)));
+        assert_eq!(storage.get(&Point3::new(0, 1, -2)), Some(&TestVoxel(0)));
+    }
+
+    #[test]
+    fn default_palette() {
+        let palette = VoxPalette::default();
+        assert_eq!(palette.tint(0), None);
+        assert_eq!(palette.color(1), rgba(0xff, 0xff, 0xff, 0xff));
+        assert_eq!(palette.color(216), rgba(0, 0, 0xee, 0xff));
+        assert_eq!(palette.color(226), rgba(0, 0xee, 0, 0xff));
+        assert_eq!(palette.color(236), rgba(0xee, 0, 0, 0xff));
+        assert_eq!(palette.color(255), rgba(0x11, 0x11, 0x11, 0xff));
+    }
+
+    #[test]
+    fn flattens_scene_graph() {
+        let mut chunks = model([1, 1, 1], &[[0, 0, 0, 1]]);
+        chunks.extend(vec![
+            transform(0, &[], 1, -1, &[("_t", "0 0 5")]),
+            group(1, &[2, 4, 6]),
+            // Rotates 90 degrees around the z axis.
+            transform(2, &[], 3, 0, &[("_r", "17"), ("_t", "10 0 0")]),
+            shape(3, 0),
+            transform(4, &[("_hidden", "1")], 5, 0, &[]),
+            shape(5, 0),
+            transform(6, &[], 7, 1, &[]),
+            shape(7, 0),
+        ]);
+        let mut layer = ints(&[1]);
+        layer.extend(dict(&[("_hidden", "1")]));
+        layer.extend(ints(&[-1]));
+        chunks.push(chunk(b"LAYR", &layer));
+
+        let file = read(&chunks).unwrap();
+        assert_eq!(file.instances.len(), 1);
+        assert_eq!(file.instances[0].model, 0);
+        assert_eq!(
+            file.instances[0].transform,
+            VoxTransform {
+                rotation: Matrix3::new(0, -1, 0, 1, 0, 0, 0, 0, 1),
+                translation: Vector3::new(10, 0, 5),
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_scene_graphs() {
+        let graphs = vec![
+            // Missing node.
+            vec![transform(0, &[], 1, -1, &[])],
+            // Cycle.
+            vec![transform(0, &[], 1, -1, &[]), group(1, &[0])],
+            // Shared node.
+            vec![
+                transform(0, &[], 1, -1, &[]),
+                group(1, &[2, 2]),
+                transform(2, &[], 3, -1, &[]),
+                shape(3, 0),
+            ],
+            // Duplicate node.
+            vec![transform(0, &[], 1, -1, &[]), shape(1, 0), shape(1, 0)],
+            // Missing model.
+            vec![transform(0, &[], 1, -1, &[]), shape(1, 1)],
+            // Invalid rotation.
+            vec![transform(0, &[], 1, -1, &[("_r", "3")]), shape(1, 0)],
+        ];
+
+        for graph in graphs {
+            let mut chunks = model([1, 1, 1], &[[0, 0, 0, 1]]);
+            chunks.extend(graph);
+            assert!(matches!(read(&chunks), Err(Error::Corrupt(_))));
+        }
+    }
+
+    #[test]
+    fn rejects_deep_scene_graphs() {
+        let mut chunks = model([1, 1, 1], &[[0, 0, 0, 1]]);
+        let depth = MAX_NODE_DEPTH as i32 + 1;
+        for id in 0..depth {
+            chunks.push(group(id, &[id + 1]));
+        }
+        chunks.push(shape(depth, 0));
+        assert!(matches!(read(&chunks), Err(Error::Corrupt(_))));
+    }
+
+    #[test]
+    fn truncated() {
+        let bytes = file(&model([2, 2, 2], &[[0, 0, 0, 1]]));
+        for len in &[0, 6, 12, bytes.len() - 1] {
+            assert!(matches!(
+                VoxFile::read_from(&bytes[..*len]),
+                Err(Error::Truncated)
+            ));
+        }
+    }
+
+    #[test]
+    fn corrupt() {
+        let mut bytes = file(&[]);
+        bytes[0] = b'P';
+        assert!(matches!(
+            VoxFile::read_from(&bytes[..]),
+            Err(Error::InvalidMagic)
+        ));
+
+        let mut bytes = file(&[]);
+        bytes[4..8].copy_from_slice(&1u32.to_le_bytes());
+        assert!(matches!(
+            VoxFile::read_from(&bytes[..]),
+            Err(Error::UnsupportedVersion(1))
+        ));
+
+        let chunks = vec![
+            // Chunk longer than its parent.
+            vec![chunk(b"SIZE", &ints(&[1, 1, 1]))[..20].to_vec()],
+            // Invalid model size.
+            model([0, 1, 1], &[]),
+            model([257, 1, 1], &[]),
+            // Voxel outside of the model.
+            model([1, 1, 1], &[[1, 0, 0, 1]]),
+            // More voxels than the chunk contains.
+            vec![
+                chunk(b"SIZE", &ints(&[1, 1, 1])),
+                chunk(b"XYZI", &ints(&[2, 0])),
+            ],
+            // Voxels without model size.
+            vec![chunk(b"XYZI", &ints(&[0]))],
+            // Negative length.
+            vec![chunk(b"LAYR", &ints(&[0, -1]))],
+            // Too short palette.
+            vec![chunk(b"RGBA", &[0xff; 16])],
+        ];
+
+        for chunks in chunks {
+            assert!(matches!(read(&chunks), Err(Error::Corrupt(_))));
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let bounds = Bounds::new(Point3::new(-3, -2, -4), Point3::new(260, 3, 2));
+        let mut storage = VecStorage::<TestVoxel, FlatEncoder>::from_bounds(bounds.clone());
+        for coord in bounds.iter() {
+            if (coord.x + coord.y + coord.z).rem_euclid(7) == 0 {
+                *storage.get_mut(&coord).unwrap() = TestVoxel(1 + coord.x.rem_euclid(5) as u16);
+            }
+        }
+
+        let color = |index: u8| rgba(index, 0x80, 0xff - index, 0xff);
+        let file = VoxFile::from_storage(&storage, |voxel| {
+            (voxel.0 != 0).then(|| (voxel.0 as u8, color(voxel.0 as u8)))
+        })
+        .unwrap();
+        assert_eq!(file.models.len(), 2);
+
+        let mut bytes = Vec::new();
+        file.write_to(&mut bytes).unwrap();
+        let file = VoxFile::read_from(&bytes[..]).unwrap();
+
+        for index in 1..=5 {
+            assert_eq!(file.palette.color(index), color(index));
+        }
+
+        let read: VecStorage<TestVoxel, FlatEncoder> =
+            file.to_storage(|index| TestVoxel(index.into())).unwrap();
+        let occupied = storage.iter().filter(|(_, voxel)| voxel.0 != 0).count();
+        assert_eq!(
+            read.iter().filter(|(_, voxel)| voxel.0 != 0).count(),
+            occupied
+        );
+        for (coord, voxel) in storage.iter() {
+            if voxel.0 != 0 {
+                assert_eq!(read.get(&coord), Some(voxel));
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_palette_index_zero() {
+        let bounds = Bounds::new(Point3::new(0, 0, 0), Point3::new(1, 1, 1));
+        let storage = VecStorage::<TestVoxel, MortonEncoder>::from_bounds(bounds);
+        assert!(matches!(
+            VoxFile::from_storage(&storage, |_| Some((0, rgba(0, 0, 0, 0xff)))),
+            Err(Error::Unsupported(_))
+        ));
+    }
+}
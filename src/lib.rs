@@ -7,17 +7,30 @@
 
 #![allow(dead_code)]
 
+#[cfg(feature = "assets")]
+pub mod asset;
 pub mod bounds;
+mod buffer;
+pub mod formats;
 pub mod map;
+pub mod mesher;
 pub mod pass;
 mod pod;
 pub mod storage;
+#[cfg(test)]
+mod testing;
 
 pub use amethyst_tiles::{
     CoordinateEncoder,
     FlatEncoder,
     MortonEncoder,
 };
+#[cfg(feature = "assets")]
+pub use asset::{
+    VoxelMapAsset,
+    VoxelMapAssetSystem,
+    VoxelMapHandle,
+};
 pub use bounds::{
     DrawVoxelsBounds,
     DrawVoxelsBoundsDefault,
@@ -27,3 +40,8 @@ pub use map::{
     Voxel,
     VoxelMap,
 };
+pub use mesher::{
+    VoxelFace,
+    VoxelMesher,
+    VoxelSamples,
+};
@@ -1,4 +1,11 @@
-use std::marker::PhantomData;
+use std::{
+    collections::VecDeque,
+    marker::PhantomData,
+    sync::atomic::{
+        AtomicU64,
+        Ordering,
+    },
+};
 
 use amethyst_assets::Handle;
 use amethyst_core::{
@@ -19,7 +26,6 @@ use amethyst_rendy::{
         Target,
     },
     palette::Srgba,
-    system::GraphAuxData,
     Backend,
     Factory,
     RenderGroupDesc,
@@ -33,6 +39,10 @@ pub use amethyst_tiles::{
 };
 use derivative::Derivative;
 
+use crate::{
+    bounds::Bounds,
+    storage::VoxelStorageIterMut,
+};
 pub use crate::{
     bounds::{
         DrawVoxelsBounds,
@@ -46,10 +56,12 @@ pub use crate::{
 };
 
 pub trait Voxel: 'static + Clone + Default + Send + Sync {
+    /// Whether this voxel hides the faces of its neighbours that touch it.
+    /// `coordinates` are the coordinates of this voxel.
     fn occupied(&self, coordinates: &Point3<i32>, world: &World, resources: &Resources) -> bool;
 
-    /// Index into texture coordinates of VoxelMap for each face (+x, -x, +y,
-    /// -y, +z, -z)
+    /// Index into texture coordinates of VoxelMap for each face (+z, -z, +y,
+    /// -y, -x, +x)
     fn texture(
         &self,
         coordinates: &Point3<i32>,
@@ -80,6 +92,12 @@ pub trait Voxel: 'static + Clone + Default + Send + Sync {
     }
 }
 
+/// Maximum number of entries in the change log of a [`VoxelMap`].
+const MAX_CHANGE_LOG: usize = 64;
+
+/// Source of [`VoxelMap::id`].
+static NEXT_MAP_ID: AtomicU64 = AtomicU64::new(0);
+
 #[derive(Debug)]
 pub struct VoxelMap<V: Voxel, S: VoxelStorage<V>> {
     /// Voxel data
@@ -93,44 +111,104 @@ pub struct VoxelMap<V: Voxel, S: VoxelStorage<V>> {
     // the rendered VoxelMap.
     pub(crate) transform: Matrix4<f32>,
 
+    changes: ChangeTracker,
+
+    /// Unique for every map, such that caches can tell apart maps with the
+    /// same revision.
+    id: u64,
+
     _marker: PhantomData<V>,
 }
 
 impl<V: Voxel, S: VoxelStorage<V>> VoxelMap<V, S> {
     pub fn new(data: S, sprite_sheet: Handle<SpriteSheet>) -> Self {
-        let translation = data
-            .bounds()
-            .map(|bounds| -bounds.center().coords.map(|x| x as f32))
-            .unwrap_or_default();
-        let transform = Matrix4::new_translation(&translation);
+        let transform = centering_transform(&data);
 
         Self {
             data,
             transform,
             sprite_sheet,
+            changes: ChangeTracker::new(),
+            id: NEXT_MAP_ID.fetch_add(1, Ordering::Relaxed),
             _marker: PhantomData,
         }
     }
 
-    /// Returns array of `bool`s describing which face has a neighbouring voxel
-    /// and thus can be culled.
-    pub(crate) fn get_neighbors(&self, coords: Point3<i32>, aux: &GraphAuxData) -> [bool; 6] {
-        let mut exists = [false; 6];
+    /// Returns the voxel data. With the `serde` feature enabled, this is what
+    /// should be serialized, since the sprite sheet handle can't be.
+    pub fn data(&self) -> &S {
+        &self.data
+    }
+
+    /// Consumes the map and returns its voxel data.
+    pub fn into_data(self) -> S {
+        self.data
+    }
+
+    /// Returns the voxel data for modification. Since modifications through
+    /// the returned reference can't be tracked, the whole map is marked as
+    /// changed. Call [`VoxelMap::recenter`] afterwards, if the bounds of the
+    /// data changed.
+    pub fn data_mut(&mut self) -> &mut S {
+        if let Some(bounds) = self.data.bounds() {
+            self.mark_changed(&bounds);
+        }
+        &mut self.data
+    }
+
+    /// Replaces the voxel data and returns the old data, e.g. when the asset
+    /// the map was loaded from has been reloaded. The map is recentered.
+    pub fn set_data(&mut self, data: S) -> S {
+        let old = std::mem::replace(&mut self.data, data);
+        if let Some(bounds) = old.bounds() {
+            self.mark_changed(&bounds);
+        }
+        self.recenter();
+        old
+    }
 
-        let voxel_exists = |d| {
-            self.get(&(coords + &d))
-                .map(|voxel| voxel.occupied(&coords, &aux.world, &aux.resources))
-                .unwrap_or(false)
-        };
+    /// Recomputes the transform that centers the map, e.g. after it has been
+    /// resized.
+    pub fn recenter(&mut self) {
+        self.transform = centering_transform(&self.data);
+        if let Some(bounds) = self.data.bounds() {
+            self.mark_changed(&bounds);
+        }
+    }
+
+    /// Returns the region of voxels that have been modified since the last call
+    /// to `take_changes`, or `None` if nothing changed. Any voxel that has
+    /// been accessed with `get_mut` counts as modified.
+    pub fn take_changes(&mut self) -> Option<Bounds> {
+        self.changes.take()
+    }
+
+    /// Returns the pending changes without resetting them.
+    pub fn changes(&self) -> Option<&Bounds> {
+        self.changes.pending()
+    }
+
+    /// Marks a region of voxels as modified.
+    pub fn mark_changed(&mut self, bounds: &Bounds) {
+        self.changes.mark_changed(bounds);
+    }
+
+    /// Returns the region of voxels that have been modified after `revision`,
+    /// or `None` if nothing changed. The region may be larger than the voxels
+    /// that actually changed.
+    pub fn changes_since(&self, revision: u64) -> Option<Bounds> {
+        self.changes.since(revision)
+    }
 
-        exists[1] = voxel_exists(Vector3::new(0, 0, -1));
-        exists[0] = voxel_exists(Vector3::new(0, 0, 1));
-        exists[3] = voxel_exists(Vector3::new(0, -1, 0));
-        exists[2] = voxel_exists(Vector3::new(0, 1, 0));
-        exists[4] = voxel_exists(Vector3::new(-1, 0, 0));
-        exists[5] = voxel_exists(Vector3::new(1, 0, 0));
+    /// Returns a counter that is incremented on every modification of the map.
+    /// Unlike [`VoxelMap::take_changes`] this can be used to detect changes
+    /// without mutable access to the map.
+    pub fn revision(&self) -> u64 {
+        self.changes.revision
+    }
 
-        exists
+    pub(crate) fn id(&self) -> u64 {
+        self.id
     }
 }
 
@@ -148,18 +226,184 @@ impl<V: Voxel, S: VoxelStorage<V>> VoxelStorage<V> for VoxelMap<V, S> {
     }
 
     fn get_mut(&mut self, coord: &Point3<i32>) -> Option<&mut V> {
-        self.data.get_mut(coord)
+        let voxel = self.data.get_mut(coord)?;
+        self.changes
+            .mark_changed(&Bounds::new(*coord, coord + Vector3::repeat(1)));
+        Some(voxel)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (Point3<i32>, &V)> + '_> {
+        self.data.iter()
+    }
+
+    fn iter_region(&self, bounds: &Bounds) -> Box<dyn Iterator<Item = (Point3<i32>, &V)> + '_> {
+        self.data.iter_region(bounds)
+    }
+
+    fn fill(&mut self, bounds: &Bounds, voxel: V) {
+        self.data.fill(bounds, voxel);
+        self.mark_changed(bounds);
+    }
+
+    fn copy_region(&mut self, src: &Bounds, dst_origin: &Point3<i32>) {
+        self.data.copy_region(src, dst_origin);
+        let offset = dst_origin - src.min();
+        self.mark_changed(&Bounds::new(*dst_origin, src.max() + offset));
+    }
+
+    fn swap_regions(&mut self, a: &Bounds, b_origin: &Point3<i32>) {
+        self.data.swap_regions(a, b_origin);
+        let offset = b_origin - a.min();
+        self.mark_changed(a);
+        self.mark_changed(&Bounds::new(*b_origin, a.max() + offset));
+    }
+
+    fn replace_if<F>(&mut self, bounds: &Bounds, predicate: F, voxel: V)
+    where
+        F: FnMut(&V) -> bool,
+    {
+        self.data.replace_if(bounds, predicate, voxel);
+        self.mark_changed(bounds);
+    }
+}
+
+/// Mutable iteration marks the whole iterated region as changed.
+impl<V: Voxel, S: VoxelStorageIterMut<V>> VoxelStorageIterMut<V> for VoxelMap<V, S> {
+    fn iter_mut(&mut self) -> Box<dyn Iterator<Item = (Point3<i32>, &mut V)> + '_> {
+        if let Some(bounds) = self.data.bounds() {
+            self.mark_changed(&bounds);
+        }
+        self.data.iter_mut()
+    }
+
+    fn iter_region_mut(
+        &mut self,
+        bounds: &Bounds,
+    ) -> Box<dyn Iterator<Item = (Point3<i32>, &mut V)> + '_> {
+        self.mark_changed(bounds);
+        self.data.iter_region_mut(bounds)
+    }
+}
+
+/// Keeps track of the voxels that have been modified in a [`VoxelMap`].
+#[derive(Debug)]
+struct ChangeTracker {
+    /// Region of voxels that have been modified since the last call to
+    /// `take`.
+    changes: Bounds,
+
+    /// Incremented on every modification.
+    revision: u64,
+
+    /// Regions modified by recent revisions, together with the last revision
+    /// that modified them. Holds at most [`MAX_CHANGE_LOG`] entries.
+    log: VecDeque<(u64, Bounds)>,
+}
+
+impl ChangeTracker {
+    fn new() -> Self {
+        Self {
+            changes: Bounds::empty(),
+            revision: 0,
+            log: VecDeque::new(),
+        }
+    }
+
+    fn mark_changed(&mut self, bounds: &Bounds) {
+        self.changes = self.changes.union(bounds);
+        self.revision += 1;
+        self.log(bounds);
+    }
+
+    fn since(&self, revision: u64) -> Option<Bounds> {
+        let changes = self
+            .log
+            .iter()
+            .rev()
+            .take_while(|(logged, _)| *logged > revision)
+            .fold(Bounds::empty(), |changes, (_, bounds)| {
+                changes.union(bounds)
+            });
+
+        if changes.is_empty() {
+            None
+        }
+        else {
+            Some(changes)
+        }
+    }
+
+    /// Appends a change to the log.
+    fn log(&mut self, bounds: &Bounds) {
+        if bounds.is_empty() {
+            return;
+        }
+
+        if let Some((logged, last)) = self.log.back_mut() {
+            if last.union(bounds) == *last {
+                *logged = self.revision;
+                return;
+            }
+        }
+
+        self.log.push_back((self.revision, bounds.clone()));
+
+        // Merge the oldest entries, which only makes the regions returned by
+        // `since` for old revisions larger.
+        if self.log.len() > MAX_CHANGE_LOG {
+            let (_, oldest) = self.log.pop_front().unwrap();
+            let (_, next) = self.log.front_mut().unwrap();
+            *next = next.union(&oldest);
+        }
+    }
+
+    fn pending(&self) -> Option<&Bounds> {
+        if self.changes.is_empty() {
+            None
+        }
+        else {
+            Some(&self.changes)
+        }
+    }
+
+    fn take(&mut self) -> Option<Bounds> {
+        let changes = std::mem::replace(&mut self.changes, Bounds::empty());
+        if changes.is_empty() {
+            None
+        }
+        else {
+            Some(changes)
+        }
     }
 }
 
+/// Computes the transform that moves the center of the voxel data to the
+/// origin.
+fn centering_transform<V: Voxel, S: VoxelStorage<V>>(data: &S) -> Matrix4<f32> {
+    let translation = data
+        .bounds()
+        .map(|bounds| -bounds.center().coords.map(|x| x as f32))
+        .unwrap_or_default();
+    Matrix4::new_translation(&translation)
+}
+
 #[derive(Clone, Derivative)]
 #[derivative(Debug(bound = ""), Default(bound = ""))]
 pub struct RenderVoxels<V: Voxel, S: VoxelStorage<V>, Z: DrawVoxelsBounds = DrawVoxelsBoundsDefault>
 {
     target: Target,
+    greedy_meshing: bool,
     _marker: PhantomData<(V, S, Z)>,
 }
 
+impl<V: Voxel, S: VoxelStorage<V>, Z: DrawVoxelsBounds> RenderVoxels<V, S, Z> {
+    /// Enables greedy meshing, see [`DrawVoxelsDesc::with_greedy_meshing`].
+    pub fn with_greedy_meshing(mut self, greedy_meshing: bool) -> Self {
+        self.greedy_meshing = greedy_meshing;
+        self
+    }
+}
+
 impl<B: Backend, V: Voxel, S: VoxelStorage<V>, Z: DrawVoxelsBounds> RenderPlugin<B>
     for RenderVoxels<V, S, Z>
 {
@@ -173,10 +417,113 @@ impl<B: Backend, V: Voxel, S: VoxelStorage<V>, Z: DrawVoxelsBounds> RenderPlugin
         plan.extend_target(self.target, |ctx| {
             ctx.add(
                 RenderOrder::BeforeTransparent,
-                DrawVoxelsDesc::<V, S, Z>::default().builder(),
+                DrawVoxelsDesc::<V, S, Z>::default()
+                    .with_greedy_meshing(self.greedy_meshing)
+                    .builder(),
             )?;
             Ok(())
         });
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bounds::Bounds,
+        storage::VecStorage,
+        testing::TestVoxel,
+        MortonEncoder,
+    };
+
+    #[test]
+    fn centering_transform_follows_resize() {
+        let mut storage = VecStorage::<TestVoxel, MortonEncoder>::from_bounds(Bounds::new(
+            Point3::new(-2, 0, 1),
+            Point3::new(2, 4, 3),
+        ));
+        assert_eq!(
+            centering_transform(&storage),
+            Matrix4::new_translation(&Vector3::new(0.0, -2.0, -2.0))
+        );
+
+        storage.resize(Bounds::new(Point3::new(0, 0, 0), Point3::new(8, 2, 2)));
+        assert_eq!(
+            centering_transform(&storage),
+            Matrix4::new_translation(&Vector3::new(-4.0, -1.0, -1.0))
+        );
+    }
+
+    #[test]
+    fn tracks_changes_and_revisions() {
+        let mut changes = ChangeTracker::new();
+        assert_eq!(changes.pending(), None);
+
+        changes.mark_changed(&Bounds::new(Point3::new(1, 2, 3), Point3::new(2, 3, 4)));
+        changes.mark_changed(&Bounds::new(Point3::new(-1, 0, 0), Point3::new(0, 1, 1)));
+        assert_eq!(changes.revision, 2);
+        assert_eq!(
+            changes.pending(),
+            Some(&Bounds::new(Point3::new(-1, 0, 0), Point3::new(2, 3, 4)))
+        );
+
+        assert_eq!(
+            changes.take(),
+            Some(Bounds::new(Point3::new(-1, 0, 0), Point3::new(2, 3, 4)))
+        );
+        assert_eq!(changes.take(), None);
+
+        // Empty regions don't extend the pending changes, but still count as
+        // a modification.
+        changes.mark_changed(&Bounds::empty());
+        assert_eq!(changes.revision, 3);
+        assert_eq!(changes.pending(), None);
+    }
+
+    #[test]
+    fn changes_since_revision() {
+        let voxel = |x: i32| Bounds::new(Point3::new(x, 0, 0), Point3::new(x + 1, 1, 1));
+        let mut changes = ChangeTracker::new();
+        assert_eq!(changes.since(0), None);
+
+        changes.mark_changed(&voxel(0));
+        changes.mark_changed(&voxel(4));
+        changes.mark_changed(&voxel(2));
+        changes.take();
+
+        assert_eq!(changes.since(3), None);
+        assert_eq!(changes.since(2), Some(voxel(2)));
+        assert_eq!(changes.since(1), Some(voxel(2).union(&voxel(4))));
+        assert_eq!(changes.since(0), Some(voxel(0).union(&voxel(4))));
+
+        // Changes within the last logged region only update its revision.
+        changes.mark_changed(&Bounds::new(Point3::new(0, 0, 0), Point3::new(3, 1, 1)));
+        changes.mark_changed(&voxel(1));
+        assert_eq!(changes.log.len(), 4);
+        assert_eq!(changes.since(4), Some(voxel(0).union(&voxel(2))));
+    }
+
+    #[test]
+    fn change_log_is_limited() {
+        let voxel = |x: i32| Bounds::new(Point3::new(x, 0, 0), Point3::new(x + 1, 1, 1));
+        let mut changes = ChangeTracker::new();
+        let num_changes = MAX_CHANGE_LOG as i32 + 10;
+        for x in 0..num_changes {
+            changes.mark_changed(&voxel(x));
+        }
+
+        assert_eq!(changes.log.len(), MAX_CHANGE_LOG);
+        assert_eq!(
+            changes.since(num_changes as u64 - 1),
+            Some(voxel(num_changes - 1))
+        );
+
+        // Old entries get merged into the oldest remaining one, so changes
+        // since old revisions cover more voxels than were actually changed.
+        let last = voxel(num_changes - 1);
+        assert_eq!(changes.log.front().unwrap().0, 11);
+        assert_eq!(changes.since(10), Some(voxel(0).union(&last)));
+        assert_eq!(changes.since(11), Some(voxel(11).union(&last)));
+    }
+}
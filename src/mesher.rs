@@ -0,0 +1,429 @@
+//! Extraction of the visible faces of a voxel map, independent of the
+//! renderer.
+
+use std::collections::HashMap;
+
+use amethyst_core::{
+    ecs::{
+        Resources,
+        World,
+    },
+    math::{
+        Point3,
+        Vector2,
+        Vector3,
+    },
+};
+use amethyst_rendy::palette::Srgba;
+
+use crate::{
+    bounds::Bounds,
+    storage::VoxelStorage,
+    Voxel,
+};
+
+/// Directions in which the u and v coordinates of the texture increase on each
+/// face. These must match the face vertices in the vertex shader.
+const FACE_AXES: [([i32; 3], [i32; 3]); 6] = [
+    ([1, 0, 0], [0, 1, 0]),
+    ([1, 0, 0], [0, -1, 0]),
+    ([1, 0, 0], [0, 0, -1]),
+    ([1, 0, 0], [0, 0, 1]),
+    ([0, 0, 1], [0, 1, 0]),
+    ([0, 0, -1], [0, 1, 0]),
+];
+
+/// A visible face of a voxel, or a rectangle of merged faces.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VoxelFace {
+    /// Coordinates of the voxel in the map. For merged faces this is the voxel
+    /// at the minimum u and v coordinates of the texture.
+    pub coord: Point3<i32>,
+
+    /// Index of the face, in the order +z, -z, +y, -y, -x, +x.
+    pub face: usize,
+
+    /// Index of the sprite in the sprite sheet of the map.
+    pub sprite: usize,
+
+    pub tint: Option<Srgba>,
+
+    /// Number of voxels the face spans along the u and v axes of the texture.
+    /// This is `(1, 1)` unless faces have been merged.
+    pub size: Vector2<u32>,
+}
+
+/// Turns voxel maps into the list of faces that need to be rendered. Faces
+/// of textured voxels are visible, unless they're culled by an occupied
+/// neighbour.
+///
+/// Meshing happens in two steps. [`VoxelMesher::sample`] calls the methods of
+/// [`Voxel`] for all voxels of a region, which needs the world and resources.
+/// The faces are then built from the [`VoxelSamples`] alone, which can happen
+/// on any thread. Afterwards [`merge_faces`] can merge them into larger
+/// rectangles.
+pub struct VoxelMesher<'a> {
+    world: &'a World,
+    resources: &'a Resources,
+}
+
+impl<'a> VoxelMesher<'a> {
+    pub fn new(world: &'a World, resources: &'a Resources) -> Self {
+        Self { world, resources }
+    }
+
+    /// Samples the voxels in `bounds` and whether their neighbours are
+    /// occupied. `voxel_map` is usually a [`VoxelMap`](crate::VoxelMap), but
+    /// any storage works. Voxels the storage doesn't iterate over, e.g.
+    /// because they're outside of its bounds, don't cull any faces.
+    pub fn sample<V: Voxel, S: VoxelStorage<V>>(
+        &self,
+        voxel_map: &S,
+        bounds: &Bounds,
+    ) -> VoxelSamples {
+        if bounds.is_empty() {
+            return VoxelSamples {
+                region: Bounds::empty(),
+                occupied: Vec::new(),
+                voxels: Vec::new(),
+            };
+        }
+
+        let region = Bounds::new(
+            bounds.min() - Vector3::repeat(1),
+            bounds.max() + Vector3::repeat(1),
+        );
+        let volume = (region.max() - region.min())
+            .iter()
+            .map(|&x| x as usize)
+            .product();
+        let mut samples = VoxelSamples {
+            region,
+            occupied: vec![false; volume],
+            voxels: Vec::new(),
+        };
+
+        for (coord, voxel) in voxel_map.iter_region(&samples.region) {
+            if voxel.occupied(&coord, self.world, self.resources) {
+                let index = samples.index(&coord);
+                samples.occupied[index] = true;
+            }
+
+            if !bounds.contains(&coord) {
+                continue;
+            }
+            if let Some(sprites) = voxel.texture(&coord, self.world, self.resources) {
+                samples.voxels.push(SampledVoxel {
+                    coord,
+                    sprites,
+                    tint: voxel.tint(&coord, self.world, self.resources),
+                    neighbor_culling: voxel.neighbor_culling(&coord, self.world, self.resources),
+                });
+            }
+        }
+
+        samples
+    }
+
+    /// Returns the visible faces of all voxels in `bounds`.
+    pub fn faces<V: Voxel, S: VoxelStorage<V>>(
+        &self,
+        voxel_map: &S,
+        bounds: &Bounds,
+    ) -> Vec<VoxelFace> {
+        self.sample(voxel_map, bounds).faces()
+    }
+}
+
+/// Everything needed to build the faces of a region of a voxel map, sampled by
+/// [`VoxelMesher::sample`].
+#[derive(Clone, Debug)]
+pub struct VoxelSamples {
+    /// The sampled bounds, extended by one voxel in every direction.
+    region: Bounds,
+
+    /// Whether each voxel in `region` is occupied, with x varying fastest,
+    /// then y, then z.
+    occupied: Vec<bool>,
+
+    /// Textured voxels in the sampled bounds.
+    voxels: Vec<SampledVoxel>,
+}
+
+#[derive(Clone, Debug)]
+struct SampledVoxel {
+    coord: Point3<i32>,
+    sprites: [usize; 6],
+    tint: Option<[Srgba; 6]>,
+    neighbor_culling: [bool; 6],
+}
+
+impl VoxelSamples {
+    /// Returns the visible faces of the sampled voxels. This doesn't need the
+    /// world or resources, so it can run on any thread.
+    pub fn faces(&self) -> Vec<VoxelFace> {
+        let mut faces = Vec::new();
+
+        for voxel in &self.voxels {
+            for face in 0..6 {
+                let (_, _, normal) = face_axes(face);
+                if !self.is_occupied(&(voxel.coord + normal)) || !voxel.neighbor_culling[face] {
+                    faces.push(VoxelFace {
+                        coord: voxel.coord,
+                        face,
+                        sprite: voxel.sprites[face],
+                        tint: voxel.tint.map(|tint| tint[face]),
+                        size: Vector2::new(1, 1),
+                    });
+                }
+            }
+        }
+
+        faces
+    }
+
+    fn is_occupied(&self, coord: &Point3<i32>) -> bool {
+        self.region.contains(coord) && self.occupied[self.index(coord)]
+    }
+
+    fn index(&self, coord: &Point3<i32>) -> usize {
+        let dimensions = (self.region.max() - self.region.min()).map(|x| x as usize);
+        let local = (coord - self.region.min()).map(|x| x as usize);
+        local.x + dimensions.x * (local.y + dimensions.y * local.z)
+    }
+}
+
+/// Returns the u and v axes of the texture and the normal of a face.
+fn face_axes(face: usize) -> (Vector3<i32>, Vector3<i32>, Vector3<i32>) {
+    let (u, v) = FACE_AXES[face];
+    let u = Vector3::from(u);
+    let v = Vector3::from(v);
+    (u, v, u.cross(&v))
+}
+
+/// Greedily merges adjacent coplanar faces with the same sprite and tint into
+/// rectangles. Rectangles are grown along the u axis of the texture first, then
+/// along the v axis.
+pub fn merge_faces(faces: &[VoxelFace]) -> Vec<VoxelFace> {
+    let mut merged = Vec::new();
+
+    for face in 0..6 {
+        let (u, v, normal) = face_axes(face);
+
+        let mut remaining = faces
+            .iter()
+            .filter(|single| single.face == face)
+            .map(|single| (single.coord, single))
+            .collect::<HashMap<_, _>>();
+
+        // Visit the faces slice by slice, in rows along the u axis. Then the
+        // first face that hasn't been merged yet is always the corner of a new
+        // rectangle.
+        let mut order = remaining.keys().copied().collect::<Vec<_>>();
+        order.sort_by_key(|coord| {
+            (
+                coord.coords.dot(&normal),
+                coord.coords.dot(&v),
+                coord.coords.dot(&u),
+            )
+        });
+
+        for start in order {
+            let first = match remaining.remove(&start) {
+                Some(first) => first,
+                None => continue,
+            };
+            let matches = |other: Option<&&VoxelFace>| {
+                other.map_or(false, |other| {
+                    other.sprite == first.sprite && other.tint == first.tint
+                })
+            };
+
+            let mut width = 1;
+            while matches(remaining.get(&(start + u * width))) {
+                remaining.remove(&(start + u * width));
+                width += 1;
+            }
+
+            let mut height = 1;
+            loop {
+                let row = start + v * height;
+                if !(0..width).all(|i| matches(remaining.get(&(row + u * i)))) {
+                    break;
+                }
+                for i in 0..width {
+                    remaining.remove(&(row + u * i));
+                }
+                height += 1;
+            }
+
+            merged.push(VoxelFace {
+                coord: start,
+                face,
+                sprite: first.sprite,
+                tint: first.tint,
+                size: Vector2::new(width as u32, height as u32),
+            });
+        }
+    }
+
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        storage::VecStorage,
+        testing::TestVoxel,
+        MortonEncoder,
+    };
+
+    fn storage(voxels: &[(Point3<i32>, u16)]) -> VecStorage<TestVoxel, MortonEncoder> {
+        let mut storage =
+            VecStorage::from_bounds(Bounds::new(Point3::origin(), Point3::new(3, 3, 3)));
+        for (coord, voxel) in voxels {
+            *storage.get_mut(coord).unwrap() = TestVoxel(*voxel);
+        }
+        storage
+    }
+
+    fn faces(storage: &VecStorage<TestVoxel, MortonEncoder>, bounds: &Bounds) -> Vec<VoxelFace> {
+        let (world, resources) = (World::default(), Resources::default());
+        VoxelMesher::new(&world, &resources).faces(storage, bounds)
+    }
+
+    fn visible(faces: &[VoxelFace], coord: Point3<i32>) -> Vec<usize> {
+        let mut visible: Vec<_> = faces
+            .iter()
+            .filter(|face| face.coord == coord)
+            .map(|face| face.face)
+            .collect();
+        visible.sort_unstable();
+        visible
+    }
+
+    #[test]
+    fn culls_faces_next_to_occupied_neighbours() {
+        let (a, b) = (Point3::new(1, 1, 1), Point3::new(2, 1, 1));
+        let storage = storage(&[(a, 1), (b, 2)]);
+        let faces = faces(&storage, &storage.bounds().unwrap());
+
+        assert_eq!(faces.len(), 10);
+        assert_eq!(visible(&faces, a), vec![0, 1, 2, 3, 4]);
+        assert_eq!(visible(&faces, b), vec![0, 1, 2, 3, 5]);
+    }
+
+    #[test]
+    fn culls_faces_next_to_neighbours_outside_bounds() {
+        let (a, b) = (Point3::new(1, 1, 1), Point3::new(1, 2, 1));
+        let storage = storage(&[(a, 1), (b, 1)]);
+        let faces = faces(&storage, &Bounds::new(a, a + Vector3::repeat(1)));
+
+        assert_eq!(visible(&faces, a), vec![0, 1, 3, 4, 5]);
+        assert_eq!(visible(&faces, b), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn keeps_faces_at_the_edge_of_the_storage() {
+        let corner = Point3::origin();
+        let storage = storage(&[(corner, 1)]);
+        let faces = faces(&storage, &storage.bounds().unwrap());
+
+        assert_eq!(visible(&faces, corner), vec![0, 1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn sprite_and_tint_per_face() {
+        let coord = Point3::new(0, 2, 1);
+        let storage = storage(&[(coord, 3)]);
+        let faces = faces(&storage, &storage.bounds().unwrap());
+
+        assert_eq!(faces.len(), 6);
+        for face in &faces {
+            assert_eq!(face.coord, coord);
+            assert_eq!(face.sprite, 18 + face.face);
+            assert_eq!(
+                face.tint,
+                Some(Srgba::new(face.face as f32 / 5.0, 0.0, 0.0, 1.0))
+            );
+            assert_eq!(face.size, Vector2::new(1, 1));
+        }
+    }
+
+    #[test]
+    fn greedy_meshing_merges_matching_faces() {
+        let layer = |top_right| {
+            storage(&[
+                (Point3::new(0, 0, 0), 1),
+                (Point3::new(1, 0, 0), 1),
+                (Point3::new(0, 1, 0), 1),
+                (Point3::new(1, 1, 0), top_right),
+            ])
+        };
+        let storage = layer(1);
+        let merged = merge_faces(&faces(&storage, &storage.bounds().unwrap()));
+        assert_eq!(merged.len(), 6);
+        let front = merged.iter().find(|face| face.face == 0).unwrap();
+        assert_eq!(front.coord, Point3::origin());
+        assert_eq!(front.size, Vector2::new(2, 2));
+
+        let storage = layer(2);
+        let merged = merge_faces(&faces(&storage, &storage.bounds().unwrap()));
+        let mut front = merged
+            .iter()
+            .filter(|face| face.face == 0)
+            .map(|face| (face.coord, face.size))
+            .collect::<Vec<_>>();
+        front.sort_by_key(|(coord, _)| (coord.y, coord.x));
+        assert_eq!(
+            front,
+            vec![
+                (Point3::new(0, 0, 0), Vector2::new(2, 1)),
+                (Point3::new(0, 1, 0), Vector2::new(1, 1)),
+                (Point3::new(1, 1, 0), Vector2::new(1, 1)),
+            ]
+        );
+
+        // Merged faces cover the same area as the single faces.
+        let area: u32 = merged.iter().map(|face| face.size.x * face.size.y).sum();
+        assert_eq!(area, 16);
+    }
+
+    #[test]
+    fn merges_coplanar_faces() {
+        let mut storage = VecStorage::<TestVoxel, MortonEncoder>::from_bounds(Bounds::new(
+            Point3::origin(),
+            Point3::new(4, 1, 3),
+        ));
+        for coord in &storage.bounds().unwrap() {
+            *storage.get_mut(&coord).unwrap() = TestVoxel(1);
+        }
+        *storage.get_mut(&Point3::new(3, 0, 2)).unwrap() = TestVoxel(2);
+
+        let single = faces(&storage, &storage.bounds().unwrap());
+        let merged = merge_faces(&single);
+        let area = |faces: &[VoxelFace]| -> u32 {
+            faces.iter().map(|face| face.size.x * face.size.y).sum()
+        };
+        assert_eq!(area(&merged), area(&single));
+
+        // Since v points to -z on +y faces, the top is merged row by row
+        // starting at the maximum z. The odd voxel ends the first row, so the
+        // rest of the column next to it becomes a rectangle of its own.
+        let mut top: Vec<_> = merged
+            .iter()
+            .filter(|face| face.face == 2)
+            .map(|face| (face.coord, face.size, face.sprite))
+            .collect();
+        top.sort_by_key(|(coord, _, _)| (coord.z, coord.x));
+        assert_eq!(
+            top,
+            vec![
+                (Point3::new(3, 0, 1), Vector2::new(1, 2), 8),
+                (Point3::new(0, 0, 2), Vector2::new(3, 3), 8),
+                (Point3::new(3, 0, 2), Vector2::new(1, 1), 14),
+            ]
+        );
+    }
+}
@@ -1,21 +1,29 @@
-use std::marker::PhantomData;
+use std::{
+    collections::{
+        BTreeMap,
+        HashMap,
+    },
+    marker::PhantomData,
+    ops::Range,
+};
 
 use amethyst_assets::AssetStorage;
 use amethyst_core::{
     ecs::{
         component,
+        Entity,
         IntoQuery,
         TryRead,
     },
-    math::Matrix4,
+    math::{
+        Matrix4,
+        Point3,
+        Vector3,
+    },
     Hidden,
     Transform,
 };
 use amethyst_rendy::{
-    batch::{
-        GroupIterator,
-        OrderedTwoLevelBatch,
-    },
     pipeline::{
         PipelineDescBuilder,
         PipelinesBuilder,
@@ -49,11 +57,13 @@ use amethyst_rendy::{
         },
     },
     resources::Tint,
-    sprite::Sprites,
+    sprite::{
+        Sprite,
+        Sprites,
+    },
     submodules::{
         gather::CameraGatherer,
         DynamicUniform,
-        DynamicVertexBuffer,
         TextureId,
         TextureSub,
     },
@@ -67,14 +77,23 @@ use amethyst_rendy::{
 use derivative::Derivative;
 use glsl_layout::Uniform;
 use lazy_static::lazy_static;
-use smallvec::SmallVec;
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
 
 use crate::{
     bounds::{
         compute_render_bounds,
+        Bounds,
         DrawVoxelsBounds,
         DrawVoxelsBoundsDefault,
     },
+    buffer::ChunkedVertexBuffer,
+    mesher::{
+        merge_faces,
+        VoxelFace,
+        VoxelMesher,
+        VoxelSamples,
+    },
     pod::{
         VoxelArgs,
         VoxelMapArgs,
@@ -84,6 +103,10 @@ use crate::{
     VoxelMap,
 };
 
+/// Edge length of the chunks the faces of a map are split into. Only the chunks
+/// around modified voxels are meshed and uploaded again.
+const CHUNK_SIZE: i32 = 16;
+
 lazy_static! {
     static ref VERTEX: SpirvShader = SpirvShader::from_bytes(
         include_bytes!("../shaders/compiled/voxels.vert.spv"),
@@ -114,10 +137,22 @@ pub struct DrawVoxelsDesc<
     S: VoxelStorage<V>,
     Z: DrawVoxelsBounds = DrawVoxelsBoundsDefault,
 > {
+    greedy_meshing: bool,
+
     #[derivative(Debug = "ignore")]
     _marker: PhantomData<(V, S, Z)>,
 }
 
+impl<V: Voxel, S: VoxelStorage<V>, Z: DrawVoxelsBounds> DrawVoxelsDesc<V, S, Z> {
+    /// Merges adjacent coplanar faces with the same sprite and tint into one
+    /// quad, which repeats the sprite. This reduces the number of faces of
+    /// large flat surfaces considerably, but makes meshing a bit slower.
+    pub fn with_greedy_meshing(mut self, greedy_meshing: bool) -> Self {
+        self.greedy_meshing = greedy_meshing;
+        self
+    }
+}
+
 impl<B: Backend, V: Voxel, S: VoxelStorage<V>, Z: DrawVoxelsBounds> RenderGroupDesc<B, GraphAuxData>
     for DrawVoxelsDesc<V, S, Z>
 {
@@ -140,7 +175,7 @@ impl<B: Backend, V: Voxel, S: VoxelStorage<V>, Z: DrawVoxelsBounds> RenderGroupD
             DynamicUniform::new(factory, hal::pso::ShaderStageFlags::VERTEX)?;
 
         let textures = TextureSub::new(factory)?;
-        let vertex = DynamicVertexBuffer::new();
+        let vertex = ChunkedVertexBuffer::new(VoxelArgs::empty());
 
         let (pipeline, pipeline_layout) = build_voxels_pipeline(
             factory,
@@ -156,9 +191,12 @@ impl<B: Backend, V: Voxel, S: VoxelStorage<V>, Z: DrawVoxelsBounds> RenderGroupD
             textures,
             vertex,
             env: vec![env],
-            batch: Default::default(),
+            draws: Vec::new(),
             _marker: PhantomData::default(),
             change: Default::default(),
+            meshes: HashMap::new(),
+            next_chunk_version: 0,
+            greedy_meshing: self.greedy_meshing,
         }))
     }
 }
@@ -174,10 +212,21 @@ pub struct DrawVoxels<
     pipeline: B::GraphicsPipeline,
     pipeline_layout: B::PipelineLayout,
     textures: TextureSub<B>,
-    vertex: DynamicVertexBuffer<B, VoxelArgs>,
-    batch: OrderedTwoLevelBatch<TextureId, usize, VoxelArgs>,
+    vertex: ChunkedVertexBuffer<B, (Entity, [i32; 3]), VoxelArgs>,
     change: ChangeDetection,
 
+    /// Texture, index into `env` and range of instances of every draw call.
+    draws: Vec<(TextureId, usize, Range<u32>)>,
+
+    /// Faces of every rendered map from the last frame.
+    #[derivative(Debug = "ignore")]
+    meshes: HashMap<Entity, MapMesh>,
+
+    /// Version assigned to the next chunk that is meshed.
+    next_chunk_version: u64,
+
+    greedy_meshing: bool,
+
     env: Vec<DynamicUniform<B, VoxelMapArgs>>,
 
     #[derivative(Debug = "ignore")]
@@ -212,135 +261,209 @@ impl<B: Backend, V: Voxel, S: VoxelStorage<V>, Z: DrawVoxelsBounds> RenderGroup<
             .expect("AssetStorage<Sprites> missing");
 
         let textures_ref = &mut self.textures;
-        let batch_ref = &mut self.batch;
-
-        batch_ref.swap_clear();
 
         let CameraGatherer { projview, .. } = CameraGatherer::gather(aux.world, aux.resources);
 
         let mut voxelmap_args = vec![];
 
+        // Entity, texture handle and index into `voxelmap_args` of every map.
+        let mut rendered = vec![];
+
+        // Entity, texture and index into `voxelmap_args` of every map with
+        // faces.
+        let mut visible = vec![];
+
+        // Chunks that need to be meshed again, and the sprites of their maps.
+        let mut jobs = vec![];
+        let mut map_sprites = vec![];
+
+        // Meshes of maps that aren't rendered anymore are dropped.
+        let mut old_meshes = std::mem::take(&mut self.meshes);
+
         let mut query =
-            <(&VoxelMap<V, S>, TryRead<Transform>)>::query().filter(!component::<Hidden>());
-
-        for (voxel_map, transform) in query.iter(aux.world) {
-            if let Some((sprite_sheet, sprites)) = sprite_sheet_storage
-                .get(&voxel_map.sprite_sheet)
-                .and_then(|sprite_sheet| {
-                    Some((sprite_sheet, {
-                        if let Some(sprites) = sprites_storage.get(&sprite_sheet.sprites) {
-                            Some(sprites)
-                        }
-                        else {
-                            log::error!("No Sprites found in SpritesStorage");
-                            None
-                        }
-                    }?))
-                })
-            {
-                let sprites = sprites.build_sprites();
-
-                let voxelmap_args_index = voxelmap_args.len();
-
-                let map_coordinate_transform: [[f32; 4]; 4] = voxel_map.transform.into();
-
-                let map_transform: [[f32; 4]; 4] = transform.map_or_else(
-                    || Matrix4::identity().into(),
-                    |transform| (*transform.global_matrix()).into(),
-                );
-
-                voxelmap_args.push(VoxelMapArgs {
-                    proj: projview.proj,
-                    view: projview.view,
-                    map_coordinate_transform: map_coordinate_transform.into(),
-                    map_transform: map_transform.into(),
-                    // TODO: Remove. This is unnecessarty, since you can just scale the
-                    // while VoxelMap.
-                    voxel_dimensions: [1.0, 1.0, 1.0].into(),
-                });
+            <(Entity, &VoxelMap<V, S>, TryRead<Transform>)>::query().filter(!component::<Hidden>());
+
+        for (entity, voxel_map, transform) in query.iter(aux.world) {
+            let (sprite_sheet, sprite_sheet_version) =
+                match sprite_sheet_storage.get_asset_with_version(&voxel_map.sprite_sheet) {
+                    Some(sprite_sheet) => sprite_sheet,
+                    None => continue,
+                };
+            let (sprites, sprites_version) =
+                match sprites_storage.get_asset_with_version(&sprite_sheet.sprites) {
+                    Some(sprites) => sprites,
+                    None => {
+                        log::error!("No Sprites found in SpritesStorage");
+                        continue;
+                    }
+                };
 
-                compute_render_bounds::<V, S, Z>(&voxel_map, transform, aux)
-                    .iter()
-                    .filter_map(|coord| {
-                        let voxel = voxel_map.get(&coord).unwrap();
-
-                        if let Some(tex_indices) = voxel.texture(&coord, aux.world, aux.resources) {
-                            let tint = voxel.tint(&coord, aux.world, aux.resources);
-                            let mut batch_datas = SmallVec::<[(TextureId, VoxelArgs); 6]>::new();
-
-                            let neighbors = voxel_map.get_neighbors(coord, aux);
-                            let neighbor_culling =
-                                voxel.neighbor_culling(&coord, &aux.world, &aux.resources);
-
-                            for face in 0..6 {
-                                if !neighbors[face] || !neighbor_culling[face] {
-                                    let (tex_id, this_changed) = {
-                                        let r = textures_ref.insert(
-                                            factory,
-                                            aux.resources,
-                                            &sprite_sheet.texture,
-                                            hal::image::Layout::ShaderReadOnlyOptimal,
-                                        );
-
-                                        if r.is_none() {
-                                            log::error!(
-                                                "Texture missing: {:?}",
-                                                sprite_sheet.texture
-                                            );
-                                        }
-
-                                        r?
-                                    };
-                                    changed = changed || this_changed;
-
-                                    let tint = tint.map(|t| Tint(t[face].clone()));
-
-                                    let sprite = sprites
-                                        .get(tex_indices[face])
-                                        .expect("Sprite number out of range");
-                                    let tex_coords = [
-                                        TexCoord([sprite.tex_coords.left, sprite.tex_coords.top]),
-                                        TexCoord([
-                                            sprite.tex_coords.right,
-                                            sprite.tex_coords.bottom,
-                                        ]),
-                                    ];
-
-                                    let batch_data = VoxelArgs::from_data(
-                                        &tex_coords,
-                                        //Some(&TintComponent(tile.tint(coord, aux.world))),
-                                        tint.as_ref(),
-                                        &coord,
-                                        face,
-                                    );
-
-                                    batch_datas.push((tex_id, batch_data));
-                                }
-                            }
+            let voxelmap_args_index = voxelmap_args.len();
 
-                            return Some(batch_datas);
-                        }
-                        None
-                    })
-                    .flatten()
-                    .for_each_group(|tex_id, batch_data| {
-                        batch_ref.insert(tex_id, voxelmap_args_index, batch_data.drain(..))
+            let map_coordinate_transform: [[f32; 4]; 4] = voxel_map.transform.into();
+
+            let map_transform: [[f32; 4]; 4] = transform.map_or_else(
+                || Matrix4::identity().into(),
+                |transform| (*transform.global_matrix()).into(),
+            );
+
+            voxelmap_args.push(VoxelMapArgs {
+                proj: projview.proj,
+                view: projview.view,
+                map_coordinate_transform: map_coordinate_transform.into(),
+                map_transform: map_transform.into(),
+                // TODO: Remove. This is unnecessarty, since you can just scale the
+                // while VoxelMap.
+                voxel_dimensions: [1.0, 1.0, 1.0].into(),
+            });
+
+            let render_bounds = compute_render_bounds::<V, S, Z>(&voxel_map, transform, aux);
+            let key = MeshKey {
+                map_id: voxel_map.id(),
+                sprite_sheet_version,
+                sprites_version,
+                render_bounds: render_bounds.clone(),
+            };
+
+            let (mut mesh, dirty) = match old_meshes.remove(entity) {
+                Some(mesh) if mesh.key == key => {
+                    // Faces at the border of the changed region depend on the
+                    // voxels on both sides, so the chunks next to it are meshed
+                    // again too.
+                    let dirty = voxel_map.changes_since(mesh.revision).map(|changes| {
+                        Bounds::new(
+                            changes.min() - Vector3::repeat(1),
+                            changes.max() + Vector3::repeat(1),
+                        )
+                        .intersection(&render_bounds)
+                    });
+                    (mesh, dirty)
+                }
+                _ => {
+                    let mesh = MapMesh {
+                        key,
+                        revision: 0,
+                        chunks: BTreeMap::new(),
+                    };
+                    (mesh, Some(render_bounds.clone()))
+                }
+            };
+            mesh.revision = voxel_map.revision();
+
+            if let Some(dirty) = dirty.filter(|dirty| !dirty.is_empty()) {
+                #[cfg(feature = "profiler")]
+                profile_scope!("sample_voxels");
+
+                let sprites_index = map_sprites.len();
+                map_sprites.push(sprites.build_sprites());
+                let mesher = VoxelMesher::new(aux.world, aux.resources);
+
+                for chunk in chunks_in(&dirty) {
+                    let bounds = chunk_bounds(chunk).intersection(&render_bounds);
+                    jobs.push(ChunkJob {
+                        entity: *entity,
+                        chunk,
+                        sprites_index,
+                        samples: mesher.sample(voxel_map, &bounds),
                     });
+                }
+            }
+
+            self.meshes.insert(*entity, mesh);
+            rendered.push((*entity, &sprite_sheet.texture, voxelmap_args_index));
+        }
+
+        {
+            #[cfg(feature = "profiler")]
+            profile_scope!("build_faces");
+
+            // The results are applied in the order of the jobs, such that the
+            // versions of the chunks don't depend on the scheduling of threads.
+            let chunk_faces = build_chunks(&jobs, &map_sprites, self.greedy_meshing);
+            for (job, faces) in jobs.iter().zip(chunk_faces) {
+                let mesh = self.meshes.get_mut(&job.entity).unwrap();
+                if faces.is_empty() {
+                    mesh.chunks.remove(&job.chunk);
+                }
+                else {
+                    let version = self.next_chunk_version;
+                    self.next_chunk_version += 1;
+                    mesh.chunks.insert(job.chunk, ChunkMesh { faces, version });
+                }
+            }
+        }
+
+        for (entity, texture, voxelmap_args_index) in rendered {
+            if self.meshes[&entity].chunks.is_empty() {
+                continue;
+            }
+
+            if let Some((tex_id, this_changed)) = textures_ref.insert(
+                factory,
+                aux.resources,
+                texture,
+                hal::image::Layout::ShaderReadOnlyOptimal,
+            ) {
+                changed = changed || this_changed;
+                visible.push((entity, tex_id, voxelmap_args_index));
+            }
+            else {
+                log::error!("Texture missing: {:?}", texture);
             }
         }
 
         self.textures.maintain(factory, aux.resources);
-        changed = changed || batch_ref.changed();
 
         {
             #[cfg(feature = "profiler")]
             profile_scope!("write");
-            self.vertex.write(
-                factory,
-                index,
-                batch_ref.count() as u64,
-                Some(batch_ref.data()),
-            );
+
+            let meshes = &self.meshes;
+            let chunks = visible
+                .iter()
+                .flat_map(|(entity, _, _)| {
+                    meshes[entity].chunks.iter().map(move |(chunk, mesh)| {
+                        ((*entity, *chunk), &mesh.faces[..], mesh.version)
+                    })
+                })
+                .collect::<Vec<_>>();
+            changed = self.vertex.write(factory, index, &chunks) || changed;
+
+            // The chunks of a map are placed next to each other in the buffer,
+            // unless some of them outgrew their range and were moved since the
+            // buffer was packed the last time. Neighbouring chunks are merged,
+            // so usually a map takes a single draw call. A merged draw includes
+            // the padding in the spare capacity between its chunks, which
+            // consists of instances with a face size of 0. They only cost
+            // vertex shader invocations.
+            let mut draws: Vec<(TextureId, usize, Range<u32>)> = Vec::new();
+            let mut slot_end = None;
+            for (entity, tex_id, voxelmap_args_index) in &visible {
+                for chunk in meshes[entity].chunks.keys() {
+                    let (range, end) = self.vertex.range(&(*entity, *chunk)).unwrap();
+
+                    match draws.last_mut() {
+                        Some((last_tex_id, last_args_index, last_range))
+                            if last_tex_id == tex_id
+                                && last_args_index == voxelmap_args_index
+                                && slot_end == Some(range.start) =>
+                        {
+                            if !range.is_empty() {
+                                last_range.end = range.end;
+                            }
+                            slot_end = Some(end);
+                        }
+                        // Empty chunks that can't be merged don't need a draw.
+                        _ if range.is_empty() => slot_end = None,
+                        _ => {
+                            draws.push((*tex_id, *voxelmap_args_index, range));
+                            slot_end = Some(end);
+                        }
+                    }
+                }
+            }
+            changed = changed || draws != self.draws;
+            self.draws = draws;
 
             // grow tilemap_args cache if necessary, or shrink it
             if self.env.len() < voxelmap_args.len() || self.env.len() <= voxelmap_args.len() / 2 {
@@ -370,17 +493,15 @@ impl<B: Backend, V: Voxel, S: VoxelStorage<V>, Z: DrawVoxelsBounds> RenderGroup<
         let layout = &self.pipeline_layout;
         encoder.bind_graphics_pipeline(&self.pipeline);
 
-        self.vertex.bind(index, 0, 0, &mut encoder);
-        for (&tex, ranges) in self.batch.iter() {
-            if self.textures.loaded(tex) {
-                self.textures.bind(layout, 1, tex, &mut encoder);
+        self.vertex.bind(index, 0, &mut encoder);
+        for (tex, voxelmap_args_index, range) in &self.draws {
+            if self.textures.loaded(*tex) {
+                self.textures.bind(layout, 1, *tex, &mut encoder);
 
-                for (voxelmap_args_index, range) in ranges {
-                    let env = self.env.get(*voxelmap_args_index).unwrap();
-                    env.bind(index, layout, 0, &mut encoder);
-                    unsafe {
-                        encoder.draw(0..4, range.to_owned());
-                    }
+                let env = self.env.get(*voxelmap_args_index).unwrap();
+                env.bind(index, layout, 0, &mut encoder);
+                unsafe {
+                    encoder.draw(0..4, range.to_owned());
                 }
             }
         }
@@ -396,6 +517,128 @@ impl<B: Backend, V: Voxel, S: VoxelStorage<V>, Z: DrawVoxelsBounds> RenderGroup<
     }
 }
 
+/// Everything the faces of a voxel map depend on, apart from its voxels.
+#[derive(Debug, PartialEq)]
+struct MeshKey {
+    map_id: u64,
+    sprite_sheet_version: u32,
+    sprites_version: u32,
+    render_bounds: Bounds,
+}
+
+/// Faces of a voxel map, split into chunks. When the voxels of the map change,
+/// only the chunks around the changed region are meshed again. Everything is
+/// meshed again if the map, its sprite sheet or its render bounds change.
+/// Changes of world or resources that affect the textures, tints or culling of
+/// voxels aren't detected.
+struct MapMesh {
+    key: MeshKey,
+
+    /// Revision of the map the chunks have been meshed from.
+    revision: u64,
+
+    /// Non-empty chunks by their coordinates in chunks.
+    chunks: BTreeMap<[i32; 3], ChunkMesh>,
+}
+
+struct ChunkMesh {
+    faces: Vec<VoxelArgs>,
+
+    /// Changes whenever the chunk is meshed again, such that it's uploaded.
+    version: u64,
+}
+
+/// A chunk that is meshed again in this frame.
+struct ChunkJob {
+    entity: Entity,
+    chunk: [i32; 3],
+
+    /// Index of the sprites of the map.
+    sprites_index: usize,
+
+    /// Samples of all voxels in the chunk.
+    samples: VoxelSamples,
+}
+
+/// Culls the faces of a chunk and turns them into instances, merging them
+/// first if greedy meshing is enabled.
+fn build_chunk(
+    job: &ChunkJob,
+    map_sprites: &[Vec<Sprite>],
+    greedy_meshing: bool,
+) -> Vec<VoxelArgs> {
+    let sprites = &map_sprites[job.sprites_index];
+    let mut faces = job.samples.faces();
+    if greedy_meshing {
+        faces = merge_faces(&faces);
+    }
+
+    faces.iter().map(|face| voxel_args(face, sprites)).collect()
+}
+
+/// Builds the instances of all chunks, in the order of `jobs`.
+///
+/// Sampling the voxels is not parallelized: it calls the methods of [`Voxel`]
+/// with the world and resources, and legion's `Resources` is `!Sync`. So the
+/// samples are taken serially on the main thread before this is called, and
+/// only culling, merging and converting the sampled faces runs in parallel.
+#[cfg(feature = "rayon")]
+fn build_chunks(
+    jobs: &[ChunkJob],
+    map_sprites: &[Vec<Sprite>],
+    greedy_meshing: bool,
+) -> Vec<Vec<VoxelArgs>> {
+    jobs.par_iter()
+        .map(|job| build_chunk(job, map_sprites, greedy_meshing))
+        .collect()
+}
+
+/// Builds the instances of all chunks, in the order of `jobs`.
+#[cfg(not(feature = "rayon"))]
+fn build_chunks(
+    jobs: &[ChunkJob],
+    map_sprites: &[Vec<Sprite>],
+    greedy_meshing: bool,
+) -> Vec<Vec<VoxelArgs>> {
+    jobs.iter()
+        .map(|job| build_chunk(job, map_sprites, greedy_meshing))
+        .collect()
+}
+
+/// Coordinates of all chunks that intersect a non-empty region.
+fn chunks_in(bounds: &Bounds) -> impl Iterator<Item = [i32; 3]> {
+    let min = bounds.min().map(|x| x.div_euclid(CHUNK_SIZE));
+    let max = bounds.max().map(|x| (x - 1).div_euclid(CHUNK_SIZE));
+
+    (min.z..=max.z).flat_map(move |z| {
+        (min.y..=max.y).flat_map(move |y| (min.x..=max.x).map(move |x| [x, y, z]))
+    })
+}
+
+fn chunk_bounds(chunk: [i32; 3]) -> Bounds {
+    let min = Point3::from(chunk).map(|x| x * CHUNK_SIZE);
+    Bounds::new(min, min + Vector3::repeat(CHUNK_SIZE))
+}
+
+fn voxel_args(face: &VoxelFace, sprites: &[Sprite]) -> VoxelArgs {
+    let sprite = sprites
+        .get(face.sprite)
+        .expect("Sprite number out of range");
+    let tex_coords = [
+        TexCoord([sprite.tex_coords.left, sprite.tex_coords.top]),
+        TexCoord([sprite.tex_coords.right, sprite.tex_coords.bottom]),
+    ];
+    let tint = face.tint.map(Tint);
+
+    VoxelArgs::from_data(
+        &tex_coords,
+        tint.as_ref(),
+        &face.coord,
+        face.face,
+        &face.size,
+    )
+}
+
 fn build_voxels_pipeline<B: Backend>(
     factory: &Factory<B>,
     subpass: hal::pass::Subpass<'_, B>,
@@ -1,7 +1,10 @@
 #![allow(clippy::default_trait_access)]
 //! GPU POD data types.
 
-use amethyst_core::math::Point3;
+use amethyst_core::math::{
+    Point3,
+    Vector2,
+};
 use amethyst_rendy::{
     pod::IntoPod,
     rendy::{
@@ -18,6 +21,7 @@ use glsl_layout::{
     ivec3,
     mat4,
     uint,
+    uvec2,
     vec2,
     vec3,
     vec4,
@@ -59,9 +63,10 @@ pub struct VoxelMapArgs {
 /// layout(location = 2) in vec4 color;
 /// layout(location = 3) in ivec3 voxel_coordinate;
 /// layout(location = 4) in uint face;
+/// layout(location = 5) in uvec2 face_size;
 /// ```
 #[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Uniform)]
-#[repr(C, align(16))]
+#[repr(C, align(4))]
 pub struct VoxelArgs {
     /// Upper-left coordinate of the sprite in the spritesheet
     pub u_offset: vec2,
@@ -73,6 +78,9 @@ pub struct VoxelArgs {
     pub voxel_coordinate: ivec3,
     // /// Face
     pub face: uint,
+    /// Number of voxels the face spans along the u and v axes of the texture.
+    /// The sprite is repeated once per voxel.
+    pub face_size: uvec2,
 }
 
 impl AsVertex for VoxelArgs {
@@ -84,6 +92,7 @@ impl AsVertex for VoxelArgs {
             (Format::Rgba32Sfloat, "tint"),
             (Format::Rgb32Sint, "voxel_coordinate"),
             (Format::R32Uint, "face"), // TODO: How do you use a R8Uint here?
+            (Format::Rg32Uint, "face_size"),
         ))
     }
 }
@@ -96,6 +105,7 @@ impl VoxelArgs {
         tint: Option<&TintComponent>,
         voxel_coordinate: &Point3<i32>,
         face: usize,
+        face_size: &Vector2<u32>,
     ) -> Self {
         Self {
             u_offset: [tex_coords[0].0[0], tex_coords[0].0[1]].into(),
@@ -103,6 +113,22 @@ impl VoxelArgs {
             tint: tint.map_or([1.0; 4].into(), |t| t.0.into_pod()),
             voxel_coordinate: [voxel_coordinate.x, voxel_coordinate.y, voxel_coordinate.z].into(),
             face: (face as u32).into(),
+            face_size: [face_size.x, face_size.y].into(),
+        }
+    }
+
+    /// Returns an instance with a transparent tint whose face has a size of 0.
+    /// All of its vertices end up at the same position, so it doesn't draw
+    /// anything.
+    #[must_use]
+    pub fn empty() -> Self {
+        Self {
+            u_offset: [0.0; 2].into(),
+            v_offset: [0.0; 2].into(),
+            tint: [0.0; 4].into(),
+            voxel_coordinate: [0; 3].into(),
+            face: 0.into(),
+            face_size: [0; 2].into(),
         }
     }
 }
@@ -0,0 +1,417 @@
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+};
+
+use amethyst_core::math::{
+    Point3,
+    Vector3,
+};
+#[cfg(feature = "serde")]
+use serde::{
+    de::Error as _,
+    Deserialize,
+    Deserializer,
+    Serialize,
+    Serializer,
+};
+
+#[cfg(feature = "serde")]
+use crate::storage::EncoderTag;
+use crate::{
+    bounds::Bounds,
+    map::CoordinateEncoder,
+    storage::{
+        FromBounds,
+        VecStorage,
+        VoxelStorage,
+        VoxelStorageIterMut,
+    },
+    Voxel,
+};
+
+/// Default edge length of a chunk in [`ChunkedStorage`].
+pub const DEFAULT_CHUNK_SIZE: u32 = 16;
+
+/// Unbounded storage that splits the map into fixed-size chunks. Each chunk is
+/// a [`VecStorage`] covering its region of the map and is only allocated when
+/// one of its voxels is accessed with `get_mut`. Voxels in chunks that aren't
+/// allocated are returned as `V::default()` by `get`, but they're outside of
+/// the bounds of the storage, so iterators skip them.
+#[derive(Clone, Debug)]
+pub struct ChunkedStorage<V, E> {
+    chunk_dimensions: Vector3<u32>,
+    chunks: HashMap<Point3<i32>, VecStorage<V, E>>,
+    default: V,
+}
+
+impl<V: Voxel, E: CoordinateEncoder> ChunkedStorage<V, E> {
+    pub fn new(chunk_dimensions: Vector3<u32>) -> Self {
+        assert!(chunk_dimensions.iter().all(|&x| x > 0));
+
+        Self {
+            chunk_dimensions,
+            chunks: HashMap::new(),
+            default: V::default(),
+        }
+    }
+
+    pub fn chunk_dimensions(&self) -> Vector3<u32> {
+        self.chunk_dimensions
+    }
+
+    /// Splits voxel coordinates into the coordinates of the chunk and the
+    /// coordinates within that chunk.
+    pub fn split_coordinates(&self, coord: &Point3<i32>) -> (Point3<i32>, Point3<i32>) {
+        let dimensions: Vector3<i32> = self.chunk_dimensions.map(|x| x.try_into().unwrap());
+
+        let chunk = Point3::new(
+            coord.x.div_euclid(dimensions.x),
+            coord.y.div_euclid(dimensions.y),
+            coord.z.div_euclid(dimensions.z),
+        );
+        let local = Point3::new(
+            coord.x.rem_euclid(dimensions.x),
+            coord.y.rem_euclid(dimensions.y),
+            coord.z.rem_euclid(dimensions.z),
+        );
+
+        (chunk, local)
+    }
+
+    /// Returns the region of voxel coordinates covered by a chunk. Chunks at
+    /// the edge of the coordinate space are cut off at `i32::MIN` and
+    /// `i32::MAX`.
+    pub fn chunk_bounds(&self, chunk: &Point3<i32>) -> Bounds {
+        let clamp = |x: i64| x.clamp(i32::MIN.into(), i32::MAX.into()) as i32;
+
+        let min = chunk
+            .coords
+            .zip_map(&self.chunk_dimensions, |c, d| i64::from(c) * i64::from(d));
+        let max = min + self.chunk_dimensions.map(i64::from);
+        Bounds::new(Point3::from(min.map(clamp)), Point3::from(max.map(clamp)))
+    }
+
+    pub fn chunk(&self, chunk: &Point3<i32>) -> Option<&VecStorage<V, E>> {
+        self.chunks.get(chunk)
+    }
+
+    /// Iterates over all allocated chunks.
+    pub fn chunks(&self) -> impl Iterator<Item = (&Point3<i32>, &VecStorage<V, E>)> {
+        self.chunks.iter()
+    }
+
+    /// Returns the allocated chunks that intersect `bounds`, ordered by z, y
+    /// and x.
+    fn allocated_chunks_in(&self, bounds: &Bounds) -> Vec<Point3<i32>> {
+        if bounds.is_empty() {
+            return Vec::new();
+        }
+
+        let (min, _) = self.split_coordinates(&bounds.min());
+        let (max, _) = self.split_coordinates(&(bounds.max() - Vector3::repeat(1)));
+        let region = Bounds::new(min, max + Vector3::repeat(1));
+        let num_chunks = (max - min)
+            .iter()
+            .fold(1u64, |n, &x| n.saturating_mul(x as u64 + 1));
+
+        let mut chunks: Vec<_> = if num_chunks <= self.chunks.len() as u64 {
+            region
+                .iter()
+                .filter(|chunk| self.chunks.contains_key(chunk))
+                .collect()
+        }
+        else {
+            self.chunks
+                .keys()
+                .filter(|chunk| region.contains(chunk))
+                .copied()
+                .collect()
+        };
+        chunks.sort_unstable_by_key(|chunk| (chunk.z, chunk.y, chunk.x));
+        chunks
+    }
+
+    fn allocate_chunk(&mut self, chunk: Point3<i32>) -> &mut VecStorage<V, E> {
+        let bounds = self.chunk_bounds(&chunk);
+        self.chunks
+            .entry(chunk)
+            .or_insert_with(|| VecStorage::from_bounds(bounds))
+    }
+
+    /// Frees a chunk. Its voxels will read as `V::default()` afterwards.
+    pub fn remove_chunk(&mut self, chunk: &Point3<i32>) -> Option<VecStorage<V, E>> {
+        self.chunks.remove(chunk)
+    }
+}
+
+impl<V: Voxel, E: CoordinateEncoder> Default for ChunkedStorage<V, E> {
+    fn default() -> Self {
+        Self::new(Vector3::repeat(DEFAULT_CHUNK_SIZE))
+    }
+}
+
+impl<V: Voxel, E: CoordinateEncoder> FromBounds<V> for ChunkedStorage<V, E> {
+    /// Creates a storage with the default chunk size and allocates all chunks
+    /// intersecting `bounds`.
+    fn from_bounds(bounds: Bounds) -> Self {
+        let mut storage = Self::default();
+
+        if !bounds.is_empty() {
+            let (min, _) = storage.split_coordinates(&bounds.min());
+            let (max, _) = storage.split_coordinates(&(bounds.max() - Vector3::repeat(1)));
+
+            for chunk in &Bounds::new(min, max + Vector3::repeat(1)) {
+                storage.allocate_chunk(chunk);
+            }
+        }
+
+        storage
+    }
+}
+
+impl<V: Voxel, E: CoordinateEncoder> VoxelStorage<V> for ChunkedStorage<V, E> {
+    fn origin(&self) -> Point3<i32> {
+        self.bounds().unwrap().min()
+    }
+
+    fn dimensions(&self) -> Vector3<u32> {
+        let bounds = self.bounds().unwrap();
+        (bounds.max() - bounds.min()).map(|x| x.try_into().unwrap())
+    }
+
+    /// Returns the union of all allocated chunks.
+    fn bounds(&self) -> Option<Bounds> {
+        Some(self.chunks.keys().fold(Bounds::empty(), |bounds, chunk| {
+            bounds.union(&self.chunk_bounds(chunk))
+        }))
+    }
+
+    fn get(&self, coord: &Point3<i32>) -> Option<&V> {
+        let (chunk, _) = self.split_coordinates(coord);
+
+        match self.chunks.get(&chunk) {
+            Some(chunk) => chunk.get(coord),
+            None => Some(&self.default),
+        }
+    }
+
+    fn get_mut(&mut self, coord: &Point3<i32>) -> Option<&mut V> {
+        let (chunk, _) = self.split_coordinates(coord);
+        self.allocate_chunk(chunk).get_mut(coord)
+    }
+
+    /// Iterates over the voxels of all allocated chunks.
+    fn iter(&self) -> Box<dyn Iterator<Item = (Point3<i32>, &V)> + '_> {
+        Box::new(self.chunks.values().flat_map(|chunk| chunk.iter()))
+    }
+
+    /// Iterates over the voxels in `bounds` of all allocated chunks, chunk by
+    /// chunk.
+    fn iter_region(&self, bounds: &Bounds) -> Box<dyn Iterator<Item = (Point3<i32>, &V)> + '_> {
+        let bounds = bounds.clone();
+        Box::new(
+            self.allocated_chunks_in(&bounds)
+                .into_iter()
+                .flat_map(move |chunk| self.chunks[&chunk].iter_region(&bounds)),
+        )
+    }
+}
+
+impl<V: Voxel, E: CoordinateEncoder> VoxelStorageIterMut<V> for ChunkedStorage<V, E> {
+    /// Iterates over the voxels of all allocated chunks.
+    fn iter_mut(&mut self) -> Box<dyn Iterator<Item = (Point3<i32>, &mut V)> + '_> {
+        Box::new(self.chunks.values_mut().flat_map(|chunk| chunk.iter_mut()))
+    }
+
+    /// Iterates over the voxels in `bounds` of all allocated chunks, in the
+    /// same order as `iter_region`. No chunks are allocated.
+    fn iter_region_mut(
+        &mut self,
+        bounds: &Bounds,
+    ) -> Box<dyn Iterator<Item = (Point3<i32>, &mut V)> + '_> {
+        let bounds = bounds.clone();
+        let mut chunks: Vec<_> = self
+            .chunks
+            .iter_mut()
+            .filter(|(_, chunk)| chunk.bounds().unwrap().intersects(&bounds))
+            .collect();
+        chunks.sort_unstable_by_key(|(chunk, _)| (chunk.z, chunk.y, chunk.x));
+
+        Box::new(
+            chunks
+                .into_iter()
+                .flat_map(move |(_, chunk)| chunk.iter_region_mut(&bounds)),
+        )
+    }
+}
+
+/// Serializes the chunk dimensions and the allocated chunks.
+#[cfg(feature = "serde")]
+impl<V: Voxel + Serialize, E: EncoderTag> Serialize for ChunkedStorage<V, E> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        #[serde(bound = "V: Serialize")]
+        struct ChunkedStorageData<'a, V: Voxel + Serialize, E: EncoderTag> {
+            chunk_dimensions: Vector3<u32>,
+            chunks: Vec<&'a VecStorage<V, E>>,
+        }
+
+        ChunkedStorageData {
+            chunk_dimensions: self.chunk_dimensions,
+            chunks: self.chunks.values().collect(),
+        }
+        .serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, V: Voxel + Deserialize<'de>, E: EncoderTag> Deserialize<'de> for ChunkedStorage<V, E> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(bound = "V: Voxel + Deserialize<'de>, E: EncoderTag")]
+        struct ChunkedStorageData<V: Voxel, E: EncoderTag> {
+            chunk_dimensions: Vector3<u32>,
+            chunks: Vec<VecStorage<V, E>>,
+        }
+
+        let data = ChunkedStorageData::<V, E>::deserialize(deserializer)?;
+
+        if data.chunk_dimensions.iter().any(|&x| x == 0) {
+            return Err(D::Error::custom("Chunk dimensions must not be zero"));
+        }
+
+        let mut storage = Self::new(data.chunk_dimensions);
+        for chunk in data.chunks {
+            let bounds = chunk.bounds().unwrap();
+            let (key, _) = storage.split_coordinates(&bounds.min());
+
+            if bounds != storage.chunk_bounds(&key) {
+                return Err(D::Error::custom(format!(
+                    "Chunk with bounds {:?} isn't aligned to the chunk grid",
+                    bounds
+                )));
+            }
+
+            storage.chunks.insert(key, chunk);
+        }
+
+        Ok(storage)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::TestVoxel,
+        MortonEncoder,
+    };
+
+    #[test]
+    fn allocates_chunks_on_write() {
+        let mut storage = ChunkedStorage::<TestVoxel, MortonEncoder>::new(Vector3::new(4, 2, 8));
+        let coord = Point3::new(-1, 5, 8);
+
+        assert_eq!(storage.get(&coord), Some(&TestVoxel(0)));
+        assert_eq!(storage.chunks().count(), 0);
+
+        *storage.get_mut(&coord).unwrap() = TestVoxel(1);
+        assert_eq!(storage.get(&coord), Some(&TestVoxel(1)));
+        assert_eq!(storage.get(&Point3::new(-2, 5, 8)), Some(&TestVoxel(0)));
+        assert_eq!(
+            storage.split_coordinates(&coord),
+            (Point3::new(-1, 2, 1), Point3::new(3, 1, 0))
+        );
+        assert_eq!(
+            storage.bounds(),
+            Some(Bounds::new(Point3::new(-4, 4, 8), Point3::new(0, 6, 16)))
+        );
+
+        assert!(storage.remove_chunk(&Point3::new(-1, 2, 1)).is_some());
+        assert_eq!(storage.get(&coord), Some(&TestVoxel(0)));
+    }
+
+    #[test]
+    fn chunk_bounds_at_the_edge_of_the_coordinate_space() {
+        let storage = ChunkedStorage::<TestVoxel, MortonEncoder>::new(Vector3::new(3, 16, 1));
+
+        let (chunk, _) = storage.split_coordinates(&Point3::new(i32::MIN, i32::MAX, 0));
+        assert_eq!(
+            storage.chunk_bounds(&chunk),
+            Bounds::new(
+                Point3::new(i32::MIN, i32::MAX - 15, 0),
+                Point3::new(i32::MIN + 2, i32::MAX, 1)
+            )
+        );
+    }
+
+    #[test]
+    fn iterates_allocated_chunks() {
+        let mut storage = ChunkedStorage::<TestVoxel, MortonEncoder>::new(Vector3::new(4, 2, 8));
+        *storage.get_mut(&Point3::new(-1, 5, 8)).unwrap() = TestVoxel(1);
+        *storage.get_mut(&Point3::new(6, 0, -3)).unwrap() = TestVoxel(2);
+        *storage.get_mut(&Point3::new(7, 1, 9)).unwrap() = TestVoxel(3);
+        assert_eq!(storage.iter().count(), 3 * 64);
+
+        let region = Bounds::new(Point3::new(-2, 0, -8), Point3::new(7, 6, 10));
+        let voxels: Vec<_> = storage
+            .iter_region(&region)
+            .filter(|(_, voxel)| voxel.0 != 0)
+            .map(|(coord, voxel)| (coord, *voxel))
+            .collect();
+        assert_eq!(
+            voxels,
+            vec![
+                (Point3::new(6, 0, -3), TestVoxel(2)),
+                (Point3::new(-1, 5, 8), TestVoxel(1)),
+            ]
+        );
+
+        let coords: Vec<_> = storage
+            .iter_region(&region)
+            .map(|(coord, _)| coord)
+            .collect();
+        assert_eq!(coords.len(), 3 * 2 * 8 + 2 * 2 * 2 + 3 * 2 * 2);
+        assert!(coords.iter().all(|coord| region.contains(coord)));
+        let coords_mut: Vec<_> = storage
+            .iter_region_mut(&region)
+            .map(|(coord, _)| coord)
+            .collect();
+        assert_eq!(coords_mut, coords);
+        assert_eq!(storage.chunks().count(), 3);
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn serde_round_trip() {
+        use crate::testing::{
+            assert_same_voxels,
+            serde_round_trip,
+        };
+
+        let mut storage = ChunkedStorage::<TestVoxel, MortonEncoder>::new(Vector3::new(4, 2, 8));
+        *storage.get_mut(&Point3::new(-1, 5, 8)).unwrap() = TestVoxel(1);
+        *storage.get_mut(&Point3::new(6, 0, -3)).unwrap() = TestVoxel(2);
+
+        let deserialized = serde_round_trip(&storage);
+        assert_eq!(deserialized.chunk_dimensions(), storage.chunk_dimensions());
+        assert_eq!(deserialized.chunks().count(), 2);
+        assert_same_voxels(&deserialized, &storage);
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn serde_rejects_unaligned_chunks() {
+        let mut chunk = VecStorage::<TestVoxel, MortonEncoder>::from_bounds(Bounds::new(
+            Point3::new(1, 0, 0),
+            Point3::new(5, 2, 8),
+        ));
+        *chunk.get_mut(&Point3::new(1, 0, 0)).unwrap() = TestVoxel(1);
+        let json = format!(
+            r#"{{"chunk_dimensions":[4,2,8],"chunks":[{}]}}"#,
+            serde_json::to_string(&chunk).unwrap()
+        );
+        assert!(serde_json::from_str::<ChunkedStorage<TestVoxel, MortonEncoder>>(&json).is_err());
+    }
+}
@@ -0,0 +1,347 @@
+use std::{
+    convert::TryInto,
+    ops::Range,
+};
+
+use amethyst_core::math::{
+    Point3,
+    Vector3,
+};
+
+use crate::{
+    bounds::Bounds,
+    storage::{
+        FromBounds,
+        VoxelStorage,
+    },
+    Voxel,
+};
+
+/// Bounded storage that keeps every (x, z) column as runs of identical voxels
+/// along the y-axis. This is a good fit for terrain, which usually consists
+/// of long vertical runs.
+///
+/// `get_mut` splits the run containing the voxel it returns. Neighbouring runs
+/// that ended up identical are merged on the next call to `get_mut` or
+/// [`ColumnStorage::flush`], once the new value of the voxel is known.
+#[derive(Clone, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(
+        try_from = "RawColumnStorage<V>",
+        bound(deserialize = "V: serde::Deserialize<'de>")
+    )
+)]
+pub struct ColumnStorage<V> {
+    origin: Point3<i32>,
+    dimensions: Vector3<u32>,
+    /// Runs of each column, indexed by `x + z * dimensions.x`.
+    columns: Vec<Vec<Run<V>>>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    last_edit: Option<usize>,
+}
+
+/// Unvalidated column storage, as it's deserialized.
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct RawColumnStorage<V> {
+    origin: Point3<i32>,
+    dimensions: Vector3<u32>,
+    columns: Vec<Vec<Run<V>>>,
+}
+
+#[cfg(feature = "serde")]
+impl<V> std::convert::TryFrom<RawColumnStorage<V>> for ColumnStorage<V> {
+    type Error = &'static str;
+
+    fn try_from(raw: RawColumnStorage<V>) -> Result<Self, Self::Error> {
+        let height = raw.dimensions.y;
+
+        if raw.columns.len() != (raw.dimensions.x * raw.dimensions.z) as usize {
+            return Err("Number of columns doesn't match the dimensions");
+        }
+
+        for column in &raw.columns {
+            let ascending = column.windows(2).all(|runs| runs[0].end < runs[1].end);
+            let end = column.last().map_or(0, |run| run.end);
+            if !ascending || end != height || column.first().map_or(false, |run| run.end == 0) {
+                return Err("Runs of a column don't cover its height");
+            }
+        }
+
+        Ok(Self {
+            origin: raw.origin,
+            dimensions: raw.dimensions,
+            columns: raw.columns,
+            last_edit: None,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct Run<V> {
+    /// End of this run relative to the bottom of the column (exclusive). The
+    /// run starts at the end of the previous one.
+    end: u32,
+    voxel: V,
+}
+
+impl<V: Voxel + PartialEq> ColumnStorage<V> {
+    pub fn from_bounds(bounds: Bounds) -> Self {
+        let dimensions: Vector3<u32> = (bounds.max() - bounds.min()).map(|x| x.try_into().unwrap());
+        let num_columns = (dimensions.x * dimensions.z) as usize;
+
+        let column = if dimensions.y > 0 {
+            vec![Run {
+                end: dimensions.y,
+                voxel: V::default(),
+            }]
+        }
+        else {
+            vec![]
+        };
+
+        Self {
+            origin: bounds.min(),
+            dimensions,
+            columns: vec![column; num_columns],
+            last_edit: None,
+        }
+    }
+
+    /// Merges identical neighbouring runs in the column of the voxel handed
+    /// out by the last call to `get_mut`.
+    pub fn flush(&mut self) {
+        if let Some(index) = self.last_edit.take() {
+            let column = &mut self.columns[index];
+            let mut merged: Vec<Run<V>> = Vec::with_capacity(column.len());
+
+            for run in column.drain(..) {
+                match merged.last_mut() {
+                    Some(last) if last.voxel == run.voxel => last.end = run.end,
+                    _ => merged.push(run),
+                }
+            }
+
+            *column = merged;
+        }
+    }
+
+    /// Iterates over the runs of a column, or returns `None` if the column is
+    /// out of bounds.
+    pub fn column(&self, x: i32, z: i32) -> Option<ColumnIter<'_, V>> {
+        let index = self.column_index(x, z)?;
+        Some(ColumnIter::new(self.origin.y, &self.columns[index]))
+    }
+
+    /// Iterates over all columns with their (x, z) coordinates.
+    pub fn columns(&self) -> impl Iterator<Item = ((i32, i32), ColumnIter<'_, V>)> {
+        let origin = self.origin;
+        let width = self.dimensions.x as usize;
+
+        self.columns.iter().enumerate().map(move |(index, runs)| {
+            let x = origin.x + (index % width) as i32;
+            let z = origin.z + (index / width) as i32;
+            ((x, z), ColumnIter::new(origin.y, runs))
+        })
+    }
+
+    /// Returns the top-most voxel of a column for which `predicate` returns
+    /// `true`, together with its y-coordinate.
+    pub fn top_most<F>(&self, x: i32, z: i32, mut predicate: F) -> Option<(i32, &V)>
+    where
+        F: FnMut(&V) -> bool,
+    {
+        let index = self.column_index(x, z)?;
+
+        self.columns[index]
+            .iter()
+            .rev()
+            .find(|run| predicate(&run.voxel))
+            .map(|run| (self.origin.y + run.end as i32 - 1, &run.voxel))
+    }
+
+    fn column_index(&self, x: i32, z: i32) -> Option<usize> {
+        let x: u32 = (x - self.origin.x).try_into().ok()?;
+        let z: u32 = (z - self.origin.z).try_into().ok()?;
+
+        if x < self.dimensions.x && z < self.dimensions.z {
+            Some((x + z * self.dimensions.x) as usize)
+        }
+        else {
+            None
+        }
+    }
+
+    /// Returns the index of the column and the height within that column.
+    fn split_coordinates(&self, coord: &Point3<i32>) -> Option<(usize, u32)> {
+        let index = self.column_index(coord.x, coord.z)?;
+        let y: u32 = (coord.y - self.origin.y).try_into().ok()?;
+
+        if y < self.dimensions.y {
+            Some((index, y))
+        }
+        else {
+            None
+        }
+    }
+}
+
+impl<V: Voxel + PartialEq> FromBounds<V> for ColumnStorage<V> {
+    fn from_bounds(bounds: Bounds) -> Self {
+        ColumnStorage::from_bounds(bounds)
+    }
+}
+
+impl<V: Voxel + PartialEq> VoxelStorage<V> for ColumnStorage<V> {
+    fn origin(&self) -> Point3<i32> {
+        self.origin
+    }
+
+    fn dimensions(&self) -> Vector3<u32> {
+        self.dimensions
+    }
+
+    fn get(&self, coord: &Point3<i32>) -> Option<&V> {
+        let (index, y) = self.split_coordinates(coord)?;
+        let column = &self.columns[index];
+        let run = column.partition_point(|run| run.end <= y);
+
+        Some(&column[run].voxel)
+    }
+
+    fn get_mut(&mut self, coord: &Point3<i32>) -> Option<&mut V> {
+        let (index, y) = self.split_coordinates(coord)?;
+
+        if self.last_edit != Some(index) {
+            self.flush();
+            self.last_edit = Some(index);
+        }
+
+        let column = &mut self.columns[index];
+        let mut run = column.partition_point(|run| run.end <= y);
+        let start = if run == 0 { 0 } else { column[run - 1].end };
+        let end = column[run].end;
+
+        // Split the run, such that the voxel has a run of its own.
+        if y + 1 < end {
+            let voxel = column[run].voxel.clone();
+            column[run].end = y + 1;
+            column.insert(run + 1, Run { end, voxel });
+        }
+        if y > start {
+            let voxel = column[run].voxel.clone();
+            column.insert(run, Run { end: y, voxel });
+            run += 1;
+        }
+
+        Some(&mut column[run].voxel)
+    }
+}
+
+/// Iterator over the runs of a column in [`ColumnStorage`], from bottom to
+/// top. Yields the range of y-coordinates of each run and its voxel.
+#[derive(Clone, Debug)]
+pub struct ColumnIter<'a, V> {
+    bottom: i32,
+    start: u32,
+    runs: std::slice::Iter<'a, Run<V>>,
+}
+
+impl<'a, V> ColumnIter<'a, V> {
+    fn new(bottom: i32, runs: &'a [Run<V>]) -> Self {
+        Self {
+            bottom,
+            start: 0,
+            runs: runs.iter(),
+        }
+    }
+}
+
+impl<'a, V> Iterator for ColumnIter<'a, V> {
+    type Item = (Range<i32>, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let run = self.runs.next()?;
+        let range = self.bottom + self.start as i32..self.bottom + run.end as i32;
+        self.start = run.end;
+        Some((range, &run.voxel))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestVoxel;
+
+    fn runs(storage: &ColumnStorage<TestVoxel>) -> Vec<(Range<i32>, u16)> {
+        storage
+            .column(1, -1)
+            .unwrap()
+            .map(|(range, voxel)| (range, voxel.0))
+            .collect()
+    }
+
+    #[test]
+    fn splits_and_merges_runs() {
+        let mut storage =
+            ColumnStorage::from_bounds(Bounds::new(Point3::new(0, -2, -1), Point3::new(2, 6, 1)));
+        assert_eq!(runs(&storage), vec![(-2..6, 0)]);
+
+        *storage.get_mut(&Point3::new(1, 1, -1)).unwrap() = TestVoxel(1);
+        storage.flush();
+        assert_eq!(runs(&storage), vec![(-2..1, 0), (1..2, 1), (2..6, 0)]);
+
+        *storage.get_mut(&Point3::new(1, 2, -1)).unwrap() = TestVoxel(1);
+        *storage.get_mut(&Point3::new(1, -2, -1)).unwrap() = TestVoxel(2);
+        storage.flush();
+        assert_eq!(
+            runs(&storage),
+            vec![(-2..-1, 2), (-1..1, 0), (1..3, 1), (3..6, 0)]
+        );
+        assert_eq!(
+            storage.top_most(1, -1, |voxel| voxel.0 != 0),
+            Some((2, &TestVoxel(1)))
+        );
+
+        for y in -2..3 {
+            *storage.get_mut(&Point3::new(1, y, -1)).unwrap() = TestVoxel(0);
+        }
+        storage.flush();
+        assert_eq!(runs(&storage), vec![(-2..6, 0)]);
+        assert_eq!(storage.top_most(1, -1, |voxel| voxel.0 != 0), None);
+    }
+
+    #[test]
+    fn get_out_of_bounds() {
+        let storage = ColumnStorage::<TestVoxel>::from_bounds(Bounds::new(
+            Point3::new(0, 0, 0),
+            Point3::new(2, 2, 2),
+        ));
+        assert_eq!(storage.get(&Point3::new(1, 1, 1)), Some(&TestVoxel(0)));
+        assert_eq!(storage.get(&Point3::new(2, 1, 1)), None);
+        assert_eq!(storage.get(&Point3::new(1, -1, 1)), None);
+        assert!(storage.column(0, 2).is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn serde_round_trip() {
+        use crate::testing::{
+            assert_same_voxels,
+            serde_round_trip,
+        };
+
+        let mut storage =
+            ColumnStorage::from_bounds(Bounds::new(Point3::new(0, -2, -1), Point3::new(2, 6, 1)));
+        *storage.get_mut(&Point3::new(1, 1, -1)).unwrap() = TestVoxel(1);
+        *storage.get_mut(&Point3::new(0, 5, 0)).unwrap() = TestVoxel(2);
+        storage.flush();
+        assert_same_voxels(&serde_round_trip(&storage), &storage);
+
+        let json = r#"{"origin":[0,0,0],"dimensions":[1,4,1],"columns":[[{"end":2,"voxel":0}]]}"#;
+        assert!(serde_json::from_str::<ColumnStorage<TestVoxel>>(json).is_err());
+    }
+}
@@ -0,0 +1,966 @@
+pub mod chunked;
+mod column;
+mod octree;
+pub mod palette;
+mod sparse;
+
+use std::{
+    convert::TryInto,
+    ops::Range,
+};
+
+use amethyst_core::math::{
+    Point3,
+    Vector3,
+};
+#[cfg(feature = "serde")]
+use serde::{
+    de::Error as _,
+    Deserialize,
+    Deserializer,
+    Serialize,
+    Serializer,
+};
+
+pub use self::{
+    chunked::ChunkedStorage,
+    column::{
+        ColumnIter,
+        ColumnStorage,
+    },
+    octree::{
+        OctreeNode,
+        OctreeStorage,
+    },
+    palette::PaletteStorage,
+    sparse::SparseStorage,
+};
+use crate::{
+    bounds::Bounds,
+    map::CoordinateEncoder,
+    Voxel,
+};
+#[cfg(feature = "serde")]
+use crate::{
+    FlatEncoder,
+    MortonEncoder,
+};
+
+/// Trait that provides access to voxels. You can use [`VecStorage`] to store
+/// the voxels in an array in-memory, or implement your own provider (e.g. by
+/// loading voxels from a file).
+pub trait VoxelStorage<V>: Send + Sync + 'static {
+    // TODO: Deprecate in favor of `bounds`
+    fn origin(&self) -> Point3<i32>;
+
+    // TODO: Deprecate in favor of `bounds`
+    fn dimensions(&self) -> Vector3<u32>;
+
+    fn bounds(&self) -> Option<Bounds> {
+        let min = self.origin();
+        let dimensions = self.dimensions().map(|x| x.try_into().unwrap());
+        let max = min + dimensions;
+        Some(Bounds::new(min, max))
+    }
+
+    fn get(&self, coord: &Point3<i32>) -> Option<&V>;
+
+    fn get_mut(&mut self, coord: &Point3<i32>) -> Option<&mut V>;
+
+    /// Iterates over all voxels and their coordinates. Storages should yield
+    /// them in the order they're laid out in memory. Storages that don't keep
+    /// every voxel in their bounds may skip voxels that aren't stored.
+    fn iter(&self) -> Box<dyn Iterator<Item = (Point3<i32>, &V)> + '_> {
+        match self.bounds() {
+            Some(bounds) => self.iter_region(&bounds),
+            None => Box::new(std::iter::empty()),
+        }
+    }
+
+    /// Iterates over all voxels in `bounds` and their coordinates. Voxels
+    /// outside of the storage are skipped.
+    fn iter_region(&self, bounds: &Bounds) -> Box<dyn Iterator<Item = (Point3<i32>, &V)> + '_> {
+        Box::new(
+            bounds
+                .iter()
+                .filter_map(move |coord| self.get(&coord).map(|voxel| (coord, voxel))),
+        )
+    }
+
+    /// Sets all voxels in `bounds` to `voxel`. Voxels outside of the storage
+    /// are ignored.
+    fn fill(&mut self, bounds: &Bounds, voxel: V)
+    where
+        V: Clone,
+    {
+        for coord in bounds {
+            if let Some(target) = self.get_mut(&coord) {
+                *target = voxel.clone();
+            }
+        }
+    }
+
+    /// Copies the voxels in `src` to the region of the same size starting at
+    /// `dst_origin`. The regions may overlap.
+    fn copy_region(&mut self, src: &Bounds, dst_origin: &Point3<i32>)
+    where
+        V: Clone,
+    {
+        let offset = dst_origin - src.min();
+        let voxels: Vec<Option<V>> = src.iter().map(|coord| self.get(&coord).cloned()).collect();
+
+        for (coord, voxel) in src.iter().zip(voxels) {
+            if let (Some(voxel), Some(target)) = (voxel, self.get_mut(&(coord + offset))) {
+                *target = voxel;
+            }
+        }
+    }
+
+    /// Swaps the voxels in `a` with the voxels in the region of the same size
+    /// starting at `b_origin`. Voxels are only swapped if both of them are
+    /// inside the storage.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the regions overlap.
+    fn swap_regions(&mut self, a: &Bounds, b_origin: &Point3<i32>)
+    where
+        V: Clone,
+    {
+        let offset = b_origin - a.min();
+        let b = Bounds::new(*b_origin, a.max() + offset);
+        assert!(!a.intersects(&b), "Swapped regions overlap");
+
+        for coord in a {
+            let other = coord + offset;
+            if let (Some(voxel), Some(other_voxel)) =
+                (self.get(&coord).cloned(), self.get(&other).cloned())
+            {
+                *self.get_mut(&coord).unwrap() = other_voxel;
+                *self.get_mut(&other).unwrap() = voxel;
+            }
+        }
+    }
+
+    /// Sets all voxels in `bounds` for which `predicate` returns `true` to
+    /// `voxel`.
+    fn replace_if<F>(&mut self, bounds: &Bounds, mut predicate: F, voxel: V)
+    where
+        Self: Sized,
+        V: Clone,
+        F: FnMut(&V) -> bool,
+    {
+        for coord in bounds {
+            if self.get(&coord).map_or(false, &mut predicate) {
+                *self.get_mut(&coord).unwrap() = voxel.clone();
+            }
+        }
+    }
+}
+
+/// Storages that can hand out mutable references to many voxels at once.
+/// This isn't possible for storages that compress voxels, since those only
+/// allow modifying one voxel at a time.
+pub trait VoxelStorageIterMut<V>: VoxelStorage<V> {
+    /// Iterates mutably over all voxels and their coordinates, in the same
+    /// order as [`VoxelStorage::iter`].
+    fn iter_mut(&mut self) -> Box<dyn Iterator<Item = (Point3<i32>, &mut V)> + '_>;
+
+    /// Iterates mutably over all voxels in `bounds` and their coordinates.
+    fn iter_region_mut(
+        &mut self,
+        bounds: &Bounds,
+    ) -> Box<dyn Iterator<Item = (Point3<i32>, &mut V)> + '_>;
+}
+
+/// Storages that can be created to cover a region, with all voxels set to
+/// `V::default()`. Unbounded storages only use the region as a hint, e.g. to
+/// allocate chunks up front.
+pub trait FromBounds<V>: VoxelStorage<V> {
+    /// Creates a storage covering `bounds`.
+    ///
+    /// # Panics
+    ///
+    /// May panic if [`FromBounds::supports_bounds`] returns `false` for
+    /// `bounds`.
+    fn from_bounds(bounds: Bounds) -> Self;
+
+    /// Returns whether [`FromBounds::from_bounds`] can create a storage for
+    /// `bounds`. By default the extent along each axis must fit into an
+    /// `i32`.
+    fn supports_bounds(bounds: &Bounds) -> bool {
+        let (min, max) = (bounds.min(), bounds.max());
+        (0..3).all(|i| max[i].checked_sub(min[i]).is_some())
+    }
+}
+
+/// Bounded storage that keeps all voxels in a single array. The order of the
+/// voxels in memory is determined by the [`CoordinateEncoder`].
+#[derive(Clone, Debug)]
+pub struct VecStorage<V, E> {
+    origin: Point3<i32>,
+    dimensions: Vector3<u32>,
+    voxels: Vec<V>,
+    encoder: E,
+}
+
+impl<V: Voxel, E: CoordinateEncoder> VecStorage<V, E> {
+    pub fn from_dimensions(dimensions: Vector3<u32>) -> Self {
+        let max = dimensions.map(|x| x.try_into().unwrap());
+        Self::from_bounds(Bounds::new(Point3::origin(), Point3::from(max)))
+    }
+
+    /// Creates a storage covering `bounds`. The origin may have negative
+    /// coordinates.
+    pub fn from_bounds(bounds: Bounds) -> Self {
+        let dimensions = (bounds.max() - bounds.min()).map(|x| x.try_into().unwrap());
+        let encoder = E::from_dimensions(dimensions);
+        let num_voxels = E::allocation_size(dimensions);
+        let mut voxels = Vec::with_capacity(num_voxels);
+        voxels.resize_with(num_voxels, V::default);
+
+        Self {
+            origin: bounds.min(),
+            dimensions,
+            voxels,
+            encoder,
+        }
+    }
+
+    /// Changes the region covered by this storage. Voxels inside both the old
+    /// and new region are kept, new voxels are set to `V::default()`.
+    pub fn resize(&mut self, bounds: Bounds) {
+        let mut resized = Self::from_bounds(bounds);
+
+        for coord in &self
+            .bounds()
+            .unwrap()
+            .intersection(&resized.bounds().unwrap())
+        {
+            let voxel = std::mem::take(self.get_mut(&coord).unwrap());
+            *resized.get_mut(&coord).unwrap() = voxel;
+        }
+
+        *self = resized;
+    }
+
+    /// Grows the storage such that it contains `coord`. Returns `true` if the
+    /// storage had to be resized.
+    pub fn grow_to_include(&mut self, coord: &Point3<i32>) -> bool {
+        let bounds = self.bounds().unwrap();
+        if bounds.contains(coord) {
+            return false;
+        }
+
+        let voxel_bounds = Bounds::new(*coord, coord + Vector3::repeat(1));
+        self.resize(bounds.union(&voxel_bounds));
+        true
+    }
+
+    /// Moves all voxels by `offset`.
+    pub fn shift(&mut self, offset: &Vector3<i32>) {
+        self.origin += offset;
+    }
+
+    /// Returns the range in `voxels` of the `len` voxels along the x-axis
+    /// starting at `coord`, if they're stored contiguously. This assumes that
+    /// the encoder is monotonic along the x-axis, which is the case for both
+    /// [`FlatEncoder`](crate::FlatEncoder) and
+    /// [`MortonEncoder`](crate::MortonEncoder).
+    fn row(&self, coord: &Point3<i32>, len: i32) -> Option<Range<usize>> {
+        if len == 0 {
+            return None;
+        }
+
+        let start = self.index(coord)?;
+        let end = self.index(&(coord + Vector3::new(len - 1, 0, 0)))? + 1;
+
+        if end - start == len as usize {
+            Some(start..end)
+        }
+        else {
+            None
+        }
+    }
+
+    /// Returns the rows along the x-axis of `bounds`, which must be inside this
+    /// storage. Each row is returned as its first coordinate and its range in
+    /// `voxels`, if it's contiguous.
+    fn rows(&self, bounds: &Bounds) -> Vec<(Point3<i32>, Option<Range<usize>>)> {
+        let len = row_length(bounds);
+
+        row_starts(bounds)
+            .map(|coord| (coord, self.row(&coord, len)))
+            .collect()
+    }
+
+    /// Returns whether the rows along the x-axis of `bounds` are contiguous and
+    /// stored one after another, which is the case for
+    /// [`FlatEncoder`](crate::FlatEncoder). `bounds` must be non-empty and
+    /// inside this storage.
+    fn rows_in_order(&self, bounds: &Bounds) -> bool {
+        let len = row_length(bounds);
+        let mut end = 0;
+
+        row_starts(bounds).all(|coord| {
+            match self.row(&coord, len) {
+                Some(range) if range.start >= end => {
+                    end = range.end;
+                    true
+                }
+                _ => false,
+            }
+        })
+    }
+
+    /// Returns the range in `voxels` that contains all voxels of `bounds`,
+    /// which must be non-empty and inside this storage. Like [`Self::row`] this
+    /// assumes that the encoder is monotonic along every axis.
+    fn region_range(&self, bounds: &Bounds) -> Range<usize> {
+        let start = self.index(&bounds.min()).unwrap();
+        let end = self.index(&(bounds.max() - Vector3::repeat(1))).unwrap() + 1;
+        start..end
+    }
+
+    /// Computes the coordinates of the voxel at `index`. Returns `None` for
+    /// padding that some encoders allocate.
+    fn coordinates(&self, index: usize) -> Option<Point3<i32>> {
+        decode_index(&self.encoder, &self.origin, &self.dimensions, index)
+    }
+
+    /// Computes the index into `voxels` for the given coordinates, or `None` if
+    /// they're out of bounds.
+    fn index(&self, coord: &Point3<i32>) -> Option<usize> {
+        encode_index(&self.encoder, &self.origin, &self.dimensions, coord)
+    }
+}
+
+impl<V: Voxel, E: CoordinateEncoder> VoxelStorage<V> for VecStorage<V, E> {
+    fn origin(&self) -> Point3<i32> {
+        self.origin
+    }
+
+    fn dimensions(&self) -> Vector3<u32> {
+        self.dimensions
+    }
+
+    fn get(&self, coord: &Point3<i32>) -> Option<&V> {
+        let index = self.index(coord)?;
+        self.voxels.get(index)
+    }
+
+    fn get_mut(&mut self, coord: &Point3<i32>) -> Option<&mut V> {
+        let index = self.index(coord)?;
+        self.voxels.get_mut(index)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (Point3<i32>, &V)> + '_> {
+        Box::new(
+            self.voxels
+                .iter()
+                .enumerate()
+                .filter_map(move |(index, voxel)| Some((self.coordinates(index)?, voxel))),
+        )
+    }
+
+    /// Iterates over the voxels in `bounds` in the order they're stored. If the
+    /// rows of the region are stored one after another, only the rows are
+    /// visited. Otherwise all voxels between the first and the last voxel of
+    /// the region are visited, and the ones outside of it are skipped.
+    fn iter_region(&self, bounds: &Bounds) -> Box<dyn Iterator<Item = (Point3<i32>, &V)> + '_> {
+        let bounds = bounds.intersection(&self.bounds().unwrap());
+        if bounds.is_empty() {
+            return Box::new(std::iter::empty());
+        }
+
+        if self.rows_in_order(&bounds) {
+            let len = row_length(&bounds);
+            Box::new(row_starts(&bounds).flat_map(move |start| {
+                let range = self.row(&start, len).unwrap();
+                self.voxels[range]
+                    .iter()
+                    .zip(0..)
+                    .map(move |(voxel, x)| (start + Vector3::new(x, 0, 0), voxel))
+            }))
+        }
+        else {
+            let range = self.region_range(&bounds);
+            let offset = range.start;
+            Box::new(
+                self.voxels[range]
+                    .iter()
+                    .zip(offset..)
+                    .filter_map(move |(voxel, index)| {
+                        let coord = self.coordinates(index)?;
+                        if bounds.contains(&coord) {
+                            Some((coord, voxel))
+                        }
+                        else {
+                            None
+                        }
+                    }),
+            )
+        }
+    }
+
+    fn fill(&mut self, bounds: &Bounds, voxel: V) {
+        let bounds = bounds.intersection(&self.bounds().unwrap());
+        let len = row_length(&bounds);
+
+        if bounds.is_empty() {
+            return;
+        }
+
+        for (start, range) in self.rows(&bounds) {
+            match range {
+                Some(range) => self.voxels[range].fill(voxel.clone()),
+                None => {
+                    for x in 0..len {
+                        let index = self.index(&(start + Vector3::new(x, 0, 0))).unwrap();
+                        self.voxels[index] = voxel.clone();
+                    }
+                }
+            }
+        }
+    }
+
+    fn copy_region(&mut self, src: &Bounds, dst_origin: &Point3<i32>) {
+        let offset = dst_origin - src.min();
+        let dst = Bounds::new(*dst_origin, src.max() + offset);
+        // Only copy voxels that are inside the storage in both regions.
+        let src = dst.intersection(&self.bounds().unwrap());
+        let src = Bounds::new(src.min() - offset, src.max() - offset)
+            .intersection(&self.bounds().unwrap());
+        let len = row_length(&src);
+
+        if src.is_empty() {
+            return;
+        }
+
+        let rows = self.rows(&src);
+        let mut buffer = Vec::with_capacity(src.volume() as usize);
+        for (start, range) in &rows {
+            match range {
+                Some(range) => buffer.extend_from_slice(&self.voxels[range.clone()]),
+                None => {
+                    buffer.extend((0..len).map(|x| {
+                        let index = self.index(&(start + Vector3::new(x, 0, 0))).unwrap();
+                        self.voxels[index].clone()
+                    }))
+                }
+            }
+        }
+
+        let dst = Bounds::new(src.min() + offset, src.max() + offset);
+        for ((start, range), row) in self.rows(&dst).into_iter().zip(buffer.chunks(len as usize)) {
+            match range {
+                Some(range) => self.voxels[range].clone_from_slice(row),
+                None => {
+                    for (x, voxel) in row.iter().enumerate() {
+                        let index = self.index(&(start + Vector3::new(x as i32, 0, 0))).unwrap();
+                        self.voxels[index] = voxel.clone();
+                    }
+                }
+            }
+        }
+    }
+
+    fn swap_regions(&mut self, a: &Bounds, b_origin: &Point3<i32>) {
+        let offset = b_origin - a.min();
+        let b = Bounds::new(*b_origin, a.max() + offset);
+        assert!(!a.intersects(&b), "Swapped regions overlap");
+
+        // No need to clone voxels here, since they can be swapped in place.
+        for coord in a {
+            if let (Some(index), Some(other)) = (self.index(&coord), self.index(&(coord + offset)))
+            {
+                self.voxels.swap(index, other);
+            }
+        }
+    }
+
+    fn replace_if<F>(&mut self, bounds: &Bounds, mut predicate: F, voxel: V)
+    where
+        F: FnMut(&V) -> bool,
+    {
+        let bounds = bounds.intersection(&self.bounds().unwrap());
+        let len = row_length(&bounds);
+
+        if bounds.is_empty() {
+            return;
+        }
+
+        for (start, range) in self.rows(&bounds) {
+            match range {
+                Some(range) => {
+                    for target in &mut self.voxels[range] {
+                        if predicate(target) {
+                            *target = voxel.clone();
+                        }
+                    }
+                }
+                None => {
+                    for x in 0..len {
+                        let index = self.index(&(start + Vector3::new(x, 0, 0))).unwrap();
+                        if predicate(&self.voxels[index]) {
+                            self.voxels[index] = voxel.clone();
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<V: Voxel, E: CoordinateEncoder> FromBounds<V> for VecStorage<V, E> {
+    fn from_bounds(bounds: Bounds) -> Self {
+        VecStorage::from_bounds(bounds)
+    }
+}
+
+impl<V: Voxel, E: CoordinateEncoder> VoxelStorageIterMut<V> for VecStorage<V, E> {
+    fn iter_mut(&mut self) -> Box<dyn Iterator<Item = (Point3<i32>, &mut V)> + '_> {
+        let encoder = &self.encoder;
+        let origin = self.origin;
+        let dimensions = self.dimensions;
+
+        Box::new(
+            self.voxels
+                .iter_mut()
+                .enumerate()
+                .filter_map(move |(index, voxel)| {
+                    Some((decode_index(encoder, &origin, &dimensions, index)?, voxel))
+                }),
+        )
+    }
+
+    fn iter_region_mut(
+        &mut self,
+        bounds: &Bounds,
+    ) -> Box<dyn Iterator<Item = (Point3<i32>, &mut V)> + '_> {
+        let bounds = bounds.intersection(&self.bounds().unwrap());
+        if bounds.is_empty() {
+            return Box::new(std::iter::empty());
+        }
+
+        let encoder = &self.encoder;
+        let origin = self.origin;
+        let dimensions = self.dimensions;
+
+        if self.rows_in_order(&bounds) {
+            let len = row_length(&bounds);
+            let mut rest = &mut self.voxels[..];
+            let mut offset = 0;
+
+            // Since the rows are in order, we can split off every row from the
+            // remaining slice.
+            Box::new(row_starts(&bounds).flat_map(move |start| {
+                let index = encode_index(encoder, &origin, &dimensions, &start).unwrap();
+                let (_, tail) = std::mem::take(&mut rest).split_at_mut(index - offset);
+                let (row, tail) = tail.split_at_mut(len as usize);
+                rest = tail;
+                offset = index + len as usize;
+
+                row.iter_mut()
+                    .zip(0..)
+                    .map(move |(voxel, x)| (start + Vector3::new(x, 0, 0), voxel))
+            }))
+        }
+        else {
+            let range = self.region_range(&bounds);
+            let offset = range.start;
+            Box::new(self.voxels[range].iter_mut().zip(offset..).filter_map(
+                move |(voxel, index)| {
+                    let coord = decode_index(encoder, &origin, &dimensions, index)?;
+                    if bounds.contains(&coord) {
+                        Some((coord, voxel))
+                    }
+                    else {
+                        None
+                    }
+                },
+            ))
+        }
+    }
+}
+
+/// Computes the index of a voxel in a [`VecStorage`], or `None` if it's out of
+/// bounds.
+fn encode_index<E: CoordinateEncoder>(
+    encoder: &E,
+    origin: &Point3<i32>,
+    dimensions: &Vector3<u32>,
+    coord: &Point3<i32>,
+) -> Option<usize> {
+    let x: u32 = (coord.x - origin.x).try_into().ok()?;
+    let y: u32 = (coord.y - origin.y).try_into().ok()?;
+    let z: u32 = (coord.z - origin.z).try_into().ok()?;
+
+    // Not all encoders check this.
+    if x >= dimensions.x || y >= dimensions.y || z >= dimensions.z {
+        return None;
+    }
+
+    let index = encoder.encode(x, y, z)?;
+    Some(index as usize)
+}
+
+/// Computes the coordinates of the voxel at `index` in a [`VecStorage`].
+/// Returns `None` for padding that some encoders allocate.
+fn decode_index<E: CoordinateEncoder>(
+    encoder: &E,
+    origin: &Point3<i32>,
+    dimensions: &Vector3<u32>,
+    index: usize,
+) -> Option<Point3<i32>> {
+    let (x, y, z) = encoder.decode(index.try_into().ok()?)?;
+
+    if x < dimensions.x && y < dimensions.y && z < dimensions.z {
+        Some(origin + Vector3::new(x, y, z).map(|x| x as i32))
+    }
+    else {
+        None
+    }
+}
+
+fn row_length(bounds: &Bounds) -> i32 {
+    bounds.max().x - bounds.min().x
+}
+
+/// Iterates over the first coordinates of the rows along the x-axis of
+/// `bounds`.
+fn row_starts(bounds: &Bounds) -> impl Iterator<Item = Point3<i32>> {
+    let (min, max) = (bounds.min(), bounds.max());
+    (min.z..max.z).flat_map(move |z| (min.y..max.y).map(move |y| Point3::new(min.x, y, z)))
+}
+
+/// Encoders whose layout can be stored together with the voxels of a
+/// [`VecStorage`]. The tag identifies the order of the voxels in memory and
+/// must never change, since it's written to serialized maps.
+#[cfg(feature = "serde")]
+pub trait EncoderTag: CoordinateEncoder {
+    const TAG: &'static str;
+}
+
+#[cfg(feature = "serde")]
+impl EncoderTag for FlatEncoder {
+    const TAG: &'static str = "flat";
+}
+
+#[cfg(feature = "serde")]
+impl EncoderTag for MortonEncoder {
+    const TAG: &'static str = "morton";
+}
+
+/// Serializes the voxels in their memory layout, together with the tag of the
+/// encoder. Deserializing fails if the encoder doesn't match.
+#[cfg(feature = "serde")]
+impl<V: Voxel + Serialize, E: EncoderTag> Serialize for VecStorage<V, E> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct VecStorageData<'a, V> {
+            origin: Point3<i32>,
+            dimensions: Vector3<u32>,
+            encoder: &'a str,
+            voxels: &'a [V],
+        }
+
+        VecStorageData {
+            origin: self.origin,
+            dimensions: self.dimensions,
+            encoder: E::TAG,
+            voxels: &self.voxels,
+        }
+        .serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, V: Voxel + Deserialize<'de>, E: EncoderTag> Deserialize<'de> for VecStorage<V, E> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct VecStorageData<V> {
+            origin: Point3<i32>,
+            dimensions: Vector3<u32>,
+            encoder: String,
+            voxels: Vec<V>,
+        }
+
+        let data = VecStorageData::<V>::deserialize(deserializer)?;
+
+        if data.encoder != E::TAG {
+            return Err(D::Error::custom(format!(
+                "Expected voxels encoded with {}, but found {}",
+                E::TAG,
+                data.encoder
+            )));
+        }
+        if data.voxels.len() != E::allocation_size(data.dimensions) {
+            return Err(D::Error::invalid_length(
+                data.voxels.len(),
+                &"number of voxels allocated by the encoder",
+            ));
+        }
+
+        Ok(Self {
+            origin: data.origin,
+            dimensions: data.dimensions,
+            voxels: data.voxels,
+            encoder: E::from_dimensions(data.dimensions),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::TestVoxel,
+        FlatEncoder,
+        MortonEncoder,
+    };
+
+    fn numbered(bounds: Bounds) -> VecStorage<TestVoxel, MortonEncoder> {
+        let mut storage = VecStorage::from_bounds(bounds.clone());
+        for (i, coord) in bounds.iter().enumerate() {
+            *storage.get_mut(&coord).unwrap() = TestVoxel(i as u16 + 1);
+        }
+        storage
+    }
+
+    #[test]
+    fn resize_keeps_overlapping_voxels() {
+        let bounds = Bounds::new(Point3::new(-2, 0, 1), Point3::new(2, 3, 4));
+        let before = numbered(bounds.clone());
+        let mut storage = before.clone();
+
+        let resized = Bounds::new(Point3::new(0, -1, 2), Point3::new(5, 2, 3));
+        storage.resize(resized.clone());
+
+        assert_eq!(storage.bounds(), Some(resized.clone()));
+        for coord in &resized {
+            let expected = if bounds.contains(&coord) {
+                before.get(&coord)
+            }
+            else {
+                Some(&TestVoxel(0))
+            };
+            assert_eq!(storage.get(&coord), expected);
+        }
+    }
+
+    #[test]
+    fn grow_to_include() {
+        let bounds = Bounds::new(Point3::new(0, 0, 0), Point3::new(2, 2, 2));
+        let mut storage = numbered(bounds.clone());
+
+        assert!(!storage.grow_to_include(&Point3::new(1, 1, 1)));
+        assert_eq!(storage.bounds(), Some(bounds.clone()));
+
+        assert!(storage.grow_to_include(&Point3::new(-1, 4, 1)));
+        assert_eq!(
+            storage.bounds(),
+            Some(Bounds::new(Point3::new(-1, 0, 0), Point3::new(2, 5, 2)))
+        );
+        assert_eq!(storage.get(&Point3::new(1, 1, 1)), Some(&TestVoxel(8)));
+        assert_eq!(storage.get(&Point3::new(-1, 4, 1)), Some(&TestVoxel(0)));
+    }
+
+    #[test]
+    fn shift_moves_voxels() {
+        let mut storage = numbered(Bounds::new(Point3::new(0, 0, 0), Point3::new(2, 2, 2)));
+        storage.shift(&Vector3::new(-3, 1, 0));
+
+        assert_eq!(storage.origin(), Point3::new(-3, 1, 0));
+        assert_eq!(storage.get(&Point3::new(-2, 2, 1)), Some(&TestVoxel(8)));
+        assert_eq!(storage.get(&Point3::new(1, 1, 1)), None);
+    }
+
+    /// Storage that only implements the required methods, such that the
+    /// provided bulk operations are used.
+    struct Reference(VecStorage<TestVoxel, FlatEncoder>);
+
+    impl VoxelStorage<TestVoxel> for Reference {
+        fn origin(&self) -> Point3<i32> {
+            self.0.origin()
+        }
+
+        fn dimensions(&self) -> Vector3<u32> {
+            self.0.dimensions()
+        }
+
+        fn get(&self, coord: &Point3<i32>) -> Option<&TestVoxel> {
+            self.0.get(coord)
+        }
+
+        fn get_mut(&mut self, coord: &Point3<i32>) -> Option<&mut TestVoxel> {
+            self.0.get_mut(coord)
+        }
+    }
+
+    /// Applies `f` to a storage with each encoder and to the reference, and
+    /// checks that all of them end up with the same voxels.
+    fn check_bulk_operation(f: impl Fn(&mut dyn VoxelStorage<TestVoxel>)) {
+        let bounds = Bounds::new(Point3::new(-3, 0, 2), Point3::new(5, 4, 7));
+        let mut reference = Reference(VecStorage::from_bounds(bounds.clone()));
+        let mut flat = VecStorage::<TestVoxel, FlatEncoder>::from_bounds(bounds.clone());
+        let mut morton = numbered(bounds.clone());
+        for coord in &bounds {
+            let voxel = *morton.get(&coord).unwrap();
+            *reference.get_mut(&coord).unwrap() = voxel;
+            *flat.get_mut(&coord).unwrap() = voxel;
+        }
+
+        f(&mut reference);
+        f(&mut flat);
+        f(&mut morton);
+
+        for coord in &bounds {
+            assert_eq!(flat.get(&coord), reference.get(&coord), "{:?}", coord);
+            assert_eq!(morton.get(&coord), reference.get(&coord), "{:?}", coord);
+        }
+    }
+
+    fn bounds(min: [i32; 3], max: [i32; 3]) -> Bounds {
+        Bounds::new(Point3::from(min), Point3::from(max))
+    }
+
+    #[test]
+    fn fill() {
+        check_bulk_operation(|storage| storage.fill(&bounds([-1, 1, 3], [2, 3, 6]), TestVoxel(0)));
+        check_bulk_operation(|storage| storage.fill(&bounds([-5, 2, 0], [0, 9, 3]), TestVoxel(9)));
+        check_bulk_operation(|storage| storage.fill(&bounds([0, 0, 2], [0, 4, 7]), TestVoxel(9)));
+        check_bulk_operation(|storage| {
+            storage.fill(&bounds([9, 9, 9], [10, 10, 10]), TestVoxel(9))
+        });
+    }
+
+    #[test]
+    fn copy_region() {
+        check_bulk_operation(|storage| {
+            storage.copy_region(&bounds([-3, 0, 2], [0, 2, 4]), &Point3::new(1, 2, 4))
+        });
+        check_bulk_operation(|storage| {
+            storage.copy_region(&bounds([-2, 0, 2], [3, 3, 5]), &Point3::new(-1, 1, 3))
+        });
+        check_bulk_operation(|storage| {
+            storage.copy_region(&bounds([0, 1, 3], [4, 4, 7]), &Point3::new(-4, 0, 1))
+        });
+        check_bulk_operation(|storage| {
+            storage.copy_region(&bounds([0, 1, 3], [0, 4, 7]), &Point3::new(1, 1, 3))
+        });
+    }
+
+    #[test]
+    fn swap_regions() {
+        check_bulk_operation(|storage| {
+            storage.swap_regions(&bounds([-3, 0, 2], [0, 2, 4]), &Point3::new(1, 2, 4))
+        });
+        check_bulk_operation(|storage| {
+            storage.swap_regions(&bounds([-4, 0, 1], [0, 2, 3]), &Point3::new(3, 3, 5))
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "Swapped regions overlap")]
+    fn swap_overlapping_regions() {
+        let mut storage = numbered(bounds([0, 0, 0], [4, 4, 4]));
+        storage.swap_regions(&bounds([0, 0, 0], [2, 2, 2]), &Point3::new(1, 1, 1));
+    }
+
+    #[test]
+    fn replace_if() {
+        let region = bounds([-1, 1, 3], [7, 3, 6]);
+        let before = numbered(bounds([-3, 0, 2], [5, 4, 7]));
+        let mut storage = before.clone();
+
+        storage.replace_if(&region, |voxel| voxel.0 % 2 == 0, TestVoxel(0));
+        for coord in &before.bounds().unwrap() {
+            let voxel = *before.get(&coord).unwrap();
+            let expected = if region.contains(&coord) && voxel.0 % 2 == 0 {
+                TestVoxel(0)
+            }
+            else {
+                voxel
+            };
+            assert_eq!(storage.get(&coord), Some(&expected));
+        }
+
+        storage.replace_if(&bounds([0, 1, 3], [0, 3, 6]), |_| true, TestVoxel(1));
+        assert_eq!(storage.get(&Point3::new(0, 1, 3)), Some(&TestVoxel(0)));
+    }
+
+    /// Collects the voxels of an iterator, ordered by z, y and x.
+    fn sorted<'a>(
+        voxels: impl Iterator<Item = (Point3<i32>, &'a TestVoxel)>,
+    ) -> Vec<(Point3<i32>, TestVoxel)> {
+        let mut voxels: Vec<_> = voxels.map(|(coord, voxel)| (coord, *voxel)).collect();
+        voxels.sort_unstable_by_key(|(coord, _)| (coord.z, coord.y, coord.x));
+        voxels
+    }
+
+    #[test]
+    fn iterators() {
+        let morton = numbered(bounds([-3, 0, 2], [5, 4, 7]));
+        let mut flat = VecStorage::<TestVoxel, FlatEncoder>::from_bounds(morton.bounds().unwrap());
+        for (coord, voxel) in flat.iter_mut() {
+            *voxel = *morton.get(&coord).unwrap();
+        }
+        let all = sorted(morton.iter());
+        assert_eq!(all.len(), 160);
+        assert_eq!(sorted(flat.iter()), all);
+
+        for region in &[
+            bounds([-1, 1, 3], [7, 3, 6]),
+            bounds([-3, 0, 2], [5, 4, 7]),
+            bounds([-5, 3, 0], [-2, 9, 3]),
+            bounds([0, 0, 2], [0, 4, 7]),
+            bounds([9, 9, 9], [10, 10, 10]),
+        ] {
+            let expected: Vec<_> = all
+                .iter()
+                .filter(|(coord, _)| region.contains(coord))
+                .copied()
+                .collect();
+
+            assert_eq!(sorted(morton.iter_region(region)), expected);
+            assert_eq!(sorted(flat.iter_region(region)), expected);
+
+            let mut morton = morton.clone();
+            let coords: Vec<_> = morton.iter_region(region).map(|(coord, _)| coord).collect();
+            let coords_mut: Vec<_> = morton
+                .iter_region_mut(region)
+                .map(|(coord, _)| coord)
+                .collect();
+            assert_eq!(coords_mut, coords);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn serde_round_trip() {
+        use crate::testing::{
+            assert_same_voxels,
+            serde_round_trip,
+        };
+
+        let morton = numbered(bounds([-3, 0, 2], [5, 4, 7]));
+        assert_same_voxels(&serde_round_trip(&morton), &morton);
+
+        let mut flat =
+            VecStorage::<TestVoxel, FlatEncoder>::from_bounds(bounds([0, -1, 0], [3, 1, 2]));
+        *flat.get_mut(&Point3::new(2, 0, 1)).unwrap() = TestVoxel(5);
+        assert_same_voxels(&serde_round_trip(&flat), &flat);
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn serde_rejects_other_encoder() {
+        let flat = VecStorage::<TestVoxel, FlatEncoder>::from_bounds(bounds([0, 0, 0], [2, 2, 2]));
+        let json = serde_json::to_string(&flat).unwrap();
+        assert!(serde_json::from_str::<VecStorage<TestVoxel, MortonEncoder>>(&json).is_err());
+
+        let json = r#"{"origin":[0,0,0],"dimensions":[2,2,2],"encoder":"flat","voxels":[0,0]}"#;
+        assert!(serde_json::from_str::<VecStorage<TestVoxel, FlatEncoder>>(json).is_err());
+    }
+}
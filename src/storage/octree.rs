@@ -0,0 +1,416 @@
+use amethyst_core::math::{
+    Point3,
+    Vector3,
+};
+
+use crate::{
+    bounds::Bounds,
+    storage::{
+        FromBounds,
+        VoxelStorage,
+    },
+    Voxel,
+};
+
+/// Node of an [`OctreeStorage`].
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum OctreeNode<V> {
+    /// All voxels in the region of this node are the same.
+    Leaf(V),
+    /// The region of this node is split into 8 octants. The index of an octant
+    /// has bit 0 set for the upper half along the x-axis, bit 1 for the
+    /// y-axis and bit 2 for the z-axis.
+    Branch(Box<[OctreeNode<V>; 8]>),
+}
+
+impl<V> OctreeNode<V> {
+    pub fn is_leaf(&self) -> bool {
+        matches!(self, Self::Leaf(_))
+    }
+
+    /// Returns the voxel that fills this node, if it's a leaf.
+    pub fn voxel(&self) -> Option<&V> {
+        match self {
+            Self::Leaf(voxel) => Some(voxel),
+            Self::Branch(_) => None,
+        }
+    }
+
+    pub fn children(&self) -> Option<&[OctreeNode<V>; 8]> {
+        match self {
+            Self::Leaf(_) => None,
+            Self::Branch(children) => Some(children),
+        }
+    }
+}
+
+/// Sparse voxel octree covering a cube with an edge length that is a power of
+/// two. Regions of identical voxels are stored as a single leaf.
+///
+/// `get_mut` splits leaves down to the single voxel it returns. The path to
+/// that voxel is collapsed again on the next call to `get_mut` or
+/// [`OctreeStorage::flush`], once the new value of the voxel is known.
+#[derive(Clone, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(
+        try_from = "RawOctreeStorage<V>",
+        bound(deserialize = "V: serde::Deserialize<'de>")
+    )
+)]
+pub struct OctreeStorage<V> {
+    origin: Point3<i32>,
+    depth: u32,
+    root: OctreeNode<V>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    last_edit: Option<Point3<i32>>,
+}
+
+/// Unvalidated octree, as it's deserialized.
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct RawOctreeStorage<V> {
+    origin: Point3<i32>,
+    depth: u32,
+    root: OctreeNode<V>,
+}
+
+#[cfg(feature = "serde")]
+impl<V> std::convert::TryFrom<RawOctreeStorage<V>> for OctreeStorage<V> {
+    type Error = String;
+
+    fn try_from(raw: RawOctreeStorage<V>) -> Result<Self, Self::Error> {
+        fn node_depth<V>(node: &OctreeNode<V>) -> u32 {
+            match node {
+                OctreeNode::Leaf(_) => 0,
+                OctreeNode::Branch(children) => 1 + children.iter().map(node_depth).max().unwrap(),
+            }
+        }
+
+        if raw.depth >= 31 {
+            return Err(format!("Octree depth too large: {}", raw.depth));
+        }
+        if node_depth(&raw.root) > raw.depth {
+            return Err("Octree nodes exceed the depth of the octree".to_owned());
+        }
+
+        Ok(Self {
+            origin: raw.origin,
+            depth: raw.depth,
+            root: raw.root,
+            last_edit: None,
+        })
+    }
+}
+
+impl<V: Voxel + PartialEq> OctreeStorage<V> {
+    /// Creates an octree with its minimum corner at `origin` and an edge
+    /// length of `2^depth` voxels.
+    pub fn new(origin: Point3<i32>, depth: u32) -> Self {
+        assert!(depth < 31, "Octree depth too large");
+
+        Self {
+            origin,
+            depth,
+            root: OctreeNode::Leaf(V::default()),
+            last_edit: None,
+        }
+    }
+
+    /// Edge length of the cube covered by the octree.
+    pub fn size(&self) -> i32 {
+        1 << self.depth
+    }
+
+    pub fn depth(&self) -> u32 {
+        self.depth
+    }
+
+    pub fn root(&self) -> &OctreeNode<V> {
+        &self.root
+    }
+
+    /// Merges uniform subtrees along the path of the voxel handed out by the
+    /// last call to `get_mut`.
+    pub fn flush(&mut self) {
+        if let Some(coord) = self.last_edit.take() {
+            let size = self.size();
+            collapse_path(&mut self.root, self.origin, size, &coord);
+        }
+    }
+
+    /// Visits the nodes of the octree in depth-first order, together with the
+    /// region they cover. Children of a node are only visited if `f` returns
+    /// `true` for it, which allows skipping whole regions.
+    pub fn traverse<F>(&self, mut f: F)
+    where
+        F: FnMut(&Bounds, &OctreeNode<V>) -> bool,
+    {
+        traverse(&self.root, self.origin, self.size(), &mut f);
+    }
+}
+
+impl<V: Voxel + PartialEq> FromBounds<V> for OctreeStorage<V> {
+    /// Creates the smallest octree with its minimum corner at the minimum of
+    /// `bounds` that covers all of `bounds`.
+    fn from_bounds(bounds: Bounds) -> Self {
+        Self::new(bounds.min(), depth_for_bounds(&bounds))
+    }
+
+    /// The octree must have a depth below 31 and its root node must not
+    /// extend past `i32::MAX`.
+    fn supports_bounds(bounds: &Bounds) -> bool {
+        let depth = depth_for_bounds(bounds);
+        depth < 31
+            && bounds
+                .min()
+                .iter()
+                .all(|&x| i64::from(x) + (1i64 << depth) <= i64::from(i32::MAX))
+    }
+}
+
+/// Returns the depth of the smallest octree covering `bounds`.
+fn depth_for_bounds(bounds: &Bounds) -> u32 {
+    let extent = (0..3)
+        .map(|i| i64::from(bounds.max()[i]) - i64::from(bounds.min()[i]))
+        .max()
+        .unwrap();
+    64 - (extent.max(1) as u64 - 1).leading_zeros()
+}
+
+impl<V: Voxel + PartialEq> VoxelStorage<V> for OctreeStorage<V> {
+    fn origin(&self) -> Point3<i32> {
+        self.origin
+    }
+
+    fn dimensions(&self) -> Vector3<u32> {
+        Vector3::repeat(1 << self.depth)
+    }
+
+    /// Returns the region covered by the root node.
+    fn bounds(&self) -> Option<Bounds> {
+        Some(Bounds::new(
+            self.origin,
+            self.origin + Vector3::repeat(self.size()),
+        ))
+    }
+
+    fn get(&self, coord: &Point3<i32>) -> Option<&V> {
+        if !self.bounds().unwrap().contains(coord) {
+            return None;
+        }
+
+        let mut node = &self.root;
+        let mut min = self.origin;
+        let mut size = self.size();
+
+        loop {
+            match node {
+                OctreeNode::Leaf(voxel) => return Some(voxel),
+                OctreeNode::Branch(children) => {
+                    size /= 2;
+                    let (octant, octant_min) = octant(&min, size, coord);
+                    node = &children[octant];
+                    min = octant_min;
+                }
+            }
+        }
+    }
+
+    fn get_mut(&mut self, coord: &Point3<i32>) -> Option<&mut V> {
+        if !self.bounds().unwrap().contains(coord) {
+            return None;
+        }
+
+        if self.last_edit.as_ref() != Some(coord) {
+            self.flush();
+            self.last_edit = Some(*coord);
+        }
+
+        let size = self.size();
+        Some(split_path(&mut self.root, self.origin, size, coord))
+    }
+}
+
+/// Returns the index and minimum corner of the octant with edge length
+/// `half_size` that contains `coord`.
+fn octant(min: &Point3<i32>, half_size: i32, coord: &Point3<i32>) -> (usize, Point3<i32>) {
+    let mut index = 0;
+    let mut octant_min = *min;
+
+    for axis in 0..3 {
+        if coord[axis] >= min[axis] + half_size {
+            index |= 1 << axis;
+            octant_min[axis] += half_size;
+        }
+    }
+
+    (index, octant_min)
+}
+
+fn split_path<'a, V: Voxel>(
+    node: &'a mut OctreeNode<V>,
+    min: Point3<i32>,
+    size: i32,
+    coord: &Point3<i32>,
+) -> &'a mut V {
+    if size == 1 {
+        return match node {
+            OctreeNode::Leaf(voxel) => voxel,
+            OctreeNode::Branch(_) => unreachable!(),
+        };
+    }
+
+    if let OctreeNode::Leaf(voxel) = node {
+        let voxel = voxel.clone();
+        *node = OctreeNode::Branch(Box::new([(); 8].map(|_| OctreeNode::Leaf(voxel.clone()))));
+    }
+
+    match node {
+        OctreeNode::Branch(children) => {
+            let half_size = size / 2;
+            let (octant, octant_min) = octant(&min, half_size, coord);
+            split_path(&mut children[octant], octant_min, half_size, coord)
+        }
+        OctreeNode::Leaf(_) => unreachable!(),
+    }
+}
+
+fn collapse_path<V: Voxel + PartialEq>(
+    node: &mut OctreeNode<V>,
+    min: Point3<i32>,
+    size: i32,
+    coord: &Point3<i32>,
+) {
+    if let OctreeNode::Branch(children) = node {
+        let half_size = size / 2;
+        let (octant, octant_min) = octant(&min, half_size, coord);
+        collapse_path(&mut children[octant], octant_min, half_size, coord);
+
+        let uniform = match children[0].voxel() {
+            Some(first) => {
+                children[1..]
+                    .iter()
+                    .all(|child| child.voxel() == Some(first))
+            }
+            None => false,
+        };
+
+        if uniform {
+            let voxel = children[0].voxel().unwrap().clone();
+            *node = OctreeNode::Leaf(voxel);
+        }
+    }
+}
+
+fn traverse<V, F>(node: &OctreeNode<V>, min: Point3<i32>, size: i32, f: &mut F)
+where
+    F: FnMut(&Bounds, &OctreeNode<V>) -> bool,
+{
+    let bounds = Bounds::new(min, min + Vector3::repeat(size));
+
+    if f(&bounds, node) {
+        if let OctreeNode::Branch(children) = node {
+            let half_size = size / 2;
+            for (index, child) in children.iter().enumerate() {
+                let child_min = min
+                    + Vector3::new(index & 1, (index >> 1) & 1, (index >> 2) & 1)
+                        .map(|x| x as i32 * half_size);
+                traverse(child, child_min, half_size, f);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestVoxel;
+
+    fn set(octree: &mut OctreeStorage<TestVoxel>, coord: Point3<i32>, voxel: TestVoxel) {
+        *octree.get_mut(&coord).unwrap() = voxel;
+        octree.flush();
+    }
+
+    #[test]
+    fn splits_and_collapses() {
+        let mut octree = OctreeStorage::new(Point3::new(-2, 0, 5), 2);
+        let coord = Point3::new(-1, 2, 8);
+
+        set(&mut octree, coord, TestVoxel(1));
+        assert!(!octree.root().is_leaf());
+        assert_eq!(octree.get(&coord), Some(&TestVoxel(1)));
+        assert_eq!(octree.get(&Point3::new(-2, 2, 8)), Some(&TestVoxel(0)));
+
+        set(&mut octree, coord, TestVoxel(0));
+        assert_eq!(octree.root().voxel(), Some(&TestVoxel(0)));
+    }
+
+    #[test]
+    fn collapses_uniform_octants() {
+        let mut octree = OctreeStorage::new(Point3::origin(), 2);
+
+        for coord in &Bounds::new(Point3::origin(), Point3::new(2, 2, 2)) {
+            set(&mut octree, coord, TestVoxel(3));
+        }
+        let children = octree.root().children().unwrap();
+        assert_eq!(children[0].voxel(), Some(&TestVoxel(3)));
+        assert!(children[1..]
+            .iter()
+            .all(|child| child.voxel() == Some(&TestVoxel(0))));
+
+        for coord in &octree.bounds().unwrap() {
+            set(&mut octree, coord, TestVoxel(3));
+        }
+        assert_eq!(octree.root().voxel(), Some(&TestVoxel(3)));
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn serde_round_trip() {
+        use crate::testing::{
+            assert_same_voxels,
+            serde_round_trip,
+        };
+
+        let mut octree = OctreeStorage::new(Point3::new(-2, 0, 5), 2);
+        set(&mut octree, Point3::new(-1, 2, 8), TestVoxel(1));
+        set(&mut octree, Point3::new(0, 0, 5), TestVoxel(2));
+        assert_same_voxels(&serde_round_trip(&octree), &octree);
+
+        let json = r#"{"origin":[0,0,0],"depth":0,"root":{"Branch":[
+            {"Leaf":0},{"Leaf":0},{"Leaf":0},{"Leaf":0},
+            {"Leaf":0},{"Leaf":0},{"Leaf":0},{"Leaf":1}
+        ]}}"#;
+        assert!(serde_json::from_str::<OctreeStorage<TestVoxel>>(json).is_err());
+    }
+
+    #[test]
+    fn from_bounds() {
+        let octree = OctreeStorage::<TestVoxel>::from_bounds(Bounds::new(
+            Point3::new(-4, 0, 0),
+            Point3::new(1, 2, 1),
+        ));
+        assert_eq!(octree.origin(), Point3::new(-4, 0, 0));
+        assert_eq!(octree.size(), 8);
+
+        let supported = |min: Point3<i32>, max: Point3<i32>| {
+            OctreeStorage::<TestVoxel>::supports_bounds(&Bounds::new(min, max))
+        };
+        assert!(supported(Point3::origin(), Point3::new(1 << 30, 1, 1)));
+        assert!(!supported(
+            Point3::origin(),
+            Point3::new((1 << 30) + 1, 1, 1)
+        ));
+        assert!(!supported(
+            Point3::new(i32::MIN, 0, 0),
+            Point3::new(i32::MAX, 1, 1)
+        ));
+        assert!(!supported(
+            Point3::new(i32::MAX - 5, 0, 0),
+            Point3::new(i32::MAX, 1, 1)
+        ));
+    }
+}
@@ -0,0 +1,587 @@
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    hash::Hash,
+};
+
+use amethyst_core::math::{
+    Point3,
+    Vector3,
+};
+#[cfg(feature = "serde")]
+use serde::{
+    de::Error as _,
+    Deserialize,
+    Deserializer,
+    Serialize,
+    Serializer,
+};
+
+use crate::{
+    bounds::Bounds,
+    storage::{
+        FromBounds,
+        VoxelStorage,
+    },
+    Voxel,
+};
+
+/// Edge length of a chunk in [`PaletteStorage`].
+pub const CHUNK_SIZE: i32 = 16;
+
+const CHUNK_VOLUME: usize = (CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE) as usize;
+
+/// Unbounded storage that compresses voxels per chunk. Each chunk keeps a
+/// palette of the distinct voxels in it and an array of indices into the
+/// palette, which are packed with 1, 2, 4, 8 or 16 bits per voxel depending on
+/// the size of the palette. Chunks that only contain a single kind of voxel
+/// are stored as that one palette entry.
+///
+/// A voxel can't be referenced mutably while it's packed. `get_mut` therefore
+/// hands out a copy of the voxel, which is written back to its chunk on the
+/// next call to `get_mut` or [`PaletteStorage::flush`].
+#[derive(Clone, Debug)]
+pub struct PaletteStorage<V> {
+    chunks: HashMap<Point3<i32>, PaletteChunk<V>>,
+    pending: Option<(Point3<i32>, V)>,
+    default: V,
+}
+
+impl<V: Voxel + Eq + Hash> PaletteStorage<V> {
+    pub fn new() -> Self {
+        Self {
+            chunks: HashMap::new(),
+            pending: None,
+            default: V::default(),
+        }
+    }
+
+    /// Writes the voxel handed out by the last call to `get_mut` back into its
+    /// chunk.
+    pub fn flush(&mut self) {
+        if let Some((coord, voxel)) = self.pending.take() {
+            let (chunk, index) = split_coordinates(&coord);
+            self.chunks
+                .get_mut(&chunk)
+                .expect("Chunk of pending voxel missing")
+                .set(index, voxel);
+        }
+    }
+
+    /// Returns the number of bits used per voxel in a chunk, or `None` if the
+    /// chunk isn't allocated.
+    pub fn bits_per_voxel(&self, chunk: &Point3<i32>) -> Option<u8> {
+        self.chunks.get(chunk).map(|chunk| chunk.indices.bits)
+    }
+
+    /// Returns the region of voxel coordinates covered by a chunk.
+    pub fn chunk_bounds(chunk: &Point3<i32>) -> Bounds {
+        let min = chunk * CHUNK_SIZE;
+        Bounds::new(min, min + Vector3::repeat(CHUNK_SIZE))
+    }
+}
+
+impl<V: Voxel + Eq + Hash> Default for PaletteStorage<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<V: Voxel + Eq + Hash> FromBounds<V> for PaletteStorage<V> {
+    /// Creates a storage with all chunks intersecting `bounds` allocated.
+    fn from_bounds(bounds: Bounds) -> Self {
+        let mut storage = Self::new();
+
+        if !bounds.is_empty() {
+            let (min, _) = split_coordinates(&bounds.min());
+            let (max, _) = split_coordinates(&(bounds.max() - Vector3::repeat(1)));
+
+            for chunk in &Bounds::new(min, max + Vector3::repeat(1)) {
+                storage
+                    .chunks
+                    .insert(chunk, PaletteChunk::new(V::default()));
+            }
+        }
+
+        storage
+    }
+}
+
+impl<V: Voxel + Eq + Hash> VoxelStorage<V> for PaletteStorage<V> {
+    fn origin(&self) -> Point3<i32> {
+        self.bounds().unwrap().min()
+    }
+
+    fn dimensions(&self) -> Vector3<u32> {
+        let bounds = self.bounds().unwrap();
+        (bounds.max() - bounds.min()).map(|x| x.try_into().unwrap())
+    }
+
+    /// Returns the union of all allocated chunks.
+    fn bounds(&self) -> Option<Bounds> {
+        Some(self.chunks.keys().fold(Bounds::empty(), |bounds, chunk| {
+            bounds.union(&Self::chunk_bounds(chunk))
+        }))
+    }
+
+    fn get(&self, coord: &Point3<i32>) -> Option<&V> {
+        match &self.pending {
+            Some((pending_coord, voxel)) if pending_coord == coord => Some(voxel),
+            _ => {
+                let (chunk, index) = split_coordinates(coord);
+                match self.chunks.get(&chunk) {
+                    Some(chunk) => Some(chunk.get(index)),
+                    None => Some(&self.default),
+                }
+            }
+        }
+    }
+
+    fn get_mut(&mut self, coord: &Point3<i32>) -> Option<&mut V> {
+        if self
+            .pending
+            .as_ref()
+            .map_or(true, |(pending_coord, _)| pending_coord != coord)
+        {
+            self.flush();
+
+            let (chunk, index) = split_coordinates(coord);
+            let default = &self.default;
+            let voxel = self
+                .chunks
+                .entry(chunk)
+                .or_insert_with(|| PaletteChunk::new(default.clone()))
+                .get(index)
+                .clone();
+            self.pending = Some((*coord, voxel));
+        }
+
+        self.pending.as_mut().map(|(_, voxel)| voxel)
+    }
+}
+
+/// Splits voxel coordinates into the coordinates of the chunk and the index of
+/// the voxel within that chunk.
+fn split_coordinates(coord: &Point3<i32>) -> (Point3<i32>, usize) {
+    let chunk = coord.map(|x| x.div_euclid(CHUNK_SIZE));
+    let local = coord.map(|x| x.rem_euclid(CHUNK_SIZE) as usize);
+    let size = CHUNK_SIZE as usize;
+
+    (chunk, local.x + local.y * size + local.z * size * size)
+}
+
+#[derive(Clone, Debug)]
+struct PaletteChunk<V> {
+    /// Distinct voxels in this chunk. Entries with a count of 0 are unused and
+    /// can be reused for new voxels.
+    palette: Vec<V>,
+    /// Number of voxels referencing each palette entry.
+    counts: Vec<u32>,
+    /// Maps voxels to their index in `palette`.
+    lookup: HashMap<V, u16>,
+    indices: PackedIndices,
+}
+
+impl<V: Voxel + Eq + Hash> PaletteChunk<V> {
+    fn new(voxel: V) -> Self {
+        let mut lookup = HashMap::new();
+        lookup.insert(voxel.clone(), 0);
+
+        Self {
+            palette: vec![voxel],
+            counts: vec![CHUNK_VOLUME as u32],
+            lookup,
+            indices: PackedIndices::new(0),
+        }
+    }
+
+    fn get(&self, index: usize) -> &V {
+        &self.palette[self.indices.get(index)]
+    }
+
+    fn set(&mut self, index: usize, voxel: V) {
+        let old = self.indices.get(index);
+        if self.palette[old] == voxel {
+            return;
+        }
+
+        let new = match self.lookup.get(&voxel) {
+            Some(&new) => new as usize,
+            None => self.insert(voxel),
+        };
+
+        // Inserting might have repacked the indices, but it never moves
+        // existing palette entries.
+        self.indices.set(index, new);
+        self.counts[new] += 1;
+        self.counts[old] -= 1;
+
+        if self.counts[old] == 0 {
+            self.lookup.remove(&self.palette[old]);
+
+            let live = self.counts.iter().filter(|&&count| count > 0).count();
+            if required_bits(live) < self.indices.bits {
+                self.compact();
+            }
+        }
+    }
+
+    /// Adds a new voxel to the palette, reusing an unused entry if possible,
+    /// and returns its index.
+    fn insert(&mut self, voxel: V) -> usize {
+        let index = match self.counts.iter().position(|&count| count == 0) {
+            Some(index) => {
+                self.palette[index] = voxel.clone();
+                index
+            }
+            None => {
+                self.palette.push(voxel.clone());
+                self.counts.push(0);
+
+                let bits = required_bits(self.palette.len());
+                if bits > self.indices.bits {
+                    self.indices = self.indices.repack(bits, |index| index);
+                }
+
+                self.palette.len() - 1
+            }
+        };
+
+        self.lookup.insert(voxel, index as u16);
+        index
+    }
+
+    /// Removes unused palette entries and shrinks the indices accordingly.
+    fn compact(&mut self) {
+        let mut remap = vec![0; self.palette.len()];
+        let mut palette = Vec::new();
+        let mut counts = Vec::new();
+
+        for (index, voxel) in self.palette.drain(..).enumerate() {
+            let count = self.counts[index];
+            if count > 0 {
+                remap[index] = palette.len();
+                palette.push(voxel);
+                counts.push(count);
+            }
+        }
+
+        self.lookup = palette
+            .iter()
+            .enumerate()
+            .map(|(index, voxel)| (voxel.clone(), index as u16))
+            .collect();
+        self.indices = self
+            .indices
+            .repack(required_bits(palette.len()), |index| remap[index]);
+        self.palette = palette;
+        self.counts = counts;
+    }
+}
+
+/// Number of bits needed to index a palette with `n` entries.
+fn required_bits(n: usize) -> u8 {
+    match n {
+        0..=1 => 0,
+        2 => 1,
+        3..=4 => 2,
+        5..=16 => 4,
+        17..=256 => 8,
+        _ => 16,
+    }
+}
+
+/// Palette indices of a chunk, packed into words. Since the number of bits
+/// divides 64, an index never spans two words. With 0 bits all indices are 0
+/// and nothing is stored.
+#[derive(Clone, Debug)]
+struct PackedIndices {
+    bits: u8,
+    words: Vec<u64>,
+}
+
+impl PackedIndices {
+    fn new(bits: u8) -> Self {
+        let words = if bits == 0 {
+            Vec::new()
+        }
+        else {
+            vec![0; CHUNK_VOLUME * bits as usize / 64]
+        };
+
+        Self { bits, words }
+    }
+
+    fn get(&self, index: usize) -> usize {
+        if self.bits == 0 {
+            return 0;
+        }
+
+        let bits = self.bits as usize;
+        let word = self.words[index * bits / 64];
+        let shift = index * bits % 64;
+        let mask = (1 << bits) - 1;
+
+        ((word >> shift) & mask) as usize
+    }
+
+    fn set(&mut self, index: usize, value: usize) {
+        let bits = self.bits as usize;
+        debug_assert!(value < 1 << bits);
+
+        if bits == 0 {
+            return;
+        }
+
+        let word = &mut self.words[index * bits / 64];
+        let shift = index * bits % 64;
+        let mask = (1u64 << bits) - 1;
+
+        *word = (*word & !(mask << shift)) | ((value as u64) << shift);
+    }
+
+    /// Creates a copy with a different number of bits per index, mapping every
+    /// index through `f`.
+    fn repack(&self, bits: u8, mut f: impl FnMut(usize) -> usize) -> Self {
+        let mut repacked = Self::new(bits);
+        for index in 0..CHUNK_VOLUME {
+            repacked.set(index, f(self.get(index)));
+        }
+        repacked
+    }
+}
+
+/// Serializes the palette and packed indices of every allocated chunk. A voxel
+/// handed out by `get_mut` that hasn't been written back yet is serialized
+/// separately and flushed on deserialization.
+#[cfg(feature = "serde")]
+impl<V: Voxel + Eq + Hash + Serialize> Serialize for PaletteStorage<V> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct PaletteStorageData<'a, V: Serialize> {
+            chunks: Vec<(&'a Point3<i32>, PaletteChunkData<'a, V>)>,
+            pending: &'a Option<(Point3<i32>, V)>,
+        }
+
+        #[derive(Serialize)]
+        struct PaletteChunkData<'a, V: Serialize> {
+            palette: &'a [V],
+            bits: u8,
+            words: &'a [u64],
+        }
+
+        PaletteStorageData {
+            chunks: self
+                .chunks
+                .iter()
+                .map(|(key, chunk)| {
+                    (
+                        key,
+                        PaletteChunkData {
+                            palette: &chunk.palette,
+                            bits: chunk.indices.bits,
+                            words: &chunk.indices.words,
+                        },
+                    )
+                })
+                .collect(),
+            pending: &self.pending,
+        }
+        .serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, V: Voxel + Eq + Hash + Deserialize<'de>> Deserialize<'de> for PaletteStorage<V> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct PaletteStorageData<V> {
+            chunks: Vec<(Point3<i32>, PaletteChunkData<V>)>,
+            pending: Option<(Point3<i32>, V)>,
+        }
+
+        #[derive(Deserialize)]
+        struct PaletteChunkData<V> {
+            palette: Vec<V>,
+            bits: u8,
+            words: Vec<u64>,
+        }
+
+        let data = PaletteStorageData::<V>::deserialize(deserializer)?;
+        let mut storage = Self::new();
+
+        for (key, chunk) in data.chunks {
+            let PaletteChunkData {
+                palette,
+                bits,
+                words,
+            } = chunk;
+
+            if !matches!(bits, 0 | 1 | 2 | 4 | 8 | 16) {
+                return Err(D::Error::custom(format!(
+                    "Invalid number of bits per voxel: {}",
+                    bits
+                )));
+            }
+            if palette.is_empty() || palette.len() > 1 << bits {
+                return Err(D::Error::custom(format!(
+                    "Palette with {} entries can't be indexed with {} bits",
+                    palette.len(),
+                    bits
+                )));
+            }
+            if words.len() != CHUNK_VOLUME * bits as usize / 64 {
+                return Err(D::Error::invalid_length(
+                    words.len(),
+                    &"number of words matching the bits per voxel",
+                ));
+            }
+
+            let indices = PackedIndices { bits, words };
+            let mut counts = vec![0; palette.len()];
+            for index in 0..CHUNK_VOLUME {
+                match counts.get_mut(indices.get(index)) {
+                    Some(count) => *count += 1,
+                    None => return Err(D::Error::custom("Palette index out of range")),
+                }
+            }
+
+            let mut lookup = HashMap::new();
+            for (index, voxel) in palette.iter().enumerate() {
+                if counts[index] > 0 && lookup.insert(voxel.clone(), index as u16).is_some() {
+                    return Err(D::Error::custom("Duplicate palette entry"));
+                }
+            }
+
+            storage.chunks.insert(
+                key,
+                PaletteChunk {
+                    palette,
+                    counts,
+                    lookup,
+                    indices,
+                },
+            );
+        }
+
+        if let Some((coord, voxel)) = data.pending {
+            *storage.get_mut(&coord).unwrap() = voxel;
+            storage.flush();
+        }
+
+        Ok(storage)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestVoxel;
+
+    fn set(storage: &mut PaletteStorage<TestVoxel>, coord: Point3<i32>, voxel: TestVoxel) {
+        *storage.get_mut(&coord).unwrap() = voxel;
+        storage.flush();
+    }
+
+    #[test]
+    fn packs_indices_by_palette_size() {
+        let mut storage = PaletteStorage::new();
+        let chunk = Point3::origin();
+        let coords: Vec<_> = PaletteStorage::<TestVoxel>::chunk_bounds(&chunk)
+            .iter()
+            .take(300)
+            .collect();
+
+        set(&mut storage, coords[0], TestVoxel(0));
+        assert_eq!(storage.bits_per_voxel(&chunk), Some(0));
+
+        for (i, coord) in coords.iter().enumerate() {
+            set(&mut storage, *coord, TestVoxel(i as u16 + 1));
+
+            let expected = match i + 2 {
+                2 => 1,
+                3..=4 => 2,
+                5..=16 => 4,
+                17..=256 => 8,
+                _ => 16,
+            };
+            assert_eq!(storage.bits_per_voxel(&chunk), Some(expected));
+        }
+
+        for (i, coord) in coords.iter().enumerate() {
+            assert_eq!(storage.get(coord), Some(&TestVoxel(i as u16 + 1)));
+        }
+    }
+
+    #[test]
+    fn compacts_unused_entries() {
+        let mut storage = PaletteStorage::new();
+        let chunk = Point3::new(-1, 0, 2);
+        let coords: Vec<_> = PaletteStorage::<TestVoxel>::chunk_bounds(&chunk)
+            .iter()
+            .take(20)
+            .collect();
+
+        for (i, coord) in coords.iter().enumerate() {
+            set(&mut storage, *coord, TestVoxel(i as u16 + 1));
+        }
+        assert_eq!(storage.bits_per_voxel(&chunk), Some(8));
+
+        for coord in &coords[..16] {
+            set(&mut storage, *coord, TestVoxel(0));
+        }
+        assert_eq!(storage.bits_per_voxel(&chunk), Some(4));
+        for (i, coord) in coords.iter().enumerate().skip(16) {
+            assert_eq!(storage.get(coord), Some(&TestVoxel(i as u16 + 1)));
+        }
+
+        for coord in &coords[16..] {
+            set(&mut storage, *coord, TestVoxel(0));
+        }
+        assert_eq!(storage.bits_per_voxel(&chunk), Some(0));
+        assert!(coords
+            .iter()
+            .all(|coord| storage.get(coord) == Some(&TestVoxel(0))));
+    }
+
+    #[test]
+    fn get_returns_pending_voxel() {
+        let mut storage = PaletteStorage::new();
+        let coord = Point3::new(3, -4, 5);
+
+        *storage.get_mut(&coord).unwrap() = TestVoxel(7);
+        assert_eq!(storage.get(&coord), Some(&TestVoxel(7)));
+        assert_eq!(storage.bits_per_voxel(&Point3::new(0, -1, 0)), Some(0));
+
+        storage.flush();
+        assert_eq!(storage.get(&coord), Some(&TestVoxel(7)));
+        assert_eq!(storage.bits_per_voxel(&Point3::new(0, -1, 0)), Some(1));
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn serde_round_trip() {
+        use crate::testing::{
+            assert_same_voxels,
+            serde_round_trip,
+        };
+
+        let mut storage = PaletteStorage::new();
+        for (i, coord) in Bounds::new(Point3::new(-3, 0, 2), Point3::new(5, 4, 7))
+            .iter()
+            .enumerate()
+        {
+            set(&mut storage, coord, TestVoxel(i as u16 % 7));
+        }
+        *storage.get_mut(&Point3::new(40, 1, 1)).unwrap() = TestVoxel(9);
+
+        let mut deserialized = serde_round_trip(&storage);
+        assert_eq!(
+            deserialized.get(&Point3::new(40, 1, 1)),
+            Some(&TestVoxel(9))
+        );
+        storage.flush();
+        deserialized.flush();
+        assert_same_voxels(&deserialized, &storage);
+    }
+}
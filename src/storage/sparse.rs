@@ -0,0 +1,306 @@
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+};
+
+use amethyst_core::math::{
+    Point3,
+    Vector3,
+};
+#[cfg(feature = "serde")]
+use serde::{
+    de::Error as _,
+    Deserialize,
+    Deserializer,
+    Serialize,
+    Serializer,
+};
+
+use crate::{
+    bounds::Bounds,
+    storage::{
+        FromBounds,
+        VoxelStorage,
+        VoxelStorageIterMut,
+    },
+    Voxel,
+};
+
+/// Unbounded storage that only keeps voxels that have been written to in a
+/// hash map. All other voxels read as `V::default()`. This is a good fit for
+/// maps that are mostly empty.
+#[derive(Clone, Debug)]
+pub struct SparseStorage<V> {
+    voxels: HashMap<Point3<i32>, V>,
+    bounds: Bounds,
+    default: V,
+}
+
+impl<V: Voxel> SparseStorage<V> {
+    pub fn new() -> Self {
+        Self {
+            voxels: HashMap::new(),
+            bounds: Bounds::empty(),
+            default: V::default(),
+        }
+    }
+
+    /// Number of voxels that are actually stored.
+    pub fn len(&self) -> usize {
+        self.voxels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.voxels.is_empty()
+    }
+
+    /// Iterates over all stored voxels.
+    pub fn voxels(&self) -> impl Iterator<Item = (&Point3<i32>, &V)> {
+        self.voxels.iter()
+    }
+
+    /// Removes a voxel, such that it reads as `V::default()` again. This
+    /// doesn't shrink the bounds, use [`SparseStorage::prune`] for that.
+    pub fn remove(&mut self, coord: &Point3<i32>) -> Option<V> {
+        self.voxels.remove(coord)
+    }
+
+    fn recompute_bounds(&mut self) {
+        self.bounds = self.voxels.keys().fold(Bounds::empty(), |bounds, coord| {
+            bounds.union(&voxel_bounds(coord))
+        });
+    }
+}
+
+impl<V: Voxel + PartialEq> SparseStorage<V> {
+    /// Removes all stored voxels that are equal to `V::default()` and shrinks
+    /// the bounds to the remaining voxels.
+    pub fn prune(&mut self) {
+        let default = &self.default;
+        self.voxels.retain(|_, voxel| voxel != default);
+        self.recompute_bounds();
+    }
+}
+
+impl<V: Voxel> Default for SparseStorage<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<V: Voxel> FromBounds<V> for SparseStorage<V> {
+    /// Creates an empty storage, which reports `bounds` as its bounds until
+    /// it's pruned.
+    fn from_bounds(bounds: Bounds) -> Self {
+        Self {
+            bounds,
+            ..Self::new()
+        }
+    }
+}
+
+impl<V: Voxel> VoxelStorage<V> for SparseStorage<V> {
+    fn origin(&self) -> Point3<i32> {
+        self.bounds.min()
+    }
+
+    fn dimensions(&self) -> Vector3<u32> {
+        (self.bounds.max() - self.bounds.min()).map(|x| x.try_into().unwrap())
+    }
+
+    /// Returns the bounds of all voxels that have been written to.
+    fn bounds(&self) -> Option<Bounds> {
+        Some(self.bounds.clone())
+    }
+
+    fn get(&self, coord: &Point3<i32>) -> Option<&V> {
+        Some(self.voxels.get(coord).unwrap_or(&self.default))
+    }
+
+    fn get_mut(&mut self, coord: &Point3<i32>) -> Option<&mut V> {
+        if !self.bounds.contains(coord) {
+            self.bounds = self.bounds.union(&voxel_bounds(coord));
+        }
+
+        Some(self.voxels.entry(*coord).or_default())
+    }
+
+    /// Iterates over all stored voxels.
+    fn iter(&self) -> Box<dyn Iterator<Item = (Point3<i32>, &V)> + '_> {
+        Box::new(self.voxels.iter().map(|(coord, voxel)| (*coord, voxel)))
+    }
+
+    /// Iterates over all stored voxels in `bounds`. Voxels that aren't stored
+    /// are skipped.
+    fn iter_region(&self, bounds: &Bounds) -> Box<dyn Iterator<Item = (Point3<i32>, &V)> + '_> {
+        let bounds = bounds.clone();
+        Box::new(
+            self.voxels
+                .iter()
+                .filter(move |(coord, _)| bounds.contains(coord))
+                .map(|(coord, voxel)| (*coord, voxel)),
+        )
+    }
+}
+
+impl<V: Voxel> VoxelStorageIterMut<V> for SparseStorage<V> {
+    /// Iterates over all stored voxels.
+    fn iter_mut(&mut self) -> Box<dyn Iterator<Item = (Point3<i32>, &mut V)> + '_> {
+        Box::new(self.voxels.iter_mut().map(|(coord, voxel)| (*coord, voxel)))
+    }
+
+    /// Iterates over all stored voxels in `bounds`. Voxels that aren't stored
+    /// are skipped.
+    fn iter_region_mut(
+        &mut self,
+        bounds: &Bounds,
+    ) -> Box<dyn Iterator<Item = (Point3<i32>, &mut V)> + '_> {
+        let bounds = bounds.clone();
+        Box::new(
+            self.voxels
+                .iter_mut()
+                .filter(move |(coord, _)| bounds.contains(coord))
+                .map(|(coord, voxel)| (*coord, voxel)),
+        )
+    }
+}
+
+/// Bounds containing only a single voxel.
+fn voxel_bounds(coord: &Point3<i32>) -> Bounds {
+    Bounds::new(*coord, coord + Vector3::repeat(1))
+}
+
+/// Serializes the bounds and the stored voxels as a sequence of coordinates and
+/// voxels.
+#[cfg(feature = "serde")]
+impl<V: Voxel + Serialize> Serialize for SparseStorage<V> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct SparseStorageData<'a, V> {
+            bounds: &'a Bounds,
+            voxels: Vec<(&'a Point3<i32>, &'a V)>,
+        }
+
+        SparseStorageData {
+            bounds: &self.bounds,
+            voxels: self.voxels.iter().collect(),
+        }
+        .serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, V: Voxel + Deserialize<'de>> Deserialize<'de> for SparseStorage<V> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct SparseStorageData<V> {
+            bounds: Bounds,
+            voxels: Vec<(Point3<i32>, V)>,
+        }
+
+        let data = SparseStorageData::<V>::deserialize(deserializer)?;
+
+        if let Some((coord, _)) = data
+            .voxels
+            .iter()
+            .find(|(coord, _)| !data.bounds.contains(coord))
+        {
+            return Err(D::Error::custom(format!(
+                "Voxel at {:?} is outside of the bounds {:?}",
+                coord, data.bounds
+            )));
+        }
+
+        let mut storage = Self::new();
+        storage.voxels = data.voxels.into_iter().collect();
+        storage.bounds = data.bounds;
+
+        Ok(storage)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestVoxel;
+
+    #[test]
+    fn grows_bounds_on_write() {
+        let mut storage = SparseStorage::new();
+        assert_eq!(storage.get(&Point3::new(1, 2, 3)), Some(&TestVoxel(0)));
+        assert!(storage.is_empty());
+
+        *storage.get_mut(&Point3::new(1, 2, 3)).unwrap() = TestVoxel(1);
+        *storage.get_mut(&Point3::new(-1, 2, 5)).unwrap() = TestVoxel(2);
+
+        assert_eq!(storage.len(), 2);
+        assert_eq!(storage.get(&Point3::new(-1, 2, 5)), Some(&TestVoxel(2)));
+        assert_eq!(
+            storage.bounds(),
+            Some(Bounds::new(Point3::new(-1, 2, 3), Point3::new(2, 3, 6)))
+        );
+        assert_eq!(storage.dimensions(), Vector3::new(3, 1, 3));
+    }
+
+    #[test]
+    fn prune_shrinks_bounds() {
+        let mut storage = SparseStorage::new();
+        *storage.get_mut(&Point3::new(1, 2, 3)).unwrap() = TestVoxel(1);
+        *storage.get_mut(&Point3::new(-1, 2, 5)).unwrap() = TestVoxel(2);
+        storage.get_mut(&Point3::new(4, 4, 4));
+
+        assert_eq!(storage.remove(&Point3::new(-1, 2, 5)), Some(TestVoxel(2)));
+        assert_eq!(storage.get(&Point3::new(-1, 2, 5)), Some(&TestVoxel(0)));
+        assert_eq!(storage.len(), 2);
+        assert_eq!(
+            storage.bounds(),
+            Some(Bounds::new(Point3::new(-1, 2, 3), Point3::new(5, 5, 6)))
+        );
+
+        storage.prune();
+        assert_eq!(storage.len(), 1);
+        assert_eq!(
+            storage.bounds(),
+            Some(Bounds::new(Point3::new(1, 2, 3), Point3::new(2, 3, 4)))
+        );
+    }
+
+    #[test]
+    fn iterates_stored_voxels() {
+        let mut storage = SparseStorage::new();
+        *storage.get_mut(&Point3::new(1, 2, 3)).unwrap() = TestVoxel(1);
+        *storage.get_mut(&Point3::new(-1, 2, 5)).unwrap() = TestVoxel(2);
+        assert_eq!(storage.iter().count(), 2);
+
+        let region = Bounds::new(Point3::new(0, 0, 0), Point3::new(4, 4, 4));
+        let voxels: Vec<_> = storage.iter_region(&region).collect();
+        assert_eq!(voxels, vec![(Point3::new(1, 2, 3), &TestVoxel(1))]);
+
+        for (_, voxel) in storage.iter_region_mut(&region) {
+            voxel.0 += 10;
+        }
+        assert_eq!(storage.get(&Point3::new(1, 2, 3)), Some(&TestVoxel(11)));
+        assert_eq!(storage.get(&Point3::new(-1, 2, 5)), Some(&TestVoxel(2)));
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn serde_keeps_bounds() {
+        use crate::testing::{
+            assert_same_voxels,
+            serde_round_trip,
+        };
+
+        let mut storage = SparseStorage::new();
+        *storage.get_mut(&Point3::new(1, 2, 3)).unwrap() = TestVoxel(1);
+        *storage.get_mut(&Point3::new(-1, 2, 5)).unwrap() = TestVoxel(2);
+        storage.remove(&Point3::new(-1, 2, 5));
+
+        let deserialized = serde_round_trip(&storage);
+        assert_eq!(deserialized.len(), 1);
+        assert_same_voxels(&deserialized, &storage);
+
+        let json = r#"{"bounds":{"min":[0,0,0],"max":[1,1,1]},"voxels":[[[1,0,0],1]]}"#;
+        assert!(serde_json::from_str::<SparseStorage<TestVoxel>>(json).is_err());
+    }
+}
@@ -0,0 +1,95 @@
+//! Helpers shared by the unit tests.
+
+use std::io::{
+    self,
+    Read,
+    Write,
+};
+
+use amethyst_core::{
+    ecs::{
+        Resources,
+        World,
+    },
+    math::Point3,
+};
+use amethyst_rendy::palette::Srgba;
+
+use crate::{
+    formats::native::BinaryVoxel,
+    storage::VoxelStorage,
+    Voxel,
+};
+
+/// Voxel identified by a number. `TestVoxel(0)` is empty, all others are
+/// occupied. Face `i` of `TestVoxel(n)` uses sprite `6 * n + i` and has a red
+/// tint of `i / 5`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TestVoxel(pub u16);
+
+impl Voxel for TestVoxel {
+    fn occupied(&self, _coordinates: &Point3<i32>, _world: &World, _resources: &Resources) -> bool {
+        self.0 != 0
+    }
+
+    fn texture(
+        &self,
+        _coordinates: &Point3<i32>,
+        _world: &World,
+        _resources: &Resources,
+    ) -> Option<[usize; 6]> {
+        let first = 6 * self.0 as usize;
+        (self.0 != 0).then(|| [first, first + 1, first + 2, first + 3, first + 4, first + 5])
+    }
+
+    fn tint(
+        &self,
+        _coordinates: &Point3<i32>,
+        _world: &World,
+        _resources: &Resources,
+    ) -> Option<[Srgba; 6]> {
+        let tint = |face: u8| Srgba::new(f32::from(face) / 5.0, 0.0, 0.0, 1.0);
+        (self.0 != 0).then(|| [tint(0), tint(1), tint(2), tint(3), tint(4), tint(5)])
+    }
+}
+
+impl BinaryVoxel for TestVoxel {
+    fn write_voxel(&self, writer: &mut dyn Write) -> io::Result<()> {
+        writer.write_all(&self.0.to_le_bytes())
+    }
+
+    fn read_voxel(reader: &mut dyn Read) -> io::Result<Self> {
+        let mut bytes = [0; 2];
+        reader.read_exact(&mut bytes)?;
+        Ok(Self(u16::from_le_bytes(bytes)))
+    }
+}
+
+impl From<bool> for TestVoxel {
+    fn from(occupied: bool) -> Self {
+        Self(occupied.into())
+    }
+}
+
+/// Asserts that both storages have the same bounds and the same voxels inside
+/// of them.
+pub fn assert_same_voxels<A, B>(a: &A, b: &B)
+where
+    A: VoxelStorage<TestVoxel>,
+    B: VoxelStorage<TestVoxel>,
+{
+    assert_eq!(a.bounds(), b.bounds());
+    for coord in &a.bounds().unwrap() {
+        assert_eq!(a.get(&coord), b.get(&coord), "{:?}", coord);
+    }
+}
+
+/// Serializes `value` to JSON and deserializes it again.
+#[cfg(feature = "serde")]
+pub fn serde_round_trip<T>(value: &T) -> T
+where
+    T: serde::Serialize + serde::de::DeserializeOwned,
+{
+    serde_json::from_str(&serde_json::to_string(value).unwrap()).unwrap()
+}